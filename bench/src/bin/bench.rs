@@ -9,13 +9,13 @@ extern crate trex;
 use chrono::{Duration, UTC};
 use rand::Rng;
 use std::sync::Arc;
-use std::sync::mpsc::sync_channel;
 use std::thread;
 use tesla::{AttributeDeclaration, Engine, Event, EventTemplate, Listener, Rule, SubscrFilter,
             Tuple, TupleDeclaration, TupleType};
 use tesla::expressions::*;
 use tesla::predicates::*;
 use trex::*;
+use trex::ingest::{Backpressure, IngestConfig};
 use trex::stack::StackProvider;
 
 struct Config {
@@ -40,6 +40,7 @@ fn generate_length_declarations<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<TupleD
             let attr = AttributeDeclaration {
                 name: rng.gen_ascii_chars().take(5).collect(),
                 ty: BasicType::Int,
+                nullable: false,
             };
             decls.push(TupleDeclaration {
                 ty: TupleType::Event,
@@ -99,6 +100,8 @@ fn generate_length_rules<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Rule> {
                                 )
                             ),
                         },
+                        lower_boundary: Boundary::Closed,
+                        upper_boundary: Boundary::Open,
                     },
                 },
                 tuple: ConstrainedTuple {
@@ -118,6 +121,7 @@ fn generate_length_rules<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Rule> {
             filters: Vec::new(),
             event_template: event_template,
             consuming: consuming,
+            accuracy: None,
         };
         rules.push(rule);
     }
@@ -135,6 +139,7 @@ fn generate_length_events<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Event> {
                 data: vec![Value::Int(1)],
             },
             time: UTC::now(),
+            seq: 0,
         });
     }
     events
@@ -170,13 +175,13 @@ fn execute_bench_length(cfg: &Config) {
     let rules = generate_length_rules(&mut rng, &cfg);
     let evts = generate_length_events(&mut rng, &cfg);
 
-    let providers: Vec<Box<NodeProvider>> = vec![Box::new(StackProvider)];
+    let providers: Vec<Box<NodeProvider>> = vec![Box::new(StackProvider::new())];
     let mut engine = TRex::new(num_cpus::get(), providers);
     for decl in decls {
-        engine.declare(decl);
+        engine.declare(decl).unwrap();
     }
     for rule in rules {
-        engine.define(rule);
+        engine.define(rule).unwrap();
     }
     // engine.subscribe(Box::new(DebugListener));
     engine.subscribe(SubscrFilter::Any,
@@ -187,25 +192,23 @@ fn execute_bench_length(cfg: &Config) {
 
     let start = UTC::now();
 
-    let (tx, rx) = sync_channel(cfg.queue_len);
-    let evts_per_sec = cfg.evts_per_sec as u32;
-    let thr = thread::spawn(move || {
-        let mut dropped = 0;
-        for mut evt in evts {
-            evt.time = UTC::now();
-            if tx.try_send(evt).is_err() {
-                dropped += 1;
-            }
-            thread::sleep(std::time::Duration::new(0, 1000_000_000 / evts_per_sec));
-        }
-        dropped
+    let (ingest, dispatcher) = engine.spawn(IngestConfig {
+        capacity: cfg.queue_len,
+        backpressure: Backpressure::DropNewest,
     });
-    while let Ok(evt) = rx.recv() {
-        engine.publish(&Arc::new(evt));
+    let evts_per_sec = cfg.evts_per_sec as u32;
+    for mut evt in evts {
+        evt.time = UTC::now();
+        ingest.publish(evt).unwrap();
+        thread::sleep(std::time::Duration::new(0, 1000_000_000 / evts_per_sec));
     }
+    let dropped = ingest.dropped();
+    drop(ingest);
+    let (_, errors) = dispatcher.join().unwrap();
+    assert!(errors.is_empty(), "{:?}", errors);
 
     println!("Dropped: {:2.2}% - Time: {:5}ms",
-             thr.join().unwrap() as f32 / cfg.num_events as f32 * 100.0,
+             dropped as f32 / cfg.num_events as f32 * 100.0,
              (UTC::now() - start).num_milliseconds());
 }
 
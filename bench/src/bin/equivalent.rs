@@ -15,14 +15,13 @@ use rand::distributions::{Exp, IndependentSample, Normal, Range, Sample};
 use regex::Regex;
 use std::fmt;
 use std::iter::{once, repeat};
-use std::sync::Arc;
-use std::sync::mpsc::sync_channel;
 use std::thread;
 use tesla::{AttributeDeclaration, Engine, Event, EventTemplate, Rule, SubscrFilter, Tuple,
             TupleDeclaration, TupleType};
 use tesla::expressions::*;
 use tesla::predicates::*;
 use trex::*;
+use trex::ingest::{Backpressure, IngestConfig};
 use trex::sqlite::{CacheOwnership, CacheType, SqliteConfig, SqliteProvider};
 use trex::stack::StackProvider;
 
@@ -101,6 +100,7 @@ fn db_equivalent<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Event> {
                     data: data,
                 },
                 time: UTC::now(),
+                seq: 0,
             }
         })
         .collect()
@@ -112,6 +112,7 @@ fn generate_declarations<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<TupleDeclarat
             AttributeDeclaration {
                 name: format!("col{}", i),
                 ty: BasicType::Int,
+                nullable: false,
             }
         })
         .collect();
@@ -132,6 +133,7 @@ fn generate_declarations<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<TupleDeclarat
                     AttributeDeclaration {
                         name: "attr".to_owned(),
                         ty: BasicType::Int,
+                        nullable: false,
                     },
                 ],
             };
@@ -142,6 +144,7 @@ fn generate_declarations<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<TupleDeclarat
                         AttributeDeclaration {
                             name: format!("attr{}", j),
                             ty: BasicType::Int,
+                            nullable: false,
                         }
                     })
                     .collect();
@@ -226,6 +229,8 @@ fn generate_rules<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Rule> {
                         timing: Timing {
                             upper: cfg.num_pred - 1,
                             bound: TimingBound::Within { window: Duration::days(100) },
+                            lower_boundary: Boundary::Closed,
+                            upper_boundary: Boundary::Open,
                         },
                     },
                     tuple: ConstrainedTuple {
@@ -255,6 +260,8 @@ fn generate_rules<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Rule> {
                 let timing = Timing {
                     upper: j - 1,
                     bound: TimingBound::Within { window: Duration::milliseconds(millis) },
+                    lower_boundary: Boundary::Closed,
+                    upper_boundary: Boundary::Open,
                 };
                 let num_param = if j == cfg.num_pred - 1 { 2 } else { 1 };
                 let params = (0..num_param)
@@ -295,6 +302,7 @@ fn generate_rules<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Rule> {
                 filters: Vec::new(),
                 event_template: event_template,
                 consuming: consuming,
+                accuracy: None,
             }
         })
         .collect()
@@ -320,6 +328,7 @@ fn generate_events<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Event> {
                     data: once(1).chain(once(random)).chain(upper_bound).map(From::from).collect(),
                 },
                 time: UTC::now(),
+                seq: 0,
             }
         })
         .collect()
@@ -331,16 +340,16 @@ fn execute_bench<R: Rng>(rng: &mut R, cfg: &Config) {
     let rules = generate_rules(rng, cfg);
     let evts = generate_events(rng, cfg);
 
-    let mut engine = TRex::new(cfg.threads, vec![Box::new(StackProvider)]);
+    let mut engine = TRex::new(cfg.threads, vec![Box::new(StackProvider::new())]);
     for decl in decls {
-        engine.declare(decl);
+        engine.declare(decl).unwrap();
     }
     for rule in rules {
-        engine.define(rule);
+        engine.define(rule).unwrap();
     }
 
     for event in db_eq {
-        engine.publish(&Arc::new(event));
+        engine.publish(event).unwrap();
     }
 
     use trex::listeners::{CountListener, DebugListener};
@@ -352,25 +361,23 @@ fn execute_bench<R: Rng>(rng: &mut R, cfg: &Config) {
 
     let start = UTC::now();
 
-    let (tx, rx) = sync_channel(cfg.queue_len);
-    let evts_per_sec = cfg.evts_per_sec as u32;
-    let thr = thread::spawn(move || {
-        let mut dropped = 0;
-        for mut evt in evts {
-            evt.time = UTC::now();
-            if tx.try_send(evt).is_err() {
-                dropped += 1;
-            }
-            thread::sleep(std::time::Duration::new(0, 1000_000_000 / evts_per_sec));
-        }
-        dropped
+    let (ingest, dispatcher) = engine.spawn(IngestConfig {
+        capacity: cfg.queue_len,
+        backpressure: Backpressure::DropNewest,
     });
-    while let Ok(evt) = rx.recv() {
-        engine.publish(&Arc::new(evt));
+    let evts_per_sec = cfg.evts_per_sec as u32;
+    for mut evt in evts {
+        evt.time = UTC::now();
+        ingest.publish(evt).unwrap();
+        thread::sleep(std::time::Duration::new(0, 1000_000_000 / evts_per_sec));
     }
+    let dropped = ingest.dropped();
+    drop(ingest);
+    let (_, errors) = dispatcher.join().unwrap();
+    assert!(errors.is_empty(), "{:?}", errors);
 
     println!("Dropped: {:2.2}% - Time: {:5}ms",
-             thr.join().unwrap() as f32 / cfg.num_events as f32 * 100.0,
+             dropped as f32 / cfg.num_events as f32 * 100.0,
              (UTC::now() - start).num_milliseconds());
 }
 
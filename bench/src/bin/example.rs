@@ -4,12 +4,11 @@ extern crate tesla;
 extern crate trex;
 
 use chrono::{Duration, UTC};
-use std::sync::Arc;
 use tesla::{AttributeDeclaration, Engine, Event, EventTemplate, Rule, SubscrFilter, Tuple,
             TupleDeclaration, TupleType};
 use tesla::expressions::{BasicType, BinaryOperator, Expression, Value};
-use tesla::predicates::{ConstrainedTuple, EventSelection, ParameterDeclaration, Predicate,
-                        PredicateType, Timing, TimingBound};
+use tesla::predicates::{Boundary, ConstrainedTuple, EventSelection, ParameterDeclaration,
+                        Predicate, PredicateType, Timing, TimingBound};
 use trex::TRex;
 use trex::listeners::DebugListener;
 use trex::stack::StackProvider;
@@ -23,7 +22,7 @@ fn main() {
     // Otherwise there should be one for each used data source,
     // for example `SqliteProvider` is the only other
     // implementation at the moment.
-    let provider = Box::new(StackProvider);
+    let provider = Box::new(StackProvider::new());
 
     // TRex engine instantiation with the number of threads
     // and a vector of providers.
@@ -40,9 +39,11 @@ fn main() {
                 AttributeDeclaration {
                     name: "area".to_owned(),
                     ty: BasicType::Str,
+                    nullable: false,
                 },
             ],
-    });
+    })
+    .unwrap();
 
     // `declare temperature(area: string, value: integer) with id 1`
     engine.declare(TupleDeclaration {
@@ -53,13 +54,16 @@ fn main() {
                 AttributeDeclaration {
                     name: "area".to_owned(),
                     ty: BasicType::Str,
+                    nullable: false,
                 },
                 AttributeDeclaration {
                     name: "value".to_owned(),
                     ty: BasicType::Int,
+                    nullable: false,
                 },
             ],
-    });
+    })
+    .unwrap();
 
     // `declare fire(area: string, temp: integer) with id 2`
     engine.declare(TupleDeclaration {
@@ -70,13 +74,16 @@ fn main() {
                 AttributeDeclaration {
                     name: "area".to_owned(),
                     ty: BasicType::Str,
+                    nullable: false,
                 },
                 AttributeDeclaration {
                     name: "temp".to_owned(),
                     ty: BasicType::Int,
+                    nullable: false,
                 },
             ],
-    });
+    })
+    .unwrap();
 
     // THen we define a rule over those declarations
     //
@@ -116,6 +123,8 @@ fn main() {
                         bound: TimingBound::Within {
                             window: Duration::minutes(5),
                         },
+                        lower_boundary: Boundary::Closed,
+                        upper_boundary: Boundary::Open,
                     },
                 },
                 tuple: ConstrainedTuple {
@@ -160,7 +169,9 @@ fn main() {
             ],
         },
         consuming: vec![],
-    });
+        accuracy: None,
+    })
+    .unwrap();
 
     // We subscribe a listener to receive every event,
     // the `DebugListener` prints to stdout each event.
@@ -170,7 +181,7 @@ fn main() {
     //
     // The first is a temperature event,
     // not high enogh to satisfy the rule constraint.
-    engine.publish(&Arc::new(Event {
+    engine.publish(Event {
         tuple: Tuple {
             ty_id: 1,
             data: vec![
@@ -179,10 +190,12 @@ fn main() {
             ],
         },
         time: UTC::now(),
-    }));
+        seq: 0,
+    })
+    .unwrap();
 
     // Another temperature event that now satisfy the constraint.
-    engine.publish(&Arc::new(Event {
+    engine.publish(Event {
         tuple: Tuple {
             ty_id: 1,
             data: vec![
@@ -191,11 +204,13 @@ fn main() {
             ],
         },
         time: UTC::now(),
-    }));
+        seq: 0,
+    })
+    .unwrap();
 
     // Another temperature that satisfy the constraint,
     // but is on a different area from the previous ones.
-    engine.publish(&Arc::new(Event {
+    engine.publish(Event {
         tuple: Tuple {
             ty_id: 1,
             data: vec![
@@ -204,15 +219,19 @@ fn main() {
             ],
         },
         time: UTC::now(),
-    }));
+        seq: 0,
+    })
+    .unwrap();
 
     // Finally a smoke events arrives on area 1
     // and a fire event is triggered.
-    engine.publish(&Arc::new(Event {
+    engine.publish(Event {
         tuple: Tuple {
             ty_id: 0,
             data: vec![Value::Str("area_1".to_owned())],
         },
         time: UTC::now(),
-    }));
+        seq: 0,
+    })
+    .unwrap();
 }
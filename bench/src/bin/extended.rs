@@ -53,14 +53,13 @@ use rusqlite::types::ToSql;
 use std::fmt;
 use std::iter::{once, repeat};
 use std::ops::Add;
-use std::sync::Arc;
-use std::sync::mpsc::sync_channel;
 use std::thread;
 use tesla::{AttributeDeclaration, Engine, Event, EventTemplate, Rule, SubscrFilter, Tuple,
             TupleDeclaration, TupleType};
 use tesla::expressions::*;
 use tesla::predicates::*;
 use trex::*;
+use trex::ingest::{Backpressure, IngestConfig};
 use trex::sqlite::{CacheOwnership, CacheType, SqliteConfig, SqliteProvider};
 use trex::stack::StackProvider;
 
@@ -174,6 +173,7 @@ fn generate_declarations<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<TupleDeclarat
             AttributeDeclaration {
                 name: format!("col{}", i),
                 ty: BasicType::Int,
+                nullable: false,
             }
         })
         .collect::<Vec<_>>();
@@ -200,6 +200,7 @@ fn generate_declarations<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<TupleDeclarat
                     AttributeDeclaration {
                         name: "attr".to_owned(),
                         ty: BasicType::Int,
+                        nullable: false,
                     },
                 ],
             };
@@ -210,6 +211,7 @@ fn generate_declarations<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<TupleDeclarat
                         AttributeDeclaration {
                             name: format!("attr{}", j),
                             ty: BasicType::Int,
+                            nullable: false,
                         }
                     })
                     .collect();
@@ -315,6 +317,8 @@ fn generate_rules<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Rule> {
                         timing: Timing {
                             upper: 0,
                             bound: TimingBound::Within { window: Duration::days(1000) },
+                            lower_boundary: Boundary::Closed,
+                            upper_boundary: Boundary::Open,
                         },
                     },
                     tuple: ConstrainedTuple {
@@ -339,6 +343,8 @@ fn generate_rules<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Rule> {
                 let timing = Timing {
                     upper: j - 1,
                     bound: TimingBound::Within { window: Duration::milliseconds(millis) },
+                    lower_boundary: Boundary::Closed,
+                    upper_boundary: Boundary::Open,
                 };
                 let num_param = if j == cfg.num_pred - 1 { 2 } else { 1 };
                 let params = (0..num_param)
@@ -379,6 +385,7 @@ fn generate_rules<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Rule> {
                 filters: Vec::new(),
                 event_template: event_template,
                 consuming: consuming,
+                accuracy: None,
             }
         })
         .collect()
@@ -404,6 +411,7 @@ fn generate_events<R: Rng>(rng: &mut R, cfg: &Config) -> Vec<Event> {
                     data: once(1).chain(once(random)).chain(upper_bound).map(From::from).collect(),
                 },
                 time: UTC::now(),
+                seq: 0,
             }
         })
         .collect()
@@ -423,14 +431,14 @@ fn execute_bench<R: Rng>(rng: &mut R, cfg: &Config) {
         cache_type: cfg.cache_type,
     };
     let sqlite_provider = Box::new(SqliteProvider::new(sqlite_config));
-    let providers: Vec<Box<NodeProvider>> = vec![Box::new(StackProvider), sqlite_provider];
+    let providers: Vec<Box<NodeProvider>> = vec![Box::new(StackProvider::new()), sqlite_provider];
 
     let mut engine = TRex::new(cfg.threads, providers);
     for decl in decls {
-        engine.declare(decl);
+        engine.declare(decl).unwrap();
     }
     for rule in rules {
-        engine.define(rule);
+        engine.define(rule).unwrap();
     }
 
     let tail = Event {
@@ -439,9 +447,10 @@ fn execute_bench<R: Rng>(rng: &mut R, cfg: &Config) {
             data: repeat(1.into()).take(cfg.table_columns).collect(),
         },
         time: UTC::now(),
+        seq: 0,
     };
     for _ in 0..cfg.matching_rows {
-        engine.publish(&Arc::new(tail.clone()));
+        engine.publish(tail.clone()).unwrap();
     }
 
     use trex::listeners::{CountListener, DebugListener};
@@ -453,25 +462,23 @@ fn execute_bench<R: Rng>(rng: &mut R, cfg: &Config) {
 
     let start = UTC::now();
 
-    let (tx, rx) = sync_channel(cfg.queue_len);
-    let evts_per_sec = cfg.evts_per_sec as u32;
-    let thr = thread::spawn(move || {
-        let mut dropped = 0;
-        for mut evt in evts {
-            evt.time = UTC::now();
-            if tx.try_send(evt).is_err() {
-                dropped += 1;
-            }
-            thread::sleep(std::time::Duration::new(0, 1000_000_000 / evts_per_sec));
-        }
-        dropped
+    let (ingest, dispatcher) = engine.spawn(IngestConfig {
+        capacity: cfg.queue_len,
+        backpressure: Backpressure::DropNewest,
     });
-    while let Ok(evt) = rx.recv() {
-        engine.publish(&Arc::new(evt));
+    let evts_per_sec = cfg.evts_per_sec as u32;
+    for mut evt in evts {
+        evt.time = UTC::now();
+        ingest.publish(evt).unwrap();
+        thread::sleep(std::time::Duration::new(0, 1000_000_000 / evts_per_sec));
     }
+    let dropped = ingest.dropped();
+    drop(ingest);
+    let (_, errors) = dispatcher.join().unwrap();
+    assert!(errors.is_empty(), "{:?}", errors);
 
     println!("Dropped: {:2.2}% - Time: {:5}ms",
-             thr.join().unwrap() as f32 / cfg.num_events as f32 * 100.0,
+             dropped as f32 / cfg.num_events as f32 * 100.0,
              (UTC::now() - start).num_milliseconds());
 }
 
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Duration, UTC};
 use ordered_float::NotNaN;
 use std::hash::{Hash, Hasher};
 
@@ -7,6 +8,8 @@ pub enum BasicType {
     Float,
     Bool,
     Str,
+    Timestamp,
+    Duration,
 }
 
 #[derive(Clone, Debug)]
@@ -15,45 +18,82 @@ pub enum Value {
     Float(f64),
     Bool(bool),
     Str(String),
+    Timestamp(DateTime<UTC>),
+    Duration(Duration),
+    /// Missing value of a nullable attribute, it has no type on its own
+    Null,
 }
 
 // TODO add a RawValue(i32, f32, bool, String)?
 // Paying a little cost in memory It would allow unchecked access to values
 // But for safety and ergonomy it could be easily converted to/from the Value enum.
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum UnaryOperator {
     Minus,
     Not,
+    /// Postfix `is null`, it is the only operator that is never null itself
+    IsNull,
+    /// Postfix `is not null`
+    IsNotNull,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum BinaryOperator {
     Plus,
     Minus,
     Times,
+    /// Integer division when applied to integers
     Division,
+    Remainder,
+    BitwiseAnd,
+    BitwiseOr,
+    BitwiseXor,
+    ShiftLeft,
+    ShiftRight,
     Equal,
     NotEqual,
     GreaterThan,
     GreaterEqual,
     LowerThan,
-    LowerEqual, // TODO add Reminder
+    LowerEqual,
+    /// The right operand is a regular expression searched in the left one
+    Matches,
+    /// SQL-like pattern, where `%` matches any sequence of characters, `_` any single one
+    /// and `\` escapes the character after it
+    Like,
+    And,
+    Or,
+    Xor,
 }
 
-#[derive(Clone, Debug)]
+/// How a cast from `Float` to `Int` gets rid of the fractional part
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    /// Toward zero
+    Truncate,
+    /// To the nearest integer, with halfway cases away from zero
+    Round,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Expression {
     Immediate { value: Value },
     /// It always refers to the predicate it appears in
     Reference { attribute: usize },
     /// It refers to the value of the aggregation predicate it appears in
     Aggregate,
+    /// Time of the event matched by a predicate, that can be
+    /// the one the expression appears in or any previous one
+    Timestamp { predicate: usize },
     Parameter {
         predicate: usize,
         parameter: usize, // TODO maybe replace with Arc<Expression>
     },
+    /// Explicit conversion, the rounding mode matters only from `Float` to `Int`
     Cast {
         ty: BasicType,
+        rounding: Rounding,
         expression: Box<Expression>,
     },
     UnaryOperation {
@@ -65,6 +105,37 @@ pub enum Expression {
         left: Box<Expression>,
         right: Box<Expression>,
     },
+    /// Call of a scalar function, it is resolved by name by the engine
+    Function {
+        name: String,
+        args: Vec<Expression>,
+    },
+}
+
+impl BinaryOperator {
+    /// Binding strength of the operator in the textual TESLA syntax,
+    /// all the operators are left associative.
+    pub fn precedence(&self) -> usize {
+        match *self {
+            BinaryOperator::Or => 1,
+            BinaryOperator::Xor => 2,
+            BinaryOperator::And => 3,
+            BinaryOperator::Equal |
+            BinaryOperator::NotEqual |
+            BinaryOperator::GreaterThan |
+            BinaryOperator::GreaterEqual |
+            BinaryOperator::LowerThan |
+            BinaryOperator::LowerEqual |
+            BinaryOperator::Matches |
+            BinaryOperator::Like => 4,
+            BinaryOperator::BitwiseOr => 5,
+            BinaryOperator::BitwiseXor => 6,
+            BinaryOperator::BitwiseAnd => 7,
+            BinaryOperator::ShiftLeft | BinaryOperator::ShiftRight => 8,
+            BinaryOperator::Plus | BinaryOperator::Minus => 9,
+            BinaryOperator::Times | BinaryOperator::Division | BinaryOperator::Remainder => 10,
+        }
+    }
 }
 
 // TODO think about utility of the following functions
@@ -82,6 +153,13 @@ impl Value {
     pub fn unwrap_string(&self) -> String {
         if let Value::Str(ref value) = *self { value.clone() } else { panic!("Wrong Value unwrap") }
     }
+    pub fn unwrap_timestamp(&self) -> DateTime<UTC> {
+        if let Value::Timestamp(value) = *self { value } else { panic!("Wrong Value unwrap") }
+    }
+    pub fn unwrap_duration(&self) -> Duration {
+        if let Value::Duration(value) = *self { value } else { panic!("Wrong Value unwrap") }
+    }
+    pub fn is_null(&self) -> bool { if let Value::Null = *self { true } else { false } }
 }
 
 impl From<i64> for Value {
@@ -100,6 +178,14 @@ impl From<String> for Value {
     fn from(val: String) -> Self { Value::Str(val) }
 }
 
+impl From<DateTime<UTC>> for Value {
+    fn from(val: DateTime<UTC>) -> Self { Value::Timestamp(val) }
+}
+
+impl From<Duration> for Value {
+    fn from(val: Duration) -> Self { Value::Duration(val) }
+}
+
 impl Value {
     pub fn get_type(&self) -> BasicType {
         match *self {
@@ -107,6 +193,9 @@ impl Value {
             Value::Float(_) => BasicType::Float,
             Value::Bool(_) => BasicType::Bool,
             Value::Str(_) => BasicType::Str,
+            Value::Timestamp(_) => BasicType::Timestamp,
+            Value::Duration(_) => BasicType::Duration,
+            Value::Null => panic!("Null values have no type"),
         }
     }
 }
@@ -118,6 +207,9 @@ impl Hash for Value {
             Value::Float(x) => NotNaN::from(x).hash(state),
             Value::Bool(x) => x.hash(state),
             Value::Str(ref x) => x.hash(state),
+            Value::Timestamp(ref x) => x.hash(state),
+            Value::Duration(ref x) => x.hash(state),
+            Value::Null => (),
         }
     }
 }
@@ -129,6 +221,9 @@ impl PartialEq for Value {
             (&Value::Float(x), &Value::Float(y)) => NotNaN::from(x).eq(&NotNaN::from(y)),
             (&Value::Bool(x), &Value::Bool(y)) => x.eq(&y),
             (&Value::Str(ref x), &Value::Str(ref y)) => x.eq(y),
+            (&Value::Timestamp(ref x), &Value::Timestamp(ref y)) => x.eq(y),
+            (&Value::Duration(ref x), &Value::Duration(ref y)) => x.eq(y),
+            (&Value::Null, &Value::Null) => true,
             _ => false,
         }
     }
@@ -136,19 +231,39 @@ impl PartialEq for Value {
 impl Eq for Value {}
 
 impl Expression {
+    /// Whether the expression can be evaluated on a tuple alone (i.e. without parameters).
+    ///
+    /// Boolean connectives are local only if both operands are: split conjunctions
+    /// with `into_conjuncts` to check their operands separately.
     pub fn is_local(&self) -> bool {
         // TODO maybe take into account local parameters that don't alter expression locality
         match *self {
-            Expression::Parameter { .. } => false,
+            // It may refer to another predicate
+            Expression::Parameter { .. } |
+            Expression::Timestamp { .. } => false,
             Expression::Cast { ref expression, .. } |
             Expression::UnaryOperation { ref expression, .. } => expression.is_local(),
             Expression::BinaryOperation { ref left, ref right, .. } => {
                 left.is_local() && right.is_local()
             }
+            Expression::Function { ref args, .. } => args.iter().all(Expression::is_local),
             _ => true,
         }
     }
 
+    /// Splits a conjunction into its operands,
+    /// so that each of them can be checked separately.
+    pub fn into_conjuncts(self) -> Vec<Expression> {
+        match self {
+            Expression::BinaryOperation { operator: BinaryOperator::And, left, right } => {
+                let mut res = left.into_conjuncts();
+                res.append(&mut right.into_conjuncts());
+                res
+            }
+            expr => vec![expr],
+        }
+    }
+
     pub fn get_parameters(&self) -> Vec<(usize, usize)> {
         match *self {
             Expression::Parameter { predicate, parameter } => vec![(predicate, parameter)],
@@ -161,6 +276,12 @@ impl Expression {
                 res.dedup();
                 res
             }
+            Expression::Function { ref args, .. } => {
+                let mut res = args.iter().flat_map(Expression::get_parameters).collect::<Vec<_>>();
+                res.sort();
+                res.dedup();
+                res
+            }
             _ => Vec::new(),
         }
     }
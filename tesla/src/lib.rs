@@ -3,13 +3,16 @@ extern crate ordered_float;
 
 pub mod expressions;
 pub mod predicates;
+pub mod parser;
+pub mod printer;
 
 use chrono::{DateTime, UTC};
 use expressions::{BasicType, Expression, Value};
 use predicates::Predicate;
+use std::error::Error;
 use std::sync::Arc;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TupleType {
     Static,
     Event,
@@ -19,6 +22,8 @@ pub enum TupleType {
 pub struct AttributeDeclaration {
     pub name: String,
     pub ty: BasicType,
+    /// Whether the attribute can hold `Value::Null`
+    pub nullable: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -41,6 +46,9 @@ pub struct Rule {
     pub filters: Vec<Expression>,
     pub event_template: EventTemplate,
     pub consuming: Vec<usize>,
+    /// Maximum rank error of the percentiles, relative to the number of aggregated values,
+    /// that allows approximating them on large windows, `None` computes them exactly
+    pub accuracy: Option<f64>,
 }
 
 #[derive(Clone, Debug)]
@@ -53,6 +61,14 @@ pub struct Tuple {
 pub struct Event {
     pub tuple: Tuple,
     pub time: DateTime<UTC>,
+    /// Assigned by the engine in increasing order as the events are processed,
+    /// it breaks the ties between the events with the same time
+    pub seq: usize,
+}
+
+impl Event {
+    /// Position of the event in the stream, ordered by time and then by sequence number.
+    pub fn order(&self) -> (DateTime<UTC>, usize) { (self.time, self.seq) }
 }
 
 #[derive(Clone, Debug)]
@@ -62,14 +78,17 @@ pub enum SubscrFilter {
     Content { ty: usize, filters: Vec<Expression> },
 }
 
-pub trait Listener {
+pub trait Listener: Send {
     fn receive(&mut self, event: &Arc<Event>);
 }
 
 pub trait Engine {
-    fn declare(&mut self, tuple: TupleDeclaration);
-    fn define(&mut self, rule: Rule);
-    fn publish(&mut self, event: &Arc<Event>);
+    type Error: Error;
+    fn declare(&mut self, tuple: TupleDeclaration) -> Result<(), Self::Error>;
+    fn define(&mut self, rule: Rule) -> Result<usize, Self::Error>;
+    fn undefine(&mut self, rule_id: usize) -> Result<(), Self::Error>;
+    fn redefine(&mut self, rule_id: usize, rule: Rule) -> Result<(), Self::Error>;
+    fn publish(&mut self, event: Event) -> Result<(), Self::Error>;
     fn subscribe(&mut self, condition: SubscrFilter, listener: Box<Listener>) -> usize;
     fn unsubscribe(&mut self, listener_id: usize);
 }
@@ -0,0 +1,106 @@
+use TupleType;
+use chrono::Duration;
+use expressions::{BasicType, BinaryOperator, UnaryOperator, Value};
+use predicates::{Boundary, EventSelection, Order};
+use super::Span;
+
+#[derive(Clone, Debug)]
+pub struct Ident {
+    pub name: String,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub enum ExprKind {
+    Literal(Value),
+    Attribute(String),
+    Parameter(String),
+    Call(String, Vec<Expr>),
+    Unary(UnaryOperator, Box<Expr>),
+    Binary(BinaryOperator, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct AttributeDecl {
+    pub name: Ident,
+    pub ty: BasicType,
+    pub nullable: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Declaration {
+    pub ty: TupleType,
+    pub name: Ident,
+    pub attributes: Vec<AttributeDecl>,
+    pub id: usize,
+    pub id_span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct ParamDecl {
+    pub name: Ident,
+    pub expression: Expr,
+}
+
+#[derive(Clone, Debug)]
+pub struct Assignment {
+    pub attribute: Ident,
+    pub expression: Expr,
+}
+
+#[derive(Clone, Debug)]
+pub enum TimingKind {
+    Within { window: Duration, from: Ident },
+    Between { lower: Ident, upper: Ident },
+    Last { count: usize, from: Ident },
+}
+
+#[derive(Clone, Debug)]
+pub struct Timing {
+    pub kind: TimingKind,
+    pub lower_boundary: Boundary,
+    pub upper_boundary: Boundary,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct OrderingDecl {
+    pub attribute: Ident,
+    pub direction: Order,
+}
+
+#[derive(Clone, Debug)]
+pub struct Predicate {
+    pub negated: bool,
+    pub selection: Option<EventSelection>,
+    pub tuple: Ident,
+    pub parameters: Vec<ParamDecl>,
+    pub constraints: Vec<Expr>,
+    pub alias: Option<Ident>,
+    pub timing: Option<Timing>,
+    pub ordering: Vec<OrderingDecl>,
+    pub span: Span,
+}
+
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub template: Ident,
+    pub assignments: Vec<Assignment>,
+    pub template_span: Span,
+    pub predicates: Vec<Predicate>,
+    pub filters: Vec<Expr>,
+    pub consuming: Vec<Ident>,
+    pub accuracy: Option<f64>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Statement {
+    Declaration(Declaration),
+    Rule(Rule),
+}
@@ -0,0 +1,291 @@
+use chrono::Duration;
+use std::iter::Peekable;
+use std::str::Chars;
+use super::{ParseError, Position, Span};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Ident(String),
+    Param(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Duration(Duration),
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Colon,
+    Semicolon,
+    Question,
+    Assign,
+    Equal,
+    NotEqual,
+    GreaterThan,
+    GreaterEqual,
+    LowerThan,
+    LowerEqual,
+    Plus,
+    Minus,
+    Times,
+    Division,
+    Percent,
+    Ampersand,
+    Pipe,
+    Caret,
+    ShiftLeft,
+    ShiftRight,
+    Bang,
+    And,
+    Or,
+    Eof,
+}
+
+#[derive(Clone, Debug)]
+pub struct Spanned {
+    pub token: Token,
+    pub span: Span,
+}
+
+pub fn duration_unit(unit: &str) -> Option<fn(i64) -> Duration> {
+    match unit {
+        "d" | "day" | "days" => Some(Duration::days),
+        "h" | "hour" | "hours" => Some(Duration::hours),
+        "min" | "minute" | "minutes" => Some(Duration::minutes),
+        "s" | "sec" | "second" | "seconds" => Some(Duration::seconds),
+        "ms" | "millisecond" | "milliseconds" => Some(Duration::milliseconds),
+        "us" | "microsecond" | "microseconds" => Some(Duration::microseconds),
+        "ns" | "nanosecond" | "nanoseconds" => Some(Duration::nanoseconds),
+        _ => None,
+    }
+}
+
+/// Builds a duration of `value` units, unless chrono cannot represent it.
+pub fn checked_duration(unit: fn(i64) -> Duration, value: i64) -> Option<Duration> {
+    // Chrono panics beyond `i64` milliseconds, finer units are divided and cannot exceed it
+    let millis = unit(1).num_milliseconds();
+    if millis == 0 || value.checked_mul(millis).is_some() { Some(unit(value)) } else { None }
+}
+
+struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    position: Position,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a str) -> Self {
+        Lexer {
+            chars: source.chars().peekable(),
+            position: Position { line: 1, column: 1 },
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> { self.chars.peek().cloned() }
+
+    fn bump(&mut self) -> Option<char> {
+        let next = self.chars.next();
+        match next {
+            Some('\n') => {
+                self.position.line += 1;
+                self.position.column = 1;
+            }
+            Some(_) => self.position.column += 1,
+            None => (),
+        }
+        next
+    }
+
+    fn error<T>(&self, start: Position, message: String) -> Result<T, ParseError> {
+        Err(ParseError {
+            message: message,
+            span: Span {
+                start: start,
+                end: self.position,
+            },
+        })
+    }
+
+    fn skip_whitespaces(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.bump();
+                }
+                Some('/') => {
+                    let mut lookahead = self.chars.clone();
+                    lookahead.next();
+                    if lookahead.next() != Some('/') {
+                        return;
+                    }
+                    while self.peek().map_or(false, |c| c != '\n') {
+                        self.bump();
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn take_while<F: Fn(char) -> bool>(&mut self, predicate: F) -> String {
+        let mut res = String::new();
+        while let Some(c) = self.peek() {
+            if !predicate(c) {
+                break;
+            }
+            res.push(c);
+            self.bump();
+        }
+        res
+    }
+
+    fn lex_word(&mut self) -> String { self.take_while(|c| c.is_alphanumeric() || c == '_') }
+
+    fn lex_number(&mut self, start: Position) -> Result<Token, ParseError> {
+        let mut number = self.take_while(|c| c.is_digit(10));
+        let mut is_float = false;
+        if self.peek() == Some('.') {
+            let mut lookahead = self.chars.clone();
+            lookahead.next();
+            if lookahead.next().map_or(false, |c| c.is_digit(10)) {
+                self.bump();
+                number.push('.');
+                number.push_str(&self.take_while(|c| c.is_digit(10)));
+                is_float = true;
+            }
+        }
+        let unit = self.lex_word();
+        if !unit.is_empty() {
+            if is_float {
+                return self.error(start, format!("invalid duration `{}{}`", number, unit));
+            }
+            return match (duration_unit(&unit), number.parse::<i64>()) {
+                (Some(unit), Ok(value)) => {
+                    match checked_duration(unit, value) {
+                        Some(duration) => Ok(Token::Duration(duration)),
+                        None => self.error(start, "duration out of range".to_owned()),
+                    }
+                }
+                _ => self.error(start, format!("invalid duration `{}{}`", number, unit)),
+            };
+        }
+        if is_float {
+            number.parse().map(Token::Float).or_else(|_| {
+                self.error(start, format!("invalid float literal `{}`", number))
+            })
+        } else {
+            number.parse().map(Token::Int).or_else(|_| {
+                self.error(start, format!("invalid integer literal `{}`", number))
+            })
+        }
+    }
+
+    fn lex_string(&mut self, start: Position, quote: char) -> Result<Token, ParseError> {
+        let mut res = String::new();
+        loop {
+            match self.bump() {
+                Some('\\') => {
+                    match self.bump() {
+                        Some('n') => res.push('\n'),
+                        Some('t') => res.push('\t'),
+                        Some('r') => res.push('\r'),
+                        Some(c @ '\\') | Some(c @ '\'') | Some(c @ '"') => res.push(c),
+                        Some(c) => {
+                            return self.error(start, format!("unknown escape sequence `\\{}`", c))
+                        }
+                        None => return self.error(start, "unterminated string".to_owned()),
+                    }
+                }
+                Some(c) if c == quote => return Ok(Token::Str(res)),
+                Some(c) => res.push(c),
+                None => return self.error(start, "unterminated string".to_owned()),
+            }
+        }
+    }
+
+    fn lex_symbol(&mut self, start: Position, c: char) -> Result<Token, ParseError> {
+        let next = self.peek();
+        let (token, double) = match (c, next) {
+            ('(', _) => (Token::LeftParen, false),
+            (')', _) => (Token::RightParen, false),
+            ('[', _) => (Token::LeftBracket, false),
+            (']', _) => (Token::RightBracket, false),
+            (',', _) => (Token::Comma, false),
+            (':', _) => (Token::Colon, false),
+            (';', _) => (Token::Semicolon, false),
+            ('?', _) => (Token::Question, false),
+            ('=', Some('=')) => (Token::Equal, true),
+            ('=', _) => (Token::Assign, false),
+            ('!', Some('=')) => (Token::NotEqual, true),
+            ('!', _) => (Token::Bang, false),
+            ('<', Some('>')) => (Token::NotEqual, true),
+            ('<', Some('<')) => (Token::ShiftLeft, true),
+            ('<', Some('=')) => (Token::LowerEqual, true),
+            ('<', _) => (Token::LowerThan, false),
+            ('>', Some('=')) => (Token::GreaterEqual, true),
+            ('>', Some('>')) => (Token::ShiftRight, true),
+            ('>', _) => (Token::GreaterThan, false),
+            ('+', _) => (Token::Plus, false),
+            ('-', _) => (Token::Minus, false),
+            ('*', _) => (Token::Times, false),
+            ('/', _) => (Token::Division, false),
+            ('%', _) => (Token::Percent, false),
+            ('&', Some('&')) => (Token::And, true),
+            ('&', _) => (Token::Ampersand, false),
+            ('|', Some('|')) => (Token::Or, true),
+            ('|', _) => (Token::Pipe, false),
+            ('^', _) => (Token::Caret, false),
+            _ => return self.error(start, format!("unexpected character `{}`", c)),
+        };
+        if double {
+            self.bump();
+        }
+        Ok(token)
+    }
+
+    fn next_token(&mut self) -> Result<Spanned, ParseError> {
+        self.skip_whitespaces();
+        let start = self.position;
+        let token = match self.peek() {
+            None => Token::Eof,
+            Some(c) if c.is_digit(10) => self.lex_number(start)?,
+            Some(c) if c.is_alphabetic() || c == '_' => Token::Ident(self.lex_word()),
+            Some('$') => {
+                self.bump();
+                let name = self.lex_word();
+                if name.is_empty() {
+                    return self.error(start, "expected a parameter name after `$`".to_owned());
+                }
+                Token::Param(name)
+            }
+            Some(quote @ '\'') | Some(quote @ '"') => {
+                self.bump();
+                self.lex_string(start, quote)?
+            }
+            Some(c) => {
+                self.bump();
+                self.lex_symbol(start, c)?
+            }
+        };
+        Ok(Spanned {
+            token: token,
+            span: Span {
+                start: start,
+                end: self.position,
+            },
+        })
+    }
+}
+
+pub fn tokenize(source: &str) -> Result<Vec<Spanned>, ParseError> {
+    let mut lexer = Lexer::new(source);
+    let mut tokens = Vec::new();
+    loop {
+        let next = lexer.next_token()?;
+        let is_eof = next.token == Token::Eof;
+        tokens.push(next);
+        if is_eof {
+            return Ok(tokens);
+        }
+    }
+}
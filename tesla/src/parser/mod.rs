@@ -0,0 +1,390 @@
+//! Parser for the textual TESLA rule language.
+//!
+//! A source is a sequence of tuple declarations and rule definitions:
+//!
+//! ```text
+//! declare smoke(area: string) with id 0
+//! declare temperature(area: string, value: int) with id 1
+//! declare fire(area: string, temp: int) with id 2
+//!
+//! define fire(area = $x, temp = $y)
+//! from smoke[$x = area] as smk
+//! and last temperature[$y = value](area == $x, value > 45) as temp within 5min from smk
+//! consuming temp
+//! ```
+//!
+//! Attributes are referenced by name inside the predicate they belong to,
+//! parameters (`$name`) are visible from the point they are declared onwards
+//! and predicates are referenced by their alias (which defaults to the tuple name).
+
+mod ast;
+mod lexer;
+mod resolve;
+mod syntax;
+
+use {Rule, TupleDeclaration};
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    fn to(self, other: Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}:{}: {}",
+               self.span.start.line,
+               self.span.start.column,
+               self.message)
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str { &self.message }
+}
+
+#[derive(Clone, Debug)]
+pub enum Statement {
+    Declaration(TupleDeclaration),
+    Rule(Rule),
+}
+
+/// Keeps track of the declared tuples so that rules can refer to them by name.
+#[derive(Clone, Debug, Default)]
+pub struct Parser {
+    tuples: Vec<TupleDeclaration>,
+}
+
+impl Parser {
+    pub fn new() -> Self { Parser { tuples: Vec::new() } }
+
+    pub fn with_declarations(tuples: Vec<TupleDeclaration>) -> Self { Parser { tuples: tuples } }
+
+    pub fn declarations(&self) -> &[TupleDeclaration] { &self.tuples }
+
+    /// Parses a whole source, the declarations it contains become visible
+    /// to the following statements and to subsequent calls.
+    ///
+    /// On error nothing is declared.
+    pub fn parse(&mut self, source: &str) -> Result<Vec<Statement>, ParseError> {
+        let tokens = lexer::tokenize(source)?;
+        let statements = syntax::Syntax::new(tokens).statements()?;
+        let mut tuples = self.tuples.clone();
+        let mut res = Vec::new();
+        for statement in &statements {
+            match *statement {
+                ast::Statement::Declaration(ref decl) => {
+                    let tuple = resolve::declaration(&tuples, decl)?;
+                    tuples.push(tuple.clone());
+                    res.push(Statement::Declaration(tuple));
+                }
+                ast::Statement::Rule(ref rule) => {
+                    res.push(Statement::Rule(resolve::rule(&tuples, rule)?));
+                }
+            }
+        }
+        self.tuples = tuples;
+        Ok(res)
+    }
+
+    /// Parses a source made of a single rule definition.
+    pub fn parse_rule(&self, source: &str) -> Result<Rule, ParseError> {
+        let tokens = lexer::tokenize(source)?;
+        let rule = syntax::Syntax::new(tokens).single_rule()?;
+        resolve::rule(&self.tuples, &rule)
+    }
+}
+
+pub fn parse(source: &str) -> Result<Vec<Statement>, ParseError> { Parser::new().parse(source) }
+
+#[cfg(test)]
+mod tests {
+    use super::{Parser, Position, Statement, parse};
+    use Rule;
+    use expressions::{BinaryOperator, Expression, Value};
+    use predicates::*;
+
+    const DECLARATIONS: &'static str = "
+        declare smoke(area: string) with id 0
+        declare temperature(area: string, value: int) with id 1
+        declare fire(area: string, temp: int) with id 2
+        declare static sensors(area: string, kind: string, threshold: int) with id 3
+    ";
+
+    fn parse_rule(rule: &str) -> Rule {
+        let mut parser = Parser::new();
+        parser.parse(DECLARATIONS).unwrap();
+        parser.parse_rule(rule).unwrap()
+    }
+
+    fn error_at(rule: &str) -> (Position, String) {
+        let mut parser = Parser::new();
+        parser.parse(DECLARATIONS).unwrap();
+        let err = parser.parse_rule(rule).unwrap_err();
+        (err.span.start, err.message)
+    }
+
+    #[test]
+    fn declarations() {
+        let statements = parse(DECLARATIONS).unwrap();
+        assert_eq!(statements.len(), 4);
+        if let Statement::Declaration(ref decl) = statements[3] {
+            assert_eq!(decl.id, 3);
+            assert_eq!(decl.name, "sensors");
+            assert_eq!(decl.attributes[2].name, "threshold");
+        } else {
+            panic!("expected a declaration");
+        }
+    }
+
+    #[test]
+    fn example_rule() {
+        let rule = parse_rule("
+            define fire(area = $x, temp = $y)
+            from smoke[$x = area] as smk
+            and last temperature[$y = value](area == $x, value > 45) as temp within 5min from smk
+            consuming temp
+        ");
+        assert_eq!(rule.predicates.len(), 2);
+        assert_eq!(rule.consuming, vec![1]);
+        assert_eq!(rule.event_template.ty_id, 2);
+        match rule.predicates[1].ty {
+            PredicateType::Event { selection: EventSelection::Last,
+                                   ref parameters,
+                                   timing: Timing { upper: 0, bound: TimingBound::Within { window }, .. } } => {
+                assert_eq!(parameters[0].name, "y");
+                assert_eq!(window.num_minutes(), 5);
+            }
+            ref other => panic!("unexpected predicate {:?}", other),
+        }
+        match rule.predicates[1].tuple.constraints[0] {
+            Expression::BinaryOperation { operator: BinaryOperator::Equal, ref left, ref right } => {
+                match (&**left, &**right) {
+                    (&Expression::Reference { attribute: 0 },
+                     &Expression::Parameter { predicate: 0, parameter: 0 }) => (),
+                    other => panic!("unexpected operands {:?}", other),
+                }
+            }
+            ref other => panic!("unexpected constraint {:?}", other),
+        }
+        match rule.event_template.attributes[1] {
+            Expression::Parameter { predicate: 1, parameter: 0 } => (),
+            ref other => panic!("unexpected template attribute {:?}", other),
+        }
+    }
+
+    #[test]
+    fn operator_precedence() {
+        let rule = parse_rule("
+            define fire(area = $x, temp = 1 + 2 * 3 % 4 & 1 << 2)
+            from smoke[$x = area]
+        ");
+        match rule.event_template.attributes[1] {
+            Expression::BinaryOperation { operator: BinaryOperator::BitwiseAnd,
+                                          ref left,
+                                          ref right } => {
+                match (&**left, &**right) {
+                    (&Expression::BinaryOperation { operator: BinaryOperator::Plus, .. },
+                     &Expression::BinaryOperation { operator: BinaryOperator::ShiftLeft, .. }) => {}
+                    other => panic!("unexpected operands {:?}", other),
+                }
+            }
+            ref other => panic!("unexpected expression {:?}", other),
+        }
+    }
+
+    #[test]
+    fn logical_operators() {
+        let rule = parse_rule("
+            define fire(area = $x, temp = 1)
+            from smoke[$x = area]
+            where $x == 'a' or $x == 'b' && !($x == 'c' xor $x != 'd')
+        ");
+        match rule.filters[0] {
+            Expression::BinaryOperation { operator: BinaryOperator::Or, ref right, .. } => {
+                match **right {
+                    Expression::BinaryOperation { operator: BinaryOperator::And, .. } => (),
+                    ref other => panic!("unexpected operand {:?}", other),
+                }
+            }
+            ref other => panic!("unexpected expression {:?}", other),
+        }
+    }
+
+    #[test]
+    fn pattern_operators() {
+        let rule = parse_rule("
+            define fire(area = $x, temp = 1)
+            from smoke[$x = area](area like 'north%', area matches '^n\\\\w+$' == true)
+        ");
+        let constraints = &rule.predicates[0].tuple.constraints;
+        match constraints[0] {
+            Expression::BinaryOperation { operator: BinaryOperator::Like, .. } => (),
+            ref other => panic!("unexpected constraint {:?}", other),
+        }
+        match constraints[1] {
+            Expression::BinaryOperation { operator: BinaryOperator::Equal, ref left, .. } => {
+                match **left {
+                    Expression::BinaryOperation { operator: BinaryOperator::Matches,
+                                                  ref right,
+                                                  .. } => {
+                        match **right {
+                            Expression::Immediate { value: Value::Str(ref pattern) } => {
+                                assert_eq!(pattern, "^n\\w+$")
+                            }
+                            ref other => panic!("unexpected pattern {:?}", other),
+                        }
+                    }
+                    ref other => panic!("unexpected operand {:?}", other),
+                }
+            }
+            ref other => panic!("unexpected constraint {:?}", other),
+        }
+    }
+
+    #[test]
+    fn function_calls() {
+        let rule = parse_rule("
+            define fire(area = upper($x), temp = max($y, 0))
+            from smoke[$x = area]
+            and last temperature[$y = value](lower(area) == $x, abs(value - 40) < 5)
+            within 5min from smoke
+        ");
+        match rule.event_template.attributes[1] {
+            Expression::Function { ref name, ref args } => {
+                assert_eq!(name, "max");
+                assert_eq!(args.len(), 2);
+            }
+            ref other => panic!("unexpected expression {:?}", other),
+        }
+        assert!(rule.predicates[1].tuple.constraints[1].is_local());
+        assert!(!rule.predicates[1].tuple.constraints[0].is_local());
+
+        let (_, message) = error_at("define fire(area = $x, temp = max(1))
+from smoke[$x = area]");
+        assert_eq!(message, "aggregate `max` cannot be used here");
+    }
+
+    #[test]
+    fn aggregates_and_statics() {
+        let rule = parse_rule("
+            define fire(area = $x, temp = $t)
+            from smoke[$x = area] as smk
+            and temperature[$t = float(max(value)) * 1.5](area == $x) within 1h from smk
+            and sensors[$k = kind](area == $x, threshold < $t) order by threshold desc
+            and not sensors(area == $x, kind == 'broken') as broken
+        ");
+        match rule.predicates[1].ty {
+            PredicateType::EventAggregate { aggregator: Aggregator::Max(1), .. } => (),
+            ref other => panic!("unexpected predicate {:?}", other),
+        }
+        match rule.predicates[2].ty {
+            PredicateType::OrderedStatic { ref ordering, .. } => {
+                assert_eq!(ordering[0].attribute, 2);
+            }
+            ref other => panic!("unexpected predicate {:?}", other),
+        }
+        match rule.predicates[3].ty {
+            PredicateType::StaticNegation => (),
+            ref other => panic!("unexpected predicate {:?}", other),
+        }
+        match rule.predicates[3].tuple.constraints[1] {
+            Expression::BinaryOperation { ref right, .. } => {
+                match **right {
+                    Expression::Immediate { value: Value::Str(ref value) } => {
+                        assert_eq!(value, "broken")
+                    }
+                    ref other => panic!("unexpected operand {:?}", other),
+                }
+            }
+            ref other => panic!("unexpected constraint {:?}", other),
+        }
+    }
+
+    #[test]
+    fn errors_carry_position() {
+        let (pos, message) = error_at("define fire(area = $x, temp = $y)\n\
+                                       from smoke[$x = area]\n\
+                                       and last temperature[$y = valeu] within 5min from smoke");
+        assert_eq!(pos, Position { line: 3, column: 27 });
+        assert_eq!(message, "`temperature` has no attribute `valeu`");
+
+        let (pos, message) = error_at("define fire(area = $x, temp = $y)\n\
+                                       from smoke[$x = area]\n\
+                                       and last temperature[$y = value] within 5min from smk");
+        assert_eq!(pos, Position { line: 3, column: 51 });
+        assert_eq!(message, "unknown predicate `smk`");
+
+        let (pos, message) = error_at("define fire(area = $x)\nfrom smoke[$x = area]");
+        assert_eq!(pos, Position { line: 1, column: 8 });
+        assert_eq!(message, "missing value for `temp`");
+
+        let (pos, _) = error_at("define fire(area = $x temp = 1)\nfrom smoke[$x = area]");
+        assert_eq!(pos, Position { line: 1, column: 23 });
+
+        let (pos, message) = error_at("define fire(area = $x, temp = 1)\n\
+                                       from smoke[$x = area]\n\
+                                       consuming smoke");
+        assert_eq!(pos, Position { line: 3, column: 11 });
+        assert_eq!(message, "only event selections can be consumed, `smoke` is not one");
+
+        let (pos, message) = error_at("define fire(area = $x, temp = 1) from smoke[$x = area];\n\
+                                       define fire(area = $x, temp = 2) from smoke[$x = area]");
+        assert_eq!(pos, Position { line: 2, column: 1 });
+        assert_eq!(message, "expected a single rule definition, found `define`");
+
+        let (pos, message) = error_at("\n  declare rain(area: string) with id 4");
+        assert_eq!(pos, Position { line: 2, column: 3 });
+        assert_eq!(message, "expected a single rule definition, found `declare`");
+
+        let (pos, message) = error_at(";\n");
+        assert_eq!(pos, Position { line: 2, column: 1 });
+        assert_eq!(message, "expected a single rule definition, found end of input");
+
+        let (pos, message) = error_at("define fire(area = $x, temp = 1)\n\
+                                       from smoke[$x = area]\n\
+                                       and last temperature within 9999999999999999d from smoke");
+        assert_eq!(pos, Position { line: 3, column: 29 });
+        assert_eq!(message, "duration out of range");
+
+        let (pos, message) = error_at("define fire(area = $x, temp = 1)\n\
+                                       from smoke[$x = area]\n\
+                                       and last temperature within 9999999999999999 days \
+                                       from smoke");
+        assert_eq!(pos, Position { line: 3, column: 29 });
+        assert_eq!(message, "duration out of range");
+    }
+
+    #[test]
+    fn duplicate_declarations() {
+        let mut parser = Parser::new();
+        parser.parse(DECLARATIONS).unwrap();
+        let err = parser.parse("declare other(x: int) with id 1").unwrap_err();
+        assert_eq!(err.message, "id 1 is already used by `temperature`");
+        assert_eq!(parser.declarations().len(), 4);
+    }
+}
@@ -0,0 +1,621 @@
+use {EventTemplate, Rule, TupleDeclaration, TupleType};
+use AttributeDeclaration;
+use expressions::{BasicType, Expression, Rounding, Value};
+use predicates::*;
+use super::{ParseError, Span};
+use super::ast;
+use super::ast::{Expr, ExprKind, Ident};
+
+fn error<T>(span: Span, message: String) -> Result<T, ParseError> {
+    Err(ParseError {
+        message: message,
+        span: span,
+    })
+}
+
+fn cast_type(name: &str) -> Option<BasicType> {
+    match name {
+        "int" => Some(BasicType::Int),
+        "float" => Some(BasicType::Float),
+        "bool" => Some(BasicType::Bool),
+        "string" => Some(BasicType::Str),
+        _ => None,
+    }
+}
+
+/// Rounding mode given as the second argument of an `int` cast.
+fn rounding(arg: &Expr) -> Result<Rounding, ParseError> {
+    match arg.kind {
+        ExprKind::Attribute(ref mode) if mode == "truncate" => Ok(Rounding::Truncate),
+        ExprKind::Attribute(ref mode) if mode == "round" => Ok(Rounding::Round),
+        _ => error(arg.span, "expected `truncate` or `round` as rounding mode".to_owned()),
+    }
+}
+
+/// Quantile of the percentile shorthands, as `p95(attr)` for `percentile(attr, 0.95)`.
+fn percentile_shorthand(name: &str) -> Option<f64> {
+    if !name.starts_with('p') || name.len() > 3 || !name[1..].chars().all(|c| c.is_digit(10)) {
+        return None;
+    }
+    match name[1..].parse::<u32>() {
+        Ok(n) if n > 0 => Some(n as f64 / 100.0),
+        _ => None,
+    }
+}
+
+fn is_aggregator(name: &str, args: &[Expr]) -> bool {
+    match name {
+        "avg" | "sum" | "min" | "max" | "stddev" | "variance" | "count_distinct" | "first" |
+        "last" | "any" | "all" | "median" => args.len() == 1,
+        "percentile" => args.len() == 2,
+        "count" => args.is_empty(),
+        _ => percentile_shorthand(name).is_some() && args.len() == 1,
+    }
+}
+
+fn contains_aggregator(expr: &Expr) -> bool {
+    match expr.kind {
+        ExprKind::Call(ref name, ref args) => {
+            is_aggregator(name, args) || args.iter().any(contains_aggregator)
+        }
+        ExprKind::Unary(_, ref expr) => contains_aggregator(expr),
+        ExprKind::Binary(_, ref left, ref right) => {
+            contains_aggregator(left) || contains_aggregator(right)
+        }
+        _ => false,
+    }
+}
+
+fn find_attribute(tuple: &TupleDeclaration, name: &Ident) -> Result<usize, ParseError> {
+    tuple.attributes
+        .iter()
+        .position(|attr| attr.name == name.name)
+        .ok_or_else(|| {
+            ParseError {
+                message: format!("`{}` has no attribute `{}`", tuple.name, name.name),
+                span: name.span,
+            }
+        })
+}
+
+/// What an expression is allowed to refer to, besides the parameters already declared.
+enum Current<'a> {
+    Nothing,
+    Tuple(&'a TupleDeclaration),
+    Aggregate(&'a TupleDeclaration),
+}
+
+struct Scope<'a> {
+    current: Current<'a>,
+    parameters: &'a [(String, (usize, usize))],
+    /// Aliases of the predicates preceding the current one
+    aliases: &'a [String],
+    aggregator: Option<Aggregator>,
+}
+
+impl<'a> Scope<'a> {
+    fn new(current: Current<'a>,
+           parameters: &'a [(String, (usize, usize))],
+           aliases: &'a [String])
+           -> Self {
+        Scope {
+            current: current,
+            parameters: parameters,
+            aliases: aliases,
+            aggregator: None,
+        }
+    }
+
+    /// Resolves `timestamp()`, the time of the current event,
+    /// or `timestamp(alias)`, the time of the event matched by a previous predicate.
+    fn timestamp(&self, args: &[Expr], span: Span) -> Result<Expression, ParseError> {
+        match (args.len(), args.first().map(|arg| &arg.kind), &self.current) {
+            (0, _, &Current::Tuple(_)) => {
+                Ok(Expression::Timestamp { predicate: self.aliases.len() })
+            }
+            (0, _, _) => error(span, "there is no current event to take the time of".to_owned()),
+            (1, Some(&ExprKind::Attribute(ref alias)), _) => {
+                self.aliases
+                    .iter()
+                    .position(|it| it == alias)
+                    .map(|predicate| Expression::Timestamp { predicate: predicate })
+                    .ok_or_else(|| {
+                        ParseError {
+                            message: format!("unknown predicate `{}`", alias),
+                            span: args[0].span,
+                        }
+                    })
+            }
+            _ => error(span, "`timestamp` expects a predicate name or no arguments".to_owned()),
+        }
+    }
+
+    fn aggregator(&mut self,
+                  tuple: &TupleDeclaration,
+                  name: &str,
+                  args: &[Expr],
+                  span: Span)
+                  -> Result<Expression, ParseError> {
+        let attribute = match args.first() {
+            Some(&Expr { kind: ExprKind::Attribute(ref attr), span }) => {
+                Some(find_attribute(tuple,
+                                    &Ident {
+                                        name: attr.clone(),
+                                        span: span,
+                                    })?)
+            }
+            Some(arg) => {
+                return error(arg.span,
+                             "aggregates can only be computed over attributes".to_owned())
+            }
+            None => None,
+        };
+        let aggregator = match (name, attribute) {
+            ("avg", Some(attr)) => Aggregator::Avg(attr),
+            ("sum", Some(attr)) => Aggregator::Sum(attr),
+            ("min", Some(attr)) => Aggregator::Min(attr),
+            ("max", Some(attr)) => Aggregator::Max(attr),
+            ("stddev", Some(attr)) => Aggregator::StdDev(attr),
+            ("variance", Some(attr)) => Aggregator::Variance(attr),
+            ("count_distinct", Some(attr)) => Aggregator::CountDistinct(attr),
+            ("first", Some(attr)) => Aggregator::First(attr),
+            ("last", Some(attr)) => Aggregator::Last(attr),
+            ("any", Some(attr)) => Aggregator::Any(attr),
+            ("all", Some(attr)) => Aggregator::All(attr),
+            ("median", Some(attr)) => Aggregator::Median(attr),
+            ("percentile", Some(attr)) => {
+                match args[1].kind {
+                    ExprKind::Literal(Value::Float(q)) if 0.0 <= q && q <= 1.0 => {
+                        Aggregator::Percentile(attr, q)
+                    }
+                    _ => {
+                        return error(args[1].span,
+                                     "the quantile must be a float literal between 0 and 1"
+                                         .to_owned())
+                    }
+                }
+            }
+            ("count", None) => Aggregator::Count,
+            (name, Some(attr)) => Aggregator::Percentile(attr, percentile_shorthand(name).unwrap()),
+            _ => unreachable!(),
+        };
+        if self.aggregator.is_some() {
+            return error(span, "only one aggregate is allowed per predicate".to_owned());
+        }
+        self.aggregator = Some(aggregator);
+        Ok(Expression::Aggregate)
+    }
+
+    fn resolve(&mut self, expr: &Expr) -> Result<Expression, ParseError> {
+        match expr.kind {
+            ExprKind::Literal(ref value) => Ok(Expression::Immediate { value: value.clone() }),
+            ExprKind::Attribute(ref name) => {
+                let ident = Ident {
+                    name: name.clone(),
+                    span: expr.span,
+                };
+                match self.current {
+                    Current::Tuple(tuple) => {
+                        find_attribute(tuple, &ident)
+                            .map(|attribute| Expression::Reference { attribute: attribute })
+                    }
+                    Current::Aggregate(_) => {
+                        error(expr.span,
+                              format!("attribute `{}` can only be referenced inside the \
+                                       aggregate",
+                                      name))
+                    }
+                    Current::Nothing => {
+                        error(expr.span,
+                              format!("attribute `{}` cannot be referenced here", name))
+                    }
+                }
+            }
+            ExprKind::Parameter(ref name) => {
+                self.parameters
+                    .iter()
+                    .rev()
+                    .find(|&&(ref param, _)| param == name)
+                    .map(|&(_, (predicate, parameter))| {
+                        Expression::Parameter {
+                            predicate: predicate,
+                            parameter: parameter,
+                        }
+                    })
+                    .ok_or_else(|| {
+                        ParseError {
+                            message: format!("unknown parameter `${}`", name),
+                            span: expr.span,
+                        }
+                    })
+            }
+            ExprKind::Call(ref name, ref args) if name == "timestamp" => {
+                self.timestamp(args, expr.span)
+            }
+            ExprKind::Call(ref name, ref args) => {
+                if let Current::Aggregate(tuple) = self.current {
+                    if is_aggregator(name, args) {
+                        return self.aggregator(tuple, name, args, expr.span);
+                    }
+                }
+                match (cast_type(name), args.len()) {
+                    (Some(ty), 1) => {
+                        Ok(Expression::Cast {
+                            ty: ty,
+                            rounding: Rounding::Truncate,
+                            expression: Box::new(self.resolve(&args[0])?),
+                        })
+                    }
+                    (Some(BasicType::Int), 2) => {
+                        Ok(Expression::Cast {
+                            ty: BasicType::Int,
+                            rounding: rounding(&args[1])?,
+                            expression: Box::new(self.resolve(&args[0])?),
+                        })
+                    }
+                    (Some(_), _) => {
+                        error(expr.span, format!("`{}` expects exactly one argument", name))
+                    }
+                    (None, _) if is_aggregator(name, args) => {
+                        error(expr.span,
+                              format!("aggregate `{}` cannot be used here", name))
+                    }
+                    (None, _) => {
+                        // Functions are resolved by the engine, that knows which ones exist
+                        Ok(Expression::Function {
+                            name: name.clone(),
+                            args: args.iter()
+                                .map(|arg| self.resolve(arg))
+                                .collect::<Result<_, _>>()?,
+                        })
+                    }
+                }
+            }
+            ExprKind::Unary(ref operator, ref expression) => {
+                Ok(Expression::UnaryOperation {
+                    operator: operator.clone(),
+                    expression: Box::new(self.resolve(expression)?),
+                })
+            }
+            ExprKind::Binary(ref operator, ref left, ref right) => {
+                Ok(Expression::BinaryOperation {
+                    operator: operator.clone(),
+                    left: Box::new(self.resolve(left)?),
+                    right: Box::new(self.resolve(right)?),
+                })
+            }
+        }
+    }
+}
+
+pub fn declaration(tuples: &[TupleDeclaration],
+                   decl: &ast::Declaration)
+                   -> Result<TupleDeclaration, ParseError> {
+    if tuples.iter().any(|tuple| tuple.name == decl.name.name) {
+        return error(decl.name.span,
+                     format!("tuple `{}` is already declared", decl.name.name));
+    }
+    if let Some(tuple) = tuples.iter().find(|tuple| tuple.id == decl.id) {
+        return error(decl.id_span,
+                     format!("id {} is already used by `{}`", decl.id, tuple.name));
+    }
+    for (i, attr) in decl.attributes.iter().enumerate() {
+        if decl.attributes[..i].iter().any(|it| it.name.name == attr.name.name) {
+            return error(attr.name.span,
+                         format!("attribute `{}` is declared twice", attr.name.name));
+        }
+    }
+    Ok(TupleDeclaration {
+        ty: decl.ty.clone(),
+        id: decl.id,
+        name: decl.name.name.clone(),
+        attributes: decl.attributes
+            .iter()
+            .map(|attr| {
+                AttributeDeclaration {
+                    name: attr.name.name.clone(),
+                    ty: attr.ty.clone(),
+                    nullable: attr.nullable,
+                }
+            })
+            .collect(),
+    })
+}
+
+struct RuleResolver<'a> {
+    tuples: &'a [TupleDeclaration],
+    aliases: Vec<String>,
+    parameters: Vec<(String, (usize, usize))>,
+}
+
+impl<'a> RuleResolver<'a> {
+    fn tuple(&self, name: &Ident) -> Result<&'a TupleDeclaration, ParseError> {
+        self.tuples.iter().find(|tuple| tuple.name == name.name).ok_or_else(|| {
+            ParseError {
+                message: format!("unknown tuple `{}`", name.name),
+                span: name.span,
+            }
+        })
+    }
+
+    fn alias(&self, name: &Ident) -> Result<usize, ParseError> {
+        self.aliases.iter().position(|alias| *alias == name.name).ok_or_else(|| {
+            ParseError {
+                message: format!("unknown predicate `{}`", name.name),
+                span: name.span,
+            }
+        })
+    }
+
+    fn declare_parameter(&mut self, name: &Ident, idx: (usize, usize)) -> Result<(), ParseError> {
+        if self.parameters.iter().any(|&(ref param, _)| *param == name.name) {
+            return error(name.span,
+                         format!("parameter `${}` is already declared", name.name));
+        }
+        self.parameters.push((name.name.clone(), idx));
+        Ok(())
+    }
+
+    fn parameters(&mut self,
+                  i: usize,
+                  tuple: &TupleDeclaration,
+                  params: &[ast::ParamDecl])
+                  -> Result<Vec<ParameterDeclaration>, ParseError> {
+        let mut res = Vec::new();
+        for (j, param) in params.iter().enumerate() {
+            let expression = Scope::new(Current::Tuple(tuple), &self.parameters, &self.aliases)
+                .resolve(&param.expression)?;
+            self.declare_parameter(&param.name, (i, j))?;
+            res.push(ParameterDeclaration {
+                name: param.name.name.clone(),
+                expression: expression,
+            });
+        }
+        Ok(res)
+    }
+
+    fn aggregate(&mut self,
+                 i: usize,
+                 tuple: &TupleDeclaration,
+                 pred: &ast::Predicate)
+                 -> Result<(Aggregator, ParameterDeclaration), ParseError> {
+        if pred.parameters.len() != 1 {
+            return error(pred.span,
+                         "aggregate predicates must declare exactly one parameter".to_owned());
+        }
+        let param = &pred.parameters[0];
+        let (expression, aggregator) = {
+            let mut scope = Scope::new(Current::Aggregate(tuple), &self.parameters, &self.aliases);
+            let expression = scope.resolve(&param.expression)?;
+            (expression, scope.aggregator.unwrap())
+        };
+        self.declare_parameter(&param.name, (i, 0))?;
+        Ok((aggregator,
+            ParameterDeclaration {
+            name: param.name.name.clone(),
+            expression: expression,
+        }))
+    }
+
+    fn constraints(&self,
+                   tuple: &TupleDeclaration,
+                   constraints: &[Expr])
+                   -> Result<Vec<Expression>, ParseError> {
+        constraints.iter()
+            .map(|expr| {
+                Scope::new(Current::Tuple(tuple), &self.parameters, &self.aliases).resolve(expr)
+            })
+            .collect()
+    }
+
+    fn timing(&self, timing: &Option<ast::Timing>, span: Span) -> Result<Timing, ParseError> {
+        let timing = match *timing {
+            Some(ref timing) => timing,
+            None => return error(span, "event predicates require a timing".to_owned()),
+        };
+        let (upper, bound) = match timing.kind {
+            ast::TimingKind::Within { window, ref from } => {
+                (self.alias(from)?, TimingBound::Within { window: window })
+            }
+            ast::TimingKind::Between { ref lower, ref upper } => {
+                (self.alias(upper)?, TimingBound::Between { lower: self.alias(lower)? })
+            }
+            ast::TimingKind::Last { count, ref from } => {
+                (self.alias(from)?, TimingBound::Last { count: count })
+            }
+        };
+        Ok(Timing {
+            upper: upper,
+            bound: bound,
+            lower_boundary: timing.lower_boundary,
+            upper_boundary: timing.upper_boundary,
+        })
+    }
+
+    fn predicate(&mut self, i: usize, pred: &ast::Predicate) -> Result<Predicate, ParseError> {
+        let tuple = self.tuple(&pred.tuple)?;
+        let alias = pred.alias.as_ref().unwrap_or(&pred.tuple);
+        if self.aliases.contains(&alias.name) {
+            return error(alias.span,
+                         format!("predicate `{}` is already defined, use `as` to rename it",
+                                 alias.name));
+        }
+
+        let is_aggregate = pred.parameters.iter().any(|param| contains_aggregator(&param.expression));
+        let is_static = if let TupleType::Static = tuple.ty { true } else { false };
+        if i == 0 && is_static {
+            return error(pred.tuple.span,
+                         format!("the trigger `{}` must be an event", tuple.name));
+        }
+        if i == 0 && is_aggregate {
+            return error(pred.span, "the trigger cannot compute aggregates".to_owned());
+        }
+        if pred.negated && !pred.parameters.is_empty() {
+            return error(pred.span, "negated predicates cannot declare parameters".to_owned());
+        }
+        if (is_aggregate || pred.negated || is_static) && pred.selection.is_some() {
+            return error(pred.span,
+                         "selection is only allowed on event predicates".to_owned());
+        }
+        if !pred.ordering.is_empty() && !(is_static && !is_aggregate && !pred.negated) {
+            return error(pred.span,
+                         "ordering is only allowed on static predicates".to_owned());
+        }
+        if let (true, Some(ref timing)) = (is_static, pred.timing.as_ref()) {
+            return error(timing.span, "static predicates cannot have a timing".to_owned());
+        }
+
+        // Aggregate constraints are evaluated before the aggregate itself is computed,
+        // so they cannot refer to the parameter declared by the predicate.
+        let early_constraints = if is_aggregate {
+            Some(self.constraints(tuple, &pred.constraints)?)
+        } else {
+            None
+        };
+
+        let ty = if i == 0 {
+            PredicateType::Trigger { parameters: self.parameters(i, tuple, &pred.parameters)? }
+        } else if pred.negated && is_static {
+            PredicateType::StaticNegation
+        } else if pred.negated {
+            PredicateType::EventNegation { timing: self.timing(&pred.timing, pred.span)? }
+        } else if is_aggregate && is_static {
+            let (aggregator, parameter) = self.aggregate(i, tuple, pred)?;
+            PredicateType::StaticAggregate {
+                aggregator: aggregator,
+                parameter: parameter,
+            }
+        } else if is_aggregate {
+            let timing = self.timing(&pred.timing, pred.span)?;
+            let (aggregator, parameter) = self.aggregate(i, tuple, pred)?;
+            PredicateType::EventAggregate {
+                aggregator: aggregator,
+                parameter: parameter,
+                timing: timing,
+            }
+        } else if is_static && !pred.ordering.is_empty() {
+            let parameters = self.parameters(i, tuple, &pred.parameters)?;
+            let ordering = pred.ordering
+                .iter()
+                .map(|ord| {
+                    find_attribute(tuple, &ord.attribute).map(|attribute| {
+                        Ordering {
+                            attribute: attribute,
+                            direction: ord.direction.clone(),
+                        }
+                    })
+                })
+                .collect::<Result<_, _>>()?;
+            PredicateType::OrderedStatic {
+                parameters: parameters,
+                ordering: ordering,
+            }
+        } else if is_static {
+            PredicateType::UnorderedStatic {
+                parameters: self.parameters(i, tuple, &pred.parameters)?,
+            }
+        } else {
+            let timing = self.timing(&pred.timing, pred.span)?;
+            PredicateType::Event {
+                selection: pred.selection.clone().unwrap_or(EventSelection::Each),
+                parameters: self.parameters(i, tuple, &pred.parameters)?,
+                timing: timing,
+            }
+        };
+
+        let constraints = match early_constraints {
+            Some(constraints) => constraints,
+            None => self.constraints(tuple, &pred.constraints)?,
+        };
+        self.aliases.push(alias.name.clone());
+
+        Ok(Predicate {
+            ty: ty,
+            tuple: ConstrainedTuple {
+                ty_id: tuple.id,
+                constraints: constraints,
+                alias: alias.name.clone(),
+            },
+        })
+    }
+
+    fn template(&self, rule: &ast::Rule) -> Result<EventTemplate, ParseError> {
+        let tuple = self.tuple(&rule.template)?;
+        if let TupleType::Static = tuple.ty {
+            return error(rule.template.span,
+                         format!("the rule cannot produce the static tuple `{}`", tuple.name));
+        }
+        let mut attributes = vec![None; tuple.attributes.len()];
+        for assignment in &rule.assignments {
+            let idx = find_attribute(tuple, &assignment.attribute)?;
+            if attributes[idx].is_some() {
+                return error(assignment.attribute.span,
+                             format!("attribute `{}` is assigned twice",
+                                     assignment.attribute.name));
+            }
+            let expression = Scope::new(Current::Nothing, &self.parameters, &self.aliases)
+                .resolve(&assignment.expression)?;
+            attributes[idx] = Some(expression);
+        }
+        let missing = tuple.attributes
+            .iter()
+            .zip(attributes.iter())
+            .filter(|&(_, expr)| expr.is_none())
+            .map(|(attr, _)| format!("`{}`", attr.name))
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return error(rule.template_span,
+                         format!("missing value for {}", missing.join(", ")));
+        }
+        Ok(EventTemplate {
+            ty_id: tuple.id,
+            attributes: attributes.into_iter().map(Option::unwrap).collect(),
+        })
+    }
+}
+
+pub fn rule(tuples: &[TupleDeclaration], rule: &ast::Rule) -> Result<Rule, ParseError> {
+    let mut resolver = RuleResolver {
+        tuples: tuples,
+        aliases: Vec::new(),
+        parameters: Vec::new(),
+    };
+
+    let predicates = rule.predicates
+        .iter()
+        .enumerate()
+        .map(|(i, pred)| resolver.predicate(i, pred))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let filters = rule.filters
+        .iter()
+        .map(|expr| {
+            Scope::new(Current::Nothing, &resolver.parameters, &resolver.aliases).resolve(expr)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let event_template = resolver.template(rule)?;
+
+    let consuming = rule.consuming
+        .iter()
+        .map(|alias| {
+            resolver.alias(alias).and_then(|idx| {
+                if let PredicateType::Event { .. } = predicates[idx].ty {
+                    Ok(idx)
+                } else {
+                    error(alias.span,
+                          format!("only event selections can be consumed, `{}` is not one",
+                                  alias.name))
+                }
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(Rule {
+        predicates: predicates,
+        filters: filters,
+        event_template: event_template,
+        consuming: consuming,
+        accuracy: rule.accuracy,
+    })
+}
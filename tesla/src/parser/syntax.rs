@@ -0,0 +1,609 @@
+use TupleType;
+use expressions::{BasicType, BinaryOperator, UnaryOperator, Value};
+use predicates::{Boundary, EventSelection, Order};
+use super::{ParseError, Span};
+use super::ast::*;
+use super::lexer::{Spanned, Token, checked_duration, duration_unit};
+
+fn binary_operator(token: &Token) -> Option<BinaryOperator> {
+    match *token {
+        Token::Plus => Some(BinaryOperator::Plus),
+        Token::Minus => Some(BinaryOperator::Minus),
+        Token::Times => Some(BinaryOperator::Times),
+        Token::Division => Some(BinaryOperator::Division),
+        Token::Percent => Some(BinaryOperator::Remainder),
+        Token::Ampersand => Some(BinaryOperator::BitwiseAnd),
+        Token::Pipe => Some(BinaryOperator::BitwiseOr),
+        Token::Caret => Some(BinaryOperator::BitwiseXor),
+        Token::ShiftLeft => Some(BinaryOperator::ShiftLeft),
+        Token::ShiftRight => Some(BinaryOperator::ShiftRight),
+        Token::Equal | Token::Assign => Some(BinaryOperator::Equal),
+        Token::NotEqual => Some(BinaryOperator::NotEqual),
+        Token::GreaterThan => Some(BinaryOperator::GreaterThan),
+        Token::GreaterEqual => Some(BinaryOperator::GreaterEqual),
+        Token::LowerThan => Some(BinaryOperator::LowerThan),
+        Token::LowerEqual => Some(BinaryOperator::LowerEqual),
+        Token::And => Some(BinaryOperator::And),
+        Token::Or => Some(BinaryOperator::Or),
+        Token::Ident(ref name) if name == "and" => Some(BinaryOperator::And),
+        Token::Ident(ref name) if name == "or" => Some(BinaryOperator::Or),
+        Token::Ident(ref name) if name == "xor" => Some(BinaryOperator::Xor),
+        Token::Ident(ref name) if name == "matches" => Some(BinaryOperator::Matches),
+        Token::Ident(ref name) if name == "like" => Some(BinaryOperator::Like),
+        _ => None,
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match *token {
+        Token::Ident(ref name) => format!("`{}`", name),
+        Token::Param(ref name) => format!("`${}`", name),
+        Token::Int(value) => format!("`{}`", value),
+        Token::Float(value) => format!("`{}`", value),
+        Token::Str(ref value) => format!("{:?}", value),
+        Token::Duration(_) => "duration".to_owned(),
+        Token::Eof => "end of input".to_owned(),
+        ref other => format!("{:?}", other),
+    }
+}
+
+pub struct Syntax {
+    tokens: Vec<Spanned>,
+    pos: usize,
+}
+
+impl Syntax {
+    pub fn new(tokens: Vec<Spanned>) -> Self {
+        Syntax {
+            tokens: tokens,
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> &Token { &self.tokens[self.pos].token }
+
+    fn peek_ahead(&self, n: usize) -> &Token {
+        let idx = ::std::cmp::min(self.pos + n, self.tokens.len() - 1);
+        &self.tokens[idx].token
+    }
+
+    fn span(&self) -> Span { self.tokens[self.pos].span }
+
+    fn last_span(&self) -> Span { self.tokens[self.pos.saturating_sub(1)].span }
+
+    fn since(&self, start: Span) -> Span { start.to(self.last_span()) }
+
+    fn bump(&mut self) -> Spanned {
+        let res = self.tokens[self.pos].clone();
+        if res.token != Token::Eof {
+            self.pos += 1;
+        }
+        res
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, ParseError> {
+        Err(ParseError {
+            message: format!("expected {}, found {}", expected, describe(self.peek())),
+            span: self.span(),
+        })
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        if let Token::Ident(ref name) = *self.peek() { name == keyword } else { false }
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.is_keyword(keyword) {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            self.unexpected(&format!("`{}`", keyword))
+        }
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == token {
+            self.bump();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &Token, expected: &str) -> Result<(), ParseError> {
+        if self.eat(token) { Ok(()) } else { self.unexpected(expected) }
+    }
+
+    fn ident(&mut self) -> Result<Ident, ParseError> {
+        if let Token::Ident(ref name) = *self.peek() {
+            let name = name.clone();
+            let span = self.bump().span;
+            Ok(Ident {
+                name: name,
+                span: span,
+            })
+        } else {
+            self.unexpected("an identifier")
+        }
+    }
+
+    fn list<T, F>(&mut self, close: &Token, expected: &str, mut item: F) -> Result<Vec<T>, ParseError>
+        where F: FnMut(&mut Self) -> Result<T, ParseError>
+    {
+        let mut res = Vec::new();
+        if self.eat(close) {
+            return Ok(res);
+        }
+        loop {
+            res.push(item(self)?);
+            if self.eat(close) {
+                return Ok(res);
+            }
+            self.expect(&Token::Comma, &format!("`,` or {}", expected))?;
+        }
+    }
+
+    pub fn statements(&mut self) -> Result<Vec<Statement>, ParseError> {
+        let mut res = Vec::new();
+        loop {
+            while self.eat(&Token::Semicolon) {}
+            if *self.peek() == Token::Eof {
+                return Ok(res);
+            } else if self.is_keyword("declare") {
+                res.push(Statement::Declaration(self.declaration()?));
+            } else if self.is_keyword("define") {
+                res.push(Statement::Rule(self.rule()?));
+            } else {
+                return self.unexpected("`declare` or `define`");
+            }
+        }
+    }
+
+    /// Parses a source made of a single rule definition, pointing the errors
+    /// at the statement that is missing or in excess.
+    pub fn single_rule(&mut self) -> Result<Rule, ParseError> {
+        while self.eat(&Token::Semicolon) {}
+        if !self.is_keyword("define") {
+            return self.unexpected("a single rule definition");
+        }
+        let rule = self.rule()?;
+        while self.eat(&Token::Semicolon) {}
+        if *self.peek() != Token::Eof {
+            return self.unexpected("a single rule definition");
+        }
+        Ok(rule)
+    }
+
+    fn basic_type(&mut self) -> Result<BasicType, ParseError> {
+        let ident = self.ident()?;
+        match &ident.name as &str {
+            "int" | "integer" => Ok(BasicType::Int),
+            "float" | "double" => Ok(BasicType::Float),
+            "bool" | "boolean" => Ok(BasicType::Bool),
+            "string" | "str" => Ok(BasicType::Str),
+            "timestamp" => Ok(BasicType::Timestamp),
+            "duration" => Ok(BasicType::Duration),
+            _ => {
+                Err(ParseError {
+                    message: format!("unknown type `{}`", ident.name),
+                    span: ident.span,
+                })
+            }
+        }
+    }
+
+    fn declaration(&mut self) -> Result<Declaration, ParseError> {
+        self.expect_keyword("declare")?;
+        let ty = match (self.peek().clone(), self.peek_ahead(1).clone()) {
+            (Token::Ident(ref kind), Token::Ident(_)) if kind == "event" => {
+                self.bump();
+                TupleType::Event
+            }
+            (Token::Ident(ref kind), Token::Ident(_)) if kind == "static" => {
+                self.bump();
+                TupleType::Static
+            }
+            _ => TupleType::Event,
+        };
+        let name = self.ident()?;
+        self.expect(&Token::LeftParen, "`(`")?;
+        let attributes = self.list(&Token::RightParen, "`)`", |this| {
+                let name = this.ident()?;
+                this.expect(&Token::Colon, "`:`")?;
+                let ty = this.basic_type()?;
+                let nullable = this.eat(&Token::Question);
+                Ok(AttributeDecl {
+                    name: name,
+                    ty: ty,
+                    nullable: nullable,
+                })
+            })?;
+        self.expect_keyword("with")?;
+        self.expect_keyword("id")?;
+        let id_span = self.span();
+        let id = match *self.peek() {
+            Token::Int(id) if id >= 0 => id as usize,
+            _ => return self.unexpected("a non negative tuple id"),
+        };
+        self.bump();
+        Ok(Declaration {
+            ty: ty,
+            name: name,
+            attributes: attributes,
+            id: id,
+            id_span: id_span,
+        })
+    }
+
+    fn rule(&mut self) -> Result<Rule, ParseError> {
+        self.expect_keyword("define")?;
+        let template = self.ident()?;
+        self.expect(&Token::LeftParen, "`(`")?;
+        let assignments = self.list(&Token::RightParen, "`)`", |this| {
+                let attribute = this.ident()?;
+                this.expect(&Token::Assign, "`=`")?;
+                let expression = this.expression()?;
+                Ok(Assignment {
+                    attribute: attribute,
+                    expression: expression,
+                })
+            })?;
+        let template_span = self.since(template.span);
+
+        self.expect_keyword("from")?;
+        let mut predicates = vec![self.predicate(true)?];
+        while self.eat_keyword("and") {
+            predicates.push(self.predicate(false)?);
+        }
+
+        let mut filters = Vec::new();
+        if self.eat_keyword("where") {
+            filters.push(self.expression()?);
+            while self.eat(&Token::Comma) {
+                filters.push(self.expression()?);
+            }
+        }
+
+        let mut consuming = Vec::new();
+        if self.eat_keyword("consuming") {
+            consuming.push(self.ident()?);
+            while self.eat(&Token::Comma) {
+                consuming.push(self.ident()?);
+            }
+        }
+
+        let mut accuracy = None;
+        if self.eat_keyword("with") {
+            self.expect_keyword("accuracy")?;
+            accuracy = match *self.peek() {
+                Token::Float(value) if value > 0.0 && value < 1.0 => Some(value),
+                _ => return self.unexpected("an accuracy between 0 and 1"),
+            };
+            self.bump();
+        }
+
+        Ok(Rule {
+            template: template,
+            assignments: assignments,
+            template_span: template_span,
+            predicates: predicates,
+            filters: filters,
+            consuming: consuming,
+            accuracy: accuracy,
+        })
+    }
+
+    fn is_modifier(&self, keyword: &str) -> bool {
+        self.is_keyword(keyword) &&
+        if let Token::Ident(_) = *self.peek_ahead(1) { true } else { false }
+    }
+
+    fn predicate(&mut self, trigger: bool) -> Result<Predicate, ParseError> {
+        let start = self.span();
+        let negated = !trigger && self.is_modifier("not") && {
+            self.bump();
+            true
+        };
+        let selection = if trigger || negated {
+            None
+        } else if self.is_modifier("each") {
+            self.bump();
+            Some(EventSelection::Each)
+        } else if self.is_modifier("first") {
+            self.bump();
+            Some(EventSelection::First)
+        } else if self.is_modifier("last") {
+            self.bump();
+            Some(EventSelection::Last)
+        } else {
+            None
+        };
+
+        let tuple = self.ident()?;
+
+        let parameters = if self.eat(&Token::LeftBracket) {
+            self.list(&Token::RightBracket, "`]`", |this| {
+                    let name = if let Token::Param(ref name) = *this.peek() {
+                        name.clone()
+                    } else {
+                        return this.unexpected("a parameter declaration");
+                    };
+                    let span = this.bump().span;
+                    this.expect(&Token::Assign, "`=`")?;
+                    Ok(ParamDecl {
+                        name: Ident {
+                            name: name,
+                            span: span,
+                        },
+                        expression: this.expression()?,
+                    })
+                })?
+        } else {
+            Vec::new()
+        };
+
+        let constraints = if self.eat(&Token::LeftParen) {
+            self.list(&Token::RightParen, "`)`", |this| this.expression())?
+        } else {
+            Vec::new()
+        };
+
+        let alias = if self.eat_keyword("as") { Some(self.ident()?) } else { None };
+
+        let timing_start = self.span();
+        let timing = if !trigger && self.eat_keyword("within") {
+            // Either a time window or a number of events
+            let (window, count) = match self.peek().clone() {
+                Token::Duration(window) => {
+                    self.bump();
+                    (Some(window), 0)
+                }
+                Token::Int(value) => {
+                    let start = self.span();
+                    self.bump();
+                    let unit = self.ident()?;
+                    match duration_unit(&unit.name) {
+                        Some(unit) => {
+                            match checked_duration(unit, value) {
+                                Some(window) => (Some(window), 0),
+                                None => {
+                                    return Err(ParseError {
+                                        message: "duration out of range".to_owned(),
+                                        span: self.since(start),
+                                    })
+                                }
+                            }
+                        }
+                        None if unit.name == "events" => (None, value as usize),
+                        None => {
+                            return Err(ParseError {
+                                message: format!("unknown time unit `{}`", unit.name),
+                                span: unit.span,
+                            })
+                        }
+                    }
+                }
+                _ => return self.unexpected("a time window"),
+            };
+            self.expect_keyword("from")?;
+            let from = self.ident()?;
+            let kind = match window {
+                Some(window) => {
+                    TimingKind::Within {
+                        window: window,
+                        from: from,
+                    }
+                }
+                None => {
+                    TimingKind::Last {
+                        count: count,
+                        from: from,
+                    }
+                }
+            };
+            let (lower_boundary, upper_boundary) = self.boundaries()?;
+            Some(Timing {
+                kind: kind,
+                lower_boundary: lower_boundary,
+                upper_boundary: upper_boundary,
+                span: self.since(timing_start),
+            })
+        } else if !trigger && self.eat_keyword("between") {
+            let lower = self.ident()?;
+            self.expect_keyword("and")?;
+            let upper = self.ident()?;
+            let (lower_boundary, upper_boundary) = self.boundaries()?;
+            Some(Timing {
+                kind: TimingKind::Between {
+                    lower: lower,
+                    upper: upper,
+                },
+                lower_boundary: lower_boundary,
+                upper_boundary: upper_boundary,
+                span: self.since(timing_start),
+            })
+        } else {
+            None
+        };
+
+        let mut ordering = Vec::new();
+        if !trigger && self.eat_keyword("order") {
+            self.expect_keyword("by")?;
+            loop {
+                let attribute = self.ident()?;
+                let direction = if self.eat_keyword("desc") {
+                    Order::Desc
+                } else {
+                    self.eat_keyword("asc");
+                    Order::Asc
+                };
+                ordering.push(OrderingDecl {
+                    attribute: attribute,
+                    direction: direction,
+                });
+                if !self.eat(&Token::Comma) {
+                    break;
+                }
+            }
+        }
+
+        Ok(Predicate {
+            negated: negated,
+            selection: selection,
+            tuple: tuple,
+            parameters: parameters,
+            constraints: constraints,
+            alias: alias,
+            timing: timing,
+            ordering: ordering,
+            span: self.since(start),
+        })
+    }
+
+    /// Optional boundaries of a window in interval notation, as in `bounds [)`:
+    /// a bracket includes the events at the time of that end, a parenthesis excludes them.
+    fn boundaries(&mut self) -> Result<(Boundary, Boundary), ParseError> {
+        if !self.eat_keyword("bounds") {
+            return Ok((Boundary::Closed, Boundary::Open));
+        }
+        let lower = if self.eat(&Token::LeftBracket) {
+            Boundary::Closed
+        } else {
+            self.expect(&Token::LeftParen, "`[` or `(`")?;
+            Boundary::Open
+        };
+        let upper = if self.eat(&Token::RightBracket) {
+            Boundary::Closed
+        } else {
+            self.expect(&Token::RightParen, "`]` or `)`")?;
+            Boundary::Open
+        };
+        Ok((lower, upper))
+    }
+
+    pub fn expression(&mut self) -> Result<Expr, ParseError> { self.binary(0) }
+
+    fn binary(&mut self, min_precedence: usize) -> Result<Expr, ParseError> {
+        let mut left = self.unary()?;
+        loop {
+            // Postfix `is [not] null` binds as the comparisons
+            if self.is_keyword("is") && BinaryOperator::Equal.precedence() >= min_precedence {
+                self.bump();
+                let operator = if self.eat_keyword("not") {
+                    UnaryOperator::IsNotNull
+                } else {
+                    UnaryOperator::IsNull
+                };
+                self.expect_keyword("null")?;
+                let span = self.since(left.span);
+                left = Expr {
+                    kind: ExprKind::Unary(operator, Box::new(left)),
+                    span: span,
+                };
+                continue;
+            }
+            let operator = match binary_operator(self.peek()) {
+                Some(ref op) if op.precedence() >= min_precedence => op.clone(),
+                _ => return Ok(left),
+            };
+            self.bump();
+            let right = self.binary(operator.precedence() + 1)?;
+            let span = left.span.to(right.span);
+            left = Expr {
+                kind: ExprKind::Binary(operator, Box::new(left), Box::new(right)),
+                span: span,
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        let start = self.span();
+        let operator = if self.eat(&Token::Minus) {
+            // A minus sign right before a number is part of its literal
+            let value = match *self.peek() {
+                Token::Int(value) => Some(Value::Int(-value)),
+                Token::Float(value) => Some(Value::Float(-value)),
+                Token::Duration(value) => Some(Value::Duration(-value)),
+                _ => None,
+            };
+            if let Some(value) = value {
+                self.bump();
+                return Ok(Expr {
+                    kind: ExprKind::Literal(value),
+                    span: self.since(start),
+                });
+            }
+            UnaryOperator::Minus
+        } else if self.eat(&Token::Bang) || self.eat_keyword("not") {
+            UnaryOperator::Not
+        } else {
+            return self.primary();
+        };
+        let expression = self.unary()?;
+        Ok(Expr {
+            span: start.to(expression.span),
+            kind: ExprKind::Unary(operator, Box::new(expression)),
+        })
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        let start = self.span();
+        let kind = match self.peek().clone() {
+            Token::Int(value) => {
+                self.bump();
+                ExprKind::Literal(Value::Int(value))
+            }
+            Token::Float(value) => {
+                self.bump();
+                ExprKind::Literal(Value::Float(value))
+            }
+            Token::Str(value) => {
+                self.bump();
+                ExprKind::Literal(Value::Str(value))
+            }
+            Token::Duration(value) => {
+                self.bump();
+                ExprKind::Literal(Value::Duration(value))
+            }
+            Token::Param(name) => {
+                self.bump();
+                ExprKind::Parameter(name)
+            }
+            Token::Ident(ref name) if name == "true" || name == "false" => {
+                self.bump();
+                ExprKind::Literal(Value::Bool(name == "true"))
+            }
+            Token::Ident(name) => {
+                self.bump();
+                if self.eat(&Token::LeftParen) {
+                    let args = self.list(&Token::RightParen, "`)`", |this| this.expression())?;
+                    ExprKind::Call(name, args)
+                } else {
+                    ExprKind::Attribute(name)
+                }
+            }
+            Token::LeftParen => {
+                self.bump();
+                let inner = self.expression()?;
+                self.expect(&Token::RightParen, "`)`")?;
+                return Ok(Expr {
+                    kind: inner.kind,
+                    span: self.since(start),
+                });
+            }
+            _ => return self.unexpected("an expression"),
+        };
+        Ok(Expr {
+            kind: kind,
+            span: self.since(start),
+        })
+    }
+}
@@ -16,7 +16,22 @@ pub enum Aggregator {
     Sum(usize),
     Max(usize),
     Min(usize),
-    Count, // TODO add ANY and ALL?
+    Count,
+    /// Population standard deviation
+    StdDev(usize),
+    /// Population variance
+    Variance(usize),
+    CountDistinct(usize),
+    /// Value of the earliest event
+    First(usize),
+    /// Value of the latest event
+    Last(usize),
+    Any(usize),
+    All(usize),
+    /// Nearest-rank percentile, the quantile is between 0 and 1
+    Percentile(usize, f64),
+    /// Nearest-rank median, the lower one with an even number of values
+    Median(usize),
 }
 
 #[derive(Clone, Debug)]
@@ -29,12 +44,31 @@ pub struct ParameterDeclaration {
 pub enum TimingBound {
     Within { window: Duration },
     Between { lower: usize },
+    /// The last `count` events before the upper bound, among the ones satisfying the
+    /// constraints that do not depend on other predicates
+    Last { count: usize },
 }
 
+/// Whether the events at the exact time of an end of a window are part of it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Boundary {
+    Open,
+    Closed,
+}
+
+/// Window of the events that can match a predicate.
+///
+/// As in the original TRex, by default the window is closed at the lower end and open at the
+/// upper one: the events exactly `window` before the upper event are included, while the upper
+/// event is not. The events at the same time as a bounding event are compared with it by
+/// sequence number (see `Event::order`).
 #[derive(Clone, Debug)]
 pub struct Timing {
     pub upper: usize,
     pub bound: TimingBound,
+    /// It is ignored by the `Last` bound
+    pub lower_boundary: Boundary,
+    pub upper_boundary: Boundary,
 }
 
 #[derive(Clone, Debug)]
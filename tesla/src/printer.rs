@@ -0,0 +1,805 @@
+//! Renders rules and declarations back to the textual TESLA syntax
+//! accepted by the parser.
+//!
+//! Tuple declarations are self contained and implement `Display` directly,
+//! while rules need to know the declarations of the tuples they refer to
+//! in order to print attribute names, so they are rendered through `Rule::display`.
+
+use {EventTemplate, Rule, TupleDeclaration, TupleType};
+use chrono::Duration;
+use expressions::{BasicType, BinaryOperator, Expression, Rounding, UnaryOperator, Value};
+use parser::Parser;
+use predicates::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::i64;
+
+/// Lookup of the tuple declarations a rule refers to.
+pub trait Declarations {
+    fn get_tuple(&self, id: usize) -> Option<&TupleDeclaration>;
+}
+
+impl Declarations for Vec<TupleDeclaration> {
+    fn get_tuple(&self, id: usize) -> Option<&TupleDeclaration> {
+        self.iter().find(|tuple| tuple.id == id)
+    }
+}
+
+impl Declarations for Parser {
+    fn get_tuple(&self, id: usize) -> Option<&TupleDeclaration> {
+        self.declarations().iter().find(|tuple| tuple.id == id)
+    }
+}
+
+impl<S: BuildHasher> Declarations for HashMap<usize, TupleDeclaration, S> {
+    fn get_tuple(&self, id: usize) -> Option<&TupleDeclaration> { self.get(&id) }
+}
+
+impl fmt::Display for BasicType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BasicType::Int => write!(f, "int"),
+            BasicType::Float => write!(f, "float"),
+            BasicType::Bool => write!(f, "bool"),
+            BasicType::Str => write!(f, "string"),
+            BasicType::Timestamp => write!(f, "timestamp"),
+            BasicType::Duration => write!(f, "duration"),
+        }
+    }
+}
+
+/// Values are printed as literals, negative numbers included, except for the ones
+/// that have none: the smallest integer is printed as a subtraction, and infinite
+/// and NaN floats as casts from strings, so they are read back as expressions.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Int(i64::MIN) => write!(f, "({} - 1)", i64::MIN + 1),
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) if !value.is_finite() => write!(f, "float('{}')", value),
+            Value::Float(value) => {
+                let res = format!("{}", value);
+                if res.contains('.') { write!(f, "{}", res) } else { write!(f, "{}.0", res) }
+            }
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Str(ref value) => {
+                write!(f, "'")?;
+                for c in value.chars() {
+                    match c {
+                        '\'' => write!(f, "\\'")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        c => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "'")
+            }
+            Value::Duration(ref value) => write_duration(f, value),
+            // There is no literal for timestamps, they only come from events
+            Value::Timestamp(ref value) => write!(f, "'{}'", value.to_rfc3339()),
+            Value::Null => write!(f, "null"),
+        }
+    }
+}
+
+impl fmt::Display for TupleDeclaration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if let TupleType::Static = self.ty { "static " } else { "" };
+        let attributes = self.attributes
+            .iter()
+            .map(|attr| {
+                let nullable = if attr.nullable { "?" } else { "" };
+                format!("{}: {}{}", attr.name, attr.ty, nullable)
+            })
+            .collect::<Vec<_>>();
+        write!(f,
+               "declare {}{}({}) with id {}",
+               kind,
+               self.name,
+               attributes.join(", "),
+               self.id)
+    }
+}
+
+fn write_duration(f: &mut fmt::Formatter, duration: &Duration) -> fmt::Result {
+    let units: [(fn(i64) -> Duration, fn(&Duration) -> i64, &str); 5] =
+        [(Duration::days, Duration::num_days, "d"),
+         (Duration::hours, Duration::num_hours, "h"),
+         (Duration::minutes, Duration::num_minutes, "min"),
+         (Duration::seconds, Duration::num_seconds, "s"),
+         (Duration::milliseconds, Duration::num_milliseconds, "ms")];
+    for &(build, count, unit) in &units {
+        let value = count(duration);
+        if build(value) == *duration {
+            return write!(f, "{}{}", value, unit);
+        }
+    }
+    match duration.num_microseconds() {
+        Some(value) if Duration::microseconds(value) == *duration => write!(f, "{}us", value),
+        _ => write!(f, "{}ns", duration.num_nanoseconds().unwrap_or(i64::max_value())),
+    }
+}
+
+impl fmt::Display for UnaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match *self {
+            UnaryOperator::Minus => "-",
+            UnaryOperator::Not => "!",
+            UnaryOperator::IsNull => "is null",
+            UnaryOperator::IsNotNull => "is not null",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+impl fmt::Display for BinaryOperator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let symbol = match *self {
+            BinaryOperator::Plus => "+",
+            BinaryOperator::Minus => "-",
+            BinaryOperator::Times => "*",
+            BinaryOperator::Division => "/",
+            BinaryOperator::Remainder => "%",
+            BinaryOperator::BitwiseAnd => "&",
+            BinaryOperator::BitwiseOr => "|",
+            BinaryOperator::BitwiseXor => "^",
+            BinaryOperator::ShiftLeft => "<<",
+            BinaryOperator::ShiftRight => ">>",
+            BinaryOperator::Equal => "==",
+            BinaryOperator::NotEqual => "!=",
+            BinaryOperator::GreaterThan => ">",
+            BinaryOperator::GreaterEqual => ">=",
+            BinaryOperator::LowerThan => "<",
+            BinaryOperator::LowerEqual => "<=",
+            BinaryOperator::Matches => "matches",
+            BinaryOperator::Like => "like",
+            BinaryOperator::And => "and",
+            BinaryOperator::Or => "or",
+            BinaryOperator::Xor => "xor",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+/// A rule together with the declarations needed to print it.
+///
+/// Besides rendering the whole rule it can render its single parts.
+#[derive(Clone, Copy)]
+pub struct RuleDisplay<'a> {
+    rule: &'a Rule,
+    tuples: &'a Declarations,
+}
+
+impl Rule {
+    pub fn display<'a>(&'a self, tuples: &'a Declarations) -> RuleDisplay<'a> {
+        RuleDisplay {
+            rule: self,
+            tuples: tuples,
+        }
+    }
+}
+
+impl<'a> RuleDisplay<'a> {
+    pub fn predicate(&self, idx: usize) -> PredicateDisplay<'a> {
+        PredicateDisplay {
+            ctx: *self,
+            idx: idx,
+        }
+    }
+
+    pub fn timing(&self, timing: &'a Timing) -> TimingDisplay<'a> {
+        TimingDisplay {
+            ctx: *self,
+            timing: timing,
+        }
+    }
+
+    /// Renders an expression that appears in the predicate `predicate`,
+    /// or outside of any predicate (filters and template) if it is `None`.
+    pub fn expression(&self,
+                      predicate: Option<usize>,
+                      expression: &'a Expression)
+                      -> ExpressionDisplay<'a> {
+        ExpressionDisplay {
+            ctx: *self,
+            predicate: predicate,
+            expression: expression,
+        }
+    }
+
+    fn tuple_name(&self, ty_id: usize) -> String {
+        self.tuples.get_tuple(ty_id).map(|tuple| tuple.name.clone()).unwrap_or_else(|| {
+            format!("tuple{}", ty_id)
+        })
+    }
+
+    fn attribute_name(&self, ty_id: usize, attribute: usize) -> String {
+        self.tuples
+            .get_tuple(ty_id)
+            .and_then(|tuple| tuple.attributes.get(attribute))
+            .map(|attr| attr.name.clone())
+            .unwrap_or_else(|| format!("attribute{}", attribute))
+    }
+
+    fn parameter_name(&self, predicate: usize, parameter: usize) -> String {
+        self.rule
+            .predicates
+            .get(predicate)
+            .and_then(|pred| {
+                match pred.ty {
+                    PredicateType::Trigger { ref parameters } |
+                    PredicateType::Event { ref parameters, .. } |
+                    PredicateType::OrderedStatic { ref parameters, .. } |
+                    PredicateType::UnorderedStatic { ref parameters } => parameters.get(parameter),
+                    PredicateType::EventAggregate { ref parameter, .. } |
+                    PredicateType::StaticAggregate { ref parameter, .. } => Some(parameter),
+                    _ => None,
+                }
+            })
+            .map(|param| param.name.clone())
+            .unwrap_or_else(|| format!("param{}x{}", predicate, parameter))
+    }
+
+    fn alias(&self, predicate: usize) -> String {
+        self.rule
+            .predicates
+            .get(predicate)
+            .map(|pred| pred.tuple.alias.clone())
+            .unwrap_or_else(|| format!("predicate{}", predicate))
+    }
+
+    fn write_aggregator(&self,
+                        f: &mut fmt::Formatter,
+                        ty_id: usize,
+                        aggregator: &Aggregator)
+                        -> fmt::Result {
+        let (name, attribute) = match *aggregator {
+            Aggregator::Avg(attr) => ("avg", Some(attr)),
+            Aggregator::Sum(attr) => ("sum", Some(attr)),
+            Aggregator::Max(attr) => ("max", Some(attr)),
+            Aggregator::Min(attr) => ("min", Some(attr)),
+            Aggregator::Count => ("count", None),
+            Aggregator::StdDev(attr) => ("stddev", Some(attr)),
+            Aggregator::Variance(attr) => ("variance", Some(attr)),
+            Aggregator::CountDistinct(attr) => ("count_distinct", Some(attr)),
+            Aggregator::First(attr) => ("first", Some(attr)),
+            Aggregator::Last(attr) => ("last", Some(attr)),
+            Aggregator::Any(attr) => ("any", Some(attr)),
+            Aggregator::All(attr) => ("all", Some(attr)),
+            Aggregator::Median(attr) => ("median", Some(attr)),
+            Aggregator::Percentile(attr, quantile) => {
+                return write!(f,
+                              "percentile({}, {})",
+                              self.attribute_name(ty_id, attr),
+                              Value::Float(quantile));
+            }
+        };
+        let attribute = attribute.map(|attr| self.attribute_name(ty_id, attr));
+        write!(f, "{}({})", name, attribute.unwrap_or_default())
+    }
+
+    fn write_expression(&self,
+                        f: &mut fmt::Formatter,
+                        predicate: Option<usize>,
+                        expression: &Expression,
+                        precedence: usize)
+                        -> fmt::Result {
+        match *expression {
+            Expression::Immediate { ref value } => write!(f, "{}", value),
+            Expression::Reference { attribute } => {
+                let ty_id = predicate.and_then(|idx| self.rule.predicates.get(idx))
+                    .map(|pred| pred.tuple.ty_id);
+                match ty_id {
+                    Some(ty_id) => write!(f, "{}", self.attribute_name(ty_id, attribute)),
+                    None => write!(f, "attribute{}", attribute),
+                }
+            }
+            Expression::Aggregate => {
+                let pred = predicate.and_then(|idx| self.rule.predicates.get(idx));
+                match pred.map(|pred| (pred.tuple.ty_id, &pred.ty)) {
+                    Some((ty_id, &PredicateType::EventAggregate { ref aggregator, .. })) |
+                    Some((ty_id, &PredicateType::StaticAggregate { ref aggregator, .. })) => {
+                        self.write_aggregator(f, ty_id, aggregator)
+                    }
+                    _ => write!(f, "aggregate()"),
+                }
+            }
+            Expression::Parameter { predicate, parameter } => {
+                write!(f, "${}", self.parameter_name(predicate, parameter))
+            }
+            Expression::Timestamp { predicate: idx } if Some(idx) == predicate => {
+                write!(f, "timestamp()")
+            }
+            Expression::Timestamp { predicate } => {
+                write!(f, "timestamp({})", self.alias(predicate))
+            }
+            Expression::Cast { ref ty, ref rounding, ref expression } => {
+                write!(f, "{}(", ty)?;
+                self.write_expression(f, predicate, expression, 0)?;
+                match *rounding {
+                    Rounding::Round if *ty == BasicType::Int => write!(f, ", round)"),
+                    _ => write!(f, ")"),
+                }
+            }
+            Expression::UnaryOperation { operator: ref operator @ UnaryOperator::IsNull,
+                                         ref expression } |
+            Expression::UnaryOperation { operator: ref operator @ UnaryOperator::IsNotNull,
+                                         ref expression } => {
+                let own = BinaryOperator::Equal.precedence();
+                if own < precedence {
+                    write!(f, "(")?;
+                }
+                self.write_expression(f, predicate, expression, own)?;
+                write!(f, " {}", operator)?;
+                if own < precedence {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+            Expression::UnaryOperation { ref operator, ref expression } => {
+                write!(f, "{}", operator)?;
+                let is_number = match **expression {
+                    Expression::Immediate { value: Value::Int(_) } |
+                    Expression::Immediate { value: Value::Float(_) } |
+                    Expression::Immediate { value: Value::Duration(_) } => true,
+                    _ => false,
+                };
+                // Otherwise the minus sign would be read as part of the number literal
+                if *operator == UnaryOperator::Minus && is_number {
+                    write!(f, "(")?;
+                    self.write_expression(f, predicate, expression, 0)?;
+                    write!(f, ")")
+                } else {
+                    self.write_expression(f, predicate, expression, usize::max_value())
+                }
+            }
+            Expression::BinaryOperation { ref operator, ref left, ref right } => {
+                let own = operator.precedence();
+                if own < precedence {
+                    write!(f, "(")?;
+                }
+                self.write_expression(f, predicate, left, own)?;
+                write!(f, " {} ", operator)?;
+                self.write_expression(f, predicate, right, own + 1)?;
+                if own < precedence {
+                    write!(f, ")")?;
+                }
+                Ok(())
+            }
+            Expression::Function { ref name, ref args } => {
+                write!(f, "{}(", name)?;
+                self.write_list(f, args, |f, arg| self.write_expression(f, predicate, arg, 0))?;
+                write!(f, ")")
+            }
+        }
+    }
+
+    fn write_list<T, F>(&self,
+                        f: &mut fmt::Formatter,
+                        items: &[T],
+                        mut write_item: F)
+                        -> fmt::Result
+        where F: FnMut(&mut fmt::Formatter, &T) -> fmt::Result
+    {
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write_item(f, item)?;
+        }
+        Ok(())
+    }
+
+    fn write_parameters(&self,
+                        f: &mut fmt::Formatter,
+                        idx: usize,
+                        parameters: &[ParameterDeclaration])
+                        -> fmt::Result {
+        if !parameters.is_empty() {
+            write!(f, "[")?;
+            self.write_list(f, parameters, |f, param| {
+                    write!(f, "${} = ", param.name)?;
+                    self.write_expression(f, Some(idx), &param.expression, 0)
+                })?;
+            write!(f, "]")?;
+        }
+        Ok(())
+    }
+
+    fn write_timing(&self, f: &mut fmt::Formatter, timing: &Timing) -> fmt::Result {
+        match timing.bound {
+            TimingBound::Within { ref window } => {
+                write!(f, "within ")?;
+                write_duration(f, window)?;
+                write!(f, " from {}", self.alias(timing.upper))
+            }
+            TimingBound::Between { lower } => {
+                write!(f,
+                       "between {} and {}",
+                       self.alias(lower),
+                       self.alias(timing.upper))
+            }
+            TimingBound::Last { count } => {
+                write!(f, "within {} events from {}", count, self.alias(timing.upper))
+            }
+        }?;
+        match (timing.lower_boundary, timing.upper_boundary) {
+            (Boundary::Closed, Boundary::Open) => Ok(()),
+            (lower, upper) => {
+                let lower = if lower == Boundary::Closed { '[' } else { '(' };
+                let upper = if upper == Boundary::Closed { ']' } else { ')' };
+                write!(f, " bounds {}{}", lower, upper)
+            }
+        }
+    }
+
+    fn write_predicate(&self, f: &mut fmt::Formatter, idx: usize) -> fmt::Result {
+        let pred = &self.rule.predicates[idx];
+        let tuple_name = self.tuple_name(pred.tuple.ty_id);
+
+        match pred.ty {
+            PredicateType::Event { ref selection, .. } => {
+                match *selection {
+                    EventSelection::Each => write!(f, "each ")?,
+                    EventSelection::First => write!(f, "first ")?,
+                    EventSelection::Last => write!(f, "last ")?,
+                }
+            }
+            PredicateType::EventNegation { .. } |
+            PredicateType::StaticNegation => write!(f, "not ")?,
+            _ => (),
+        }
+
+        write!(f, "{}", tuple_name)?;
+
+        match pred.ty {
+            PredicateType::Trigger { ref parameters } |
+            PredicateType::Event { ref parameters, .. } |
+            PredicateType::OrderedStatic { ref parameters, .. } |
+            PredicateType::UnorderedStatic { ref parameters } => {
+                self.write_parameters(f, idx, parameters)?
+            }
+            PredicateType::EventAggregate { ref parameter, .. } |
+            PredicateType::StaticAggregate { ref parameter, .. } => {
+                self.write_parameters(f, idx, ::std::slice::from_ref(parameter))?
+            }
+            _ => (),
+        }
+
+        if !pred.tuple.constraints.is_empty() {
+            write!(f, "(")?;
+            self.write_list(f,
+                            &pred.tuple.constraints,
+                            |f, expr| self.write_expression(f, Some(idx), expr, 0))?;
+            write!(f, ")")?;
+        }
+
+        if pred.tuple.alias != tuple_name {
+            write!(f, " as {}", pred.tuple.alias)?;
+        }
+
+        match pred.ty {
+            PredicateType::Event { ref timing, .. } |
+            PredicateType::EventAggregate { ref timing, .. } |
+            PredicateType::EventNegation { ref timing } => {
+                write!(f, " ")?;
+                self.write_timing(f, timing)?;
+            }
+            PredicateType::OrderedStatic { ref ordering, .. } => {
+                write!(f, " order by ")?;
+                self.write_list(f, ordering, |f, ord| {
+                        let direction = match ord.direction {
+                            Order::Asc => "asc",
+                            Order::Desc => "desc",
+                        };
+                        write!(f,
+                               "{} {}",
+                               self.attribute_name(pred.tuple.ty_id, ord.attribute),
+                               direction)
+                    })?;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn write_template(&self, f: &mut fmt::Formatter, template: &EventTemplate) -> fmt::Result {
+        write!(f, "define {}(", self.tuple_name(template.ty_id))?;
+        let attributes = template.attributes.iter().enumerate().collect::<Vec<_>>();
+        self.write_list(f, &attributes, |f, &(i, expr)| {
+                write!(f, "{} = ", self.attribute_name(template.ty_id, i))?;
+                self.write_expression(f, None, expr, 0)
+            })?;
+        write!(f, ")")
+    }
+}
+
+impl<'a> fmt::Display for RuleDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_template(f, &self.rule.event_template)?;
+        for i in 0..self.rule.predicates.len() {
+            write!(f, "\n{} ", if i == 0 { "from" } else { "and" })?;
+            self.write_predicate(f, i)?;
+        }
+        if !self.rule.filters.is_empty() {
+            write!(f, "\nwhere ")?;
+            self.write_list(f,
+                            &self.rule.filters,
+                            |f, expr| self.write_expression(f, None, expr, 0))?;
+        }
+        if !self.rule.consuming.is_empty() {
+            write!(f, "\nconsuming ")?;
+            self.write_list(f,
+                            &self.rule.consuming,
+                            |f, &idx| write!(f, "{}", self.alias(idx)))?;
+        }
+        if let Some(accuracy) = self.rule.accuracy {
+            write!(f, "\nwith accuracy {}", Value::Float(accuracy))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct PredicateDisplay<'a> {
+    ctx: RuleDisplay<'a>,
+    idx: usize,
+}
+
+impl<'a> fmt::Display for PredicateDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.ctx.write_predicate(f, self.idx) }
+}
+
+#[derive(Clone, Copy)]
+pub struct TimingDisplay<'a> {
+    ctx: RuleDisplay<'a>,
+    timing: &'a Timing,
+}
+
+impl<'a> fmt::Display for TimingDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { self.ctx.write_timing(f, self.timing) }
+}
+
+#[derive(Clone, Copy)]
+pub struct ExpressionDisplay<'a> {
+    ctx: RuleDisplay<'a>,
+    predicate: Option<usize>,
+    expression: &'a Expression,
+}
+
+impl<'a> fmt::Display for ExpressionDisplay<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.ctx.write_expression(f, self.predicate, self.expression, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expressions::{Expression, Value};
+    use parser::{Parser, Statement, parse};
+    use std::{f64, i64};
+
+    const DECLARATIONS: &'static str = "declare smoke(area: string) with id 0
+declare temperature(area: string, value: int) with id 1
+declare fire(area: string, temp: int) with id 2
+declare static sensors(area: string, kind: string?, threshold: float) with id 3";
+
+    fn round_trip(source: &str) -> String {
+        let mut parser = Parser::new();
+        parser.parse(DECLARATIONS).unwrap();
+        let printed = {
+            let rule = parser.parse_rule(source).unwrap();
+            format!("{}", rule.display(&parser))
+        };
+        let reparsed = parser.parse_rule(&printed).unwrap();
+        assert_eq!(format!("{}", reparsed.display(&parser)), printed);
+        printed
+    }
+
+    #[test]
+    fn declarations() {
+        let printed = parse(DECLARATIONS)
+            .unwrap()
+            .iter()
+            .map(|stmt| {
+                match *stmt {
+                    Statement::Declaration(ref decl) => format!("{}", decl),
+                    _ => panic!("expected a declaration"),
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(printed.join("\n"), DECLARATIONS);
+    }
+
+    #[test]
+    fn canonical_rule() {
+        let printed = round_trip("define fire(temp = $y, area = $x) \
+                                  from smoke[$x = area] as smk \
+                                  and last temperature[$y = value](area = $x, value > 45) \
+                                  as temp within 300s from smk \
+                                  consuming temp");
+        assert_eq!(printed,
+                   "define fire(area = $x, temp = $y)\n\
+                    from smoke[$x = area] as smk\n\
+                    and last temperature[$y = value](area == $x, value > 45) as temp within \
+                    5min from smk\n\
+                    consuming temp");
+    }
+
+    #[test]
+    fn timestamps() {
+        let printed = round_trip("define fire(area = $x, temp = 1)
+                                  from smoke[$x = area]
+                                  and each temperature(timestamp() - timestamp(smoke) >= 10s) \
+                                  within 1min from smoke
+                                  where timestamp(temperature) < timestamp(smoke) + 1500ms");
+        assert_eq!(printed,
+                   "define fire(area = $x, temp = 1)\n\
+                    from smoke[$x = area]\n\
+                    and each temperature(timestamp() - timestamp(smoke) >= 10s) within 1min \
+                    from smoke\n\
+                    where timestamp(temperature) < timestamp(smoke) + 1500ms");
+    }
+
+    #[test]
+    fn parentheses_and_literals() {
+        let printed = round_trip("define fire(area = $x + 'it\\'s', temp = (1 - (2 - 3)) * -$y)
+                                  from smoke[$x = area]
+                                  and each temperature[$y = value](!(value / 2 < 3)) \
+                                  within 1500ms from smoke
+                                  where float($y) * 2.0 >= 1.5 && ($y < 0 || $y > 10) \
+                                  && if(($y > 0), abs($y) > 1, false)");
+        assert_eq!(printed,
+                   "define fire(area = $x + 'it\\'s', temp = (1 - (2 - 3)) * -$y)\n\
+                    from smoke[$x = area]\n\
+                    and each temperature[$y = value](!(value / 2 < 3)) within 1500ms from \
+                    smoke\n\
+                    where float($y) * 2.0 >= 1.5 and ($y < 0 or $y > 10) and if($y > 0, abs($y) > \
+                    1, false)");
+    }
+
+    #[test]
+    fn aggregates_and_statics() {
+        let printed = round_trip("define fire(area = $x, temp = $t)
+                                  from smoke[$x = area] as smk
+                                  and temperature[$t = max(value)](area == $x) between smk and smk
+                                  and sensors[$k = kind](threshold < float($t)) order by \
+                                  threshold desc, kind
+                                  and not sensors(kind == $k) as broken");
+        assert_eq!(printed,
+                   "define fire(area = $x, temp = $t)\n\
+                    from smoke[$x = area] as smk\n\
+                    and temperature[$t = max(value)](area == $x) between smk and smk\n\
+                    and sensors[$k = kind](threshold < float($t)) order by threshold desc, \
+                    kind asc\n\
+                    and not sensors(kind == $k) as broken");
+    }
+
+    #[test]
+    fn aggregators() {
+        let printed = round_trip("define fire(area = $x, temp = $l)
+                                  from smoke[$x = area]
+                                  and temperature[$d = stddev(value) / variance(value)](area == \
+                                  $x) within 1min from smoke
+                                  and temperature[$l = last(value)] within 1min from smoke
+                                  and sensors[$n = count_distinct(kind)](area == $x)");
+        assert_eq!(printed,
+                   "define fire(area = $x, temp = $l)\n\
+                    from smoke[$x = area]\n\
+                    and temperature[$d = stddev(value) / variance(value)](area == $x) within \
+                    1min from smoke\n\
+                    and temperature[$l = last(value)] within 1min from smoke\n\
+                    and sensors[$n = count_distinct(kind)](area == $x)");
+    }
+
+    #[test]
+    fn percentiles() {
+        let printed = round_trip("define fire(area = $x, temp = $p)
+                                  from smoke[$x = area]
+                                  and temperature[$p = p95(value)] within 1min from smoke
+                                  and temperature[$m = median(value) * 2.0] within 1min from smoke
+                                  with accuracy 0.01");
+        assert_eq!(printed,
+                   "define fire(area = $x, temp = $p)\n\
+                    from smoke[$x = area]\n\
+                    and temperature[$p = percentile(value, 0.95)] within 1min from smoke\n\
+                    and temperature[$m = median(value) * 2.0] within 1min from smoke\n\
+                    with accuracy 0.01");
+    }
+
+    #[test]
+    fn count_windows() {
+        let printed = round_trip("define fire(area = $x, temp = $a)
+                                  from smoke[$x = area]
+                                  and temperature[$a = avg(value)](area == $x) within 50 events \
+                                  from smoke
+                                  and not temperature(value > 45) within 3 events from smoke");
+        assert_eq!(printed,
+                   "define fire(area = $x, temp = $a)\n\
+                    from smoke[$x = area]\n\
+                    and temperature[$a = avg(value)](area == $x) within 50 events from smoke\n\
+                    and not temperature(value > 45) within 3 events from smoke");
+    }
+
+    #[test]
+    fn window_boundaries() {
+        let printed = round_trip("define fire(area = $x, temp = $y)
+                                  from smoke[$x = area]
+                                  and last temperature[$y = value] within 5min from smoke bounds \
+                                  [)
+                                  and not temperature(value > 45) between smoke and smoke \
+                                  bounds [ ]");
+        assert_eq!(printed,
+                   "define fire(area = $x, temp = $y)\n\
+                    from smoke[$x = area]\n\
+                    and last temperature[$y = value] within 5min from smoke\n\
+                    and not temperature(value > 45) between smoke and smoke bounds []");
+    }
+
+    #[test]
+    fn nulls() {
+        let printed = round_trip("define fire(area = $x, temp = 1)
+                                  from smoke[$x = area]
+                                  and sensors[$k = coalesce(kind, 'none')](area == $x, \
+                                  kind is not null or (threshold + 1.0) is null)
+                                  where !($k is null) && -len($k) is not null == true");
+        assert_eq!(printed,
+                   "define fire(area = $x, temp = 1)\n\
+                    from smoke[$x = area]\n\
+                    and sensors[$k = coalesce(kind, 'none')](area == $x, kind is not null or \
+                    threshold + 1.0 is null)\n\
+                    where !($k is null) and -len($k) is not null == true");
+    }
+
+    #[test]
+    fn casts() {
+        let printed = round_trip("define fire(area = $x + 1, temp = int($t, round))
+                                  from smoke[$x = area]
+                                  and last temperature[$t = float(value) / 3.0](int(1.5) == 1)
+                                  within 1min from smoke
+                                  where int(string(int($t, truncate)) + '0') > 10");
+        assert_eq!(printed,
+                   "define fire(area = $x + 1, temp = int($t, round))\n\
+                    from smoke[$x = area]\n\
+                    and last temperature[$t = float(value) / 3.0](int(1.5) == 1) within 1min \
+                    from smoke\n\
+                    where int(string(int($t)) + '0') > 10");
+    }
+
+    #[test]
+    fn number_literals() {
+        let printed = round_trip("define fire(area = $x, temp = -5 - -(3) * - 2)
+                                  from smoke[$x = area]
+                                  and last temperature(value > -1) within 1min from smoke
+                                  where -2.5 < -$x");
+        assert_eq!(printed,
+                   "define fire(area = $x, temp = -5 - -(3) * -2)\n\
+                    from smoke[$x = area]\n\
+                    and last temperature(value > -1) within 1min from smoke\n\
+                    where -2.5 < -$x");
+        let mut parser = Parser::new();
+        parser.parse(DECLARATIONS).unwrap();
+        let rule = parser.parse_rule("define fire(area = 'north', temp = -5) from smoke").unwrap();
+        assert_eq!(rule.event_template.attributes[1],
+                   Expression::Immediate { value: Value::Int(-5) });
+
+        // The values without a literal are printed as expressions that evaluate to them
+        let printed = [Value::Int(i64::MIN),
+                       Value::Float(f64::INFINITY),
+                       Value::Float(f64::NEG_INFINITY),
+                       Value::Float(f64::NAN)]
+            .iter()
+            .map(|value| format!("{}", value))
+            .collect::<Vec<_>>();
+        assert_eq!(printed,
+                   vec!["(-9223372036854775807 - 1)",
+                        "float('inf')",
+                        "float('-inf')",
+                        "float('NaN')"]);
+        round_trip(&format!("define fire(area = $x, temp = {}) from smoke[$x = area]",
+                            printed.join(" + ")));
+    }
+}
@@ -1,3 +1,6 @@
+use sketch::QuantileSketch;
+use std::cmp::Ordering;
+use std::collections::{HashSet, VecDeque};
 use std::f64;
 use std::ops::Add;
 use std::ops::Deref;
@@ -5,6 +8,38 @@ use tesla::{AttributeDeclaration, Event};
 use tesla::expressions::{BasicType, Value};
 use tesla::predicates::Aggregator;
 
+/// Sum of integers that is exact as long as the total fits, even if the partial sums overflow,
+/// so that it does not depend on the order the values are added and removed in.
+#[derive(Clone, Copy, Debug, Default)]
+struct IntSum {
+    sum: i64,
+    /// Multiple of 2^64 the wrapped `sum` is off by
+    carry: i64,
+}
+
+impl IntSum {
+    fn add(&mut self, x: i64) {
+        let (sum, overflow) = self.sum.overflowing_add(x);
+        if overflow {
+            self.carry += if x > 0 { 1 } else { -1 };
+        }
+        self.sum = sum;
+    }
+
+    fn sub(&mut self, x: i64) {
+        let (sum, overflow) = self.sum.overflowing_sub(x);
+        if overflow {
+            self.carry -= if x > 0 { 1 } else { -1 };
+        }
+        self.sum = sum;
+    }
+
+    /// The total, if it fits.
+    fn get(&self) -> Option<i64> { if self.carry == 0 { Some(self.sum) } else { None } }
+
+    fn as_float(&self) -> f64 { self.carry as f64 * 18446744073709551616.0 + self.sum as f64 }
+}
+
 fn compute_average<'a, T, U>(iterator: T,
                              attributes: &[AttributeDeclaration],
                              attr: usize)
@@ -14,9 +49,12 @@ fn compute_average<'a, T, U>(iterator: T,
 {
     match attributes[attr].ty {
         BasicType::Int => {
-            let mapped = iterator.map(|evt| evt.tuple.data[attr].unwrap_int());
-            let (count, sum) = mapped.fold((0i64, 0), |acc, x| (acc.0 + 1, acc.1 + x));
-            if count > 0 { Some(Value::from(sum as f64 / count as f64)) } else { None }
+            let (mut count, mut sum) = (0i64, IntSum::default());
+            for evt in iterator {
+                count += 1;
+                sum.add(evt.tuple.data[attr].unwrap_int());
+            }
+            if count > 0 { Some(Value::from(sum.as_float() / count as f64)) } else { None }
         }
         BasicType::Float => {
             let mapped = iterator.map(|evt| evt.tuple.data[attr].unwrap_float());
@@ -36,8 +74,12 @@ fn compute_sum<'a, T, U>(iterator: T,
 {
     match attributes[attr].ty {
         BasicType::Int => {
-            let mapped = iterator.map(|evt| evt.tuple.data[attr].unwrap_int());
-            Some(Value::from(mapped.fold(0, Add::add)))
+            // A sum that does not fit has no value, as an evaluation that overflows
+            let mut sum = IntSum::default();
+            for evt in iterator {
+                sum.add(evt.tuple.data[attr].unwrap_int());
+            }
+            sum.get().map(Value::from)
         }
         BasicType::Float => {
             let mapped = iterator.map(|evt| evt.tuple.data[attr].unwrap_float());
@@ -89,18 +131,424 @@ fn compute_max<'a, T, U>(iterator: T,
     }
 }
 
+fn compute_variance<'a, T, U>(iterator: T,
+                              attributes: &[AttributeDeclaration],
+                              attr: usize)
+                              -> Option<f64>
+    where T: Iterator<Item = &'a U>,
+          U: Deref<Target = Event> + 'a
+{
+    let mapped = iterator.map(|evt| match attributes[attr].ty {
+        BasicType::Int => evt.tuple.data[attr].unwrap_int() as f64,
+        BasicType::Float => evt.tuple.data[attr].unwrap_float(),
+        _ => panic!("Tring to compute aggregate on wrong Value type"),
+    });
+    // Welford's algorithm, that is numerically stable in a single pass
+    let (count, _, squares) = mapped.fold((0i64, 0.0, 0.0), |(count, mean, squares), x| {
+        let delta = x - mean;
+        let mean = mean + delta / (count + 1) as f64;
+        (count + 1, mean, squares + delta * (x - mean))
+    });
+    if count > 0 { Some(squares / count as f64) } else { None }
+}
+
+fn compute_logical<'a, T, U>(iterator: T, attr: usize, any: bool) -> Option<Value>
+    where T: Iterator<Item = &'a U>,
+          U: Deref<Target = Event> + 'a
+{
+    let mut mapped = iterator.map(|evt| evt.tuple.data[attr].unwrap_bool());
+    if any {
+        Some(Value::from(mapped.any(|x| x)))
+    } else {
+        Some(Value::from(mapped.all(|x| x)))
+    }
+}
+
+fn compute_percentile<'a, T, U>(iterator: T,
+                                attributes: &[AttributeDeclaration],
+                                attr: usize,
+                                quantile: f64,
+                                accuracy: Option<f64>)
+                                -> Option<Value>
+    where T: Iterator<Item = &'a U>,
+          U: Deref<Target = Event> + 'a
+{
+    // Small windows are computed exactly anyway, until the sketch fills up
+    let mut sketch = match accuracy {
+        Some(accuracy) => QuantileSketch::with_accuracy(accuracy),
+        None => QuantileSketch::exact(),
+    };
+    let values = iterator.map(|evt| match attributes[attr].ty {
+        BasicType::Int => evt.tuple.data[attr].unwrap_int() as f64,
+        BasicType::Float => evt.tuple.data[attr].unwrap_float(),
+        _ => panic!("Tring to compute aggregate on wrong Value type"),
+    });
+    // NaN has no rank, so it is skipped as by the minimum and the maximum
+    for value in values.filter(|value| !value.is_nan()) {
+        sketch.insert(value);
+    }
+    sketch.quantile(quantile).map(Value::from)
+}
+
 pub fn compute_aggregate<'a, T, U>(aggregator: &Aggregator,
                                    iterator: T,
-                                   attributes: &[AttributeDeclaration])
+                                   attributes: &[AttributeDeclaration],
+                                   accuracy: Option<f64>)
                                    -> Option<Value>
     where T: Iterator<Item = &'a U>,
           U: Deref<Target = Event> + 'a
 {
+    // Null values are skipped, as in SQL, while `Count` considers all the events
+    let non_null = |attr: usize| move |evt: &&'a U| !evt.tuple.data[attr].is_null();
     match *aggregator {
-        Aggregator::Avg(attr) => compute_average(iterator, attributes, attr),
-        Aggregator::Sum(attr) => compute_sum(iterator, attributes, attr),
-        Aggregator::Min(attr) => compute_min(iterator, attributes, attr),
-        Aggregator::Max(attr) => compute_max(iterator, attributes, attr),
+        Aggregator::Avg(attr) => compute_average(iterator.filter(non_null(attr)), attributes, attr),
+        Aggregator::Sum(attr) => compute_sum(iterator.filter(non_null(attr)), attributes, attr),
+        Aggregator::Min(attr) => compute_min(iterator.filter(non_null(attr)), attributes, attr),
+        Aggregator::Max(attr) => compute_max(iterator.filter(non_null(attr)), attributes, attr),
         Aggregator::Count => Some(Value::from(iterator.count() as i64)),
+        Aggregator::StdDev(attr) => {
+            compute_variance(iterator.filter(non_null(attr)), attributes, attr)
+                .map(|variance| Value::from(variance.sqrt()))
+        }
+        Aggregator::Variance(attr) => {
+            compute_variance(iterator.filter(non_null(attr)), attributes, attr).map(Value::from)
+        }
+        Aggregator::CountDistinct(attr) => {
+            // NaN cannot be hashed, so it is skipped as by the minimum and the maximum
+            let distinct = iterator.filter(non_null(attr))
+                .map(|evt| &evt.tuple.data[attr])
+                .filter(|value| if let Value::Float(x) = **value { !x.is_nan() } else { true })
+                .collect::<HashSet<_>>();
+            Some(Value::from(distinct.len() as i64))
+        }
+        // The events are in chronological order
+        Aggregator::First(attr) => {
+            iterator.filter(non_null(attr)).next().map(|evt| evt.tuple.data[attr].clone())
+        }
+        Aggregator::Last(attr) => {
+            iterator.filter(non_null(attr)).last().map(|evt| evt.tuple.data[attr].clone())
+        }
+        Aggregator::Any(attr) => compute_logical(iterator.filter(non_null(attr)), attr, true),
+        Aggregator::All(attr) => compute_logical(iterator.filter(non_null(attr)), attr, false),
+        Aggregator::Percentile(attr, quantile) => {
+            compute_percentile(iterator.filter(non_null(attr)),
+                               attributes,
+                               attr,
+                               quantile,
+                               accuracy)
+        }
+        Aggregator::Median(attr) => {
+            compute_percentile(iterator.filter(non_null(attr)), attributes, attr, 0.5, accuracy)
+        }
+    }
+}
+
+fn compare(lhs: &Value, rhs: &Value) -> Ordering {
+    match (lhs, rhs) {
+        (&Value::Int(lhs), &Value::Int(rhs)) => lhs.cmp(&rhs),
+        // NaN is never compared, as it is skipped by the minimum and the maximum
+        (&Value::Float(lhs), &Value::Float(rhs)) => {
+            lhs.partial_cmp(&rhs).unwrap_or(Ordering::Equal)
+        }
+        _ => panic!("Tring to compute aggregate on wrong Value type"),
+    }
+}
+
+/// State of an aggregate over a window whose events enter and leave in chronological order,
+/// that is updated with each of them instead of scanning the whole window at each evaluation.
+///
+/// Float sums and averages are always scanned, since removing a value from a running sum
+/// can cancel out the smaller ones added after it.
+#[derive(Clone, Debug)]
+pub struct RunningAggregate {
+    aggregator: Aggregator,
+    attribute: Option<(usize, BasicType)>,
+    events: usize,
+    values: usize,
+    int_sum: IntSum,
+    /// Sequence number the next added event gets
+    next: usize,
+    /// Candidates to be the minimum (or maximum) of the window with their sequence numbers,
+    /// increasing (or decreasing) in value, each one is the extreme after the previous leaves
+    extremes: VecDeque<(usize, Value)>,
+}
+
+impl RunningAggregate {
+    /// Creates the state of an aggregator, if it can be maintained incrementally.
+    pub fn new(aggregator: &Aggregator, attributes: &[AttributeDeclaration]) -> Option<Self> {
+        let attribute = match *aggregator {
+            Aggregator::Count => None,
+            Aggregator::Avg(attr) |
+            Aggregator::Sum(attr) if attributes[attr].ty == BasicType::Int => {
+                Some((attr, BasicType::Int))
+            }
+            Aggregator::Min(attr) |
+            Aggregator::Max(attr) => Some((attr, attributes[attr].ty.clone())),
+            _ => return None,
+        };
+        Some(RunningAggregate {
+            aggregator: aggregator.clone(),
+            attribute: attribute,
+            events: 0,
+            values: 0,
+            int_sum: IntSum::default(),
+            next: 0,
+            extremes: VecDeque::new(),
+        })
+    }
+
+    /// Value of the aggregated attribute, if it counts for the aggregate.
+    fn get_value<'a>(&self, event: &'a Event) -> Option<&'a Value> {
+        self.attribute.as_ref().map(|&(attr, _)| &event.tuple.data[attr]).and_then(|value| {
+            match *value {
+                Value::Null => None,
+                // NaN is ignored by the minimum and the maximum
+                Value::Float(x) if x.is_nan() && self.extremes_order().is_some() => None,
+                _ => Some(value),
+            }
+        })
+    }
+
+    /// Order that the extremes of the window must preserve.
+    fn extremes_order(&self) -> Option<Ordering> {
+        match self.aggregator {
+            Aggregator::Min(_) => Some(Ordering::Less),
+            Aggregator::Max(_) => Some(Ordering::Greater),
+            _ => None,
+        }
+    }
+
+    /// Adds the event that follows the ones already in the window.
+    pub fn push(&mut self, event: &Event) {
+        let seq = self.next;
+        self.next += 1;
+        self.events += 1;
+        if let Some(value) = self.get_value(event) {
+            self.values += 1;
+            if let Value::Int(x) = *value {
+                self.int_sum.add(x);
+            }
+            if let Some(order) = self.extremes_order() {
+                while self.extremes.back().map_or(false, |it| compare(&it.1, value) != order) {
+                    self.extremes.pop_back();
+                }
+                self.extremes.push_back((seq, value.clone()));
+            }
+        }
+    }
+
+    /// Removes the oldest event of the window.
+    pub fn pop(&mut self, event: &Event) {
+        let seq = self.next - self.events;
+        self.events -= 1;
+        if let Some(value) = self.get_value(event) {
+            self.values -= 1;
+            if let Value::Int(x) = *value {
+                self.int_sum.sub(x);
+            }
+            if self.extremes.front().map_or(false, |it| it.0 == seq) {
+                self.extremes.pop_front();
+            }
+        }
+    }
+
+    /// Number of events in the window.
+    pub fn len(&self) -> usize { self.events }
+
+    /// Removes all the events from the window.
+    pub fn clear(&mut self) {
+        self.events = 0;
+        self.values = 0;
+        self.int_sum = IntSum::default();
+        self.extremes.clear();
+    }
+
+    /// Current value of the aggregate, as `compute_aggregate` would compute it.
+    pub fn get(&self) -> Option<Value> {
+        match self.aggregator {
+            Aggregator::Count => Some(Value::from(self.events as i64)),
+            Aggregator::Sum(_) => self.int_sum.get().map(Value::from),
+            Aggregator::Avg(_) if self.values == 0 => None,
+            Aggregator::Avg(_) => Some(Value::from(self.int_sum.as_float() / self.values as f64)),
+            _ => self.extremes.front().map(|it| it.1.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, UTC};
+    use std::{f64, i64};
+    use std::sync::Arc;
+    use super::{RunningAggregate, compute_aggregate};
+    use tesla::{AttributeDeclaration, Event, Tuple};
+    use tesla::expressions::{BasicType, Value};
+    use tesla::predicates::Aggregator;
+
+    fn window(ty: BasicType, values: Vec<Value>) -> (Vec<AttributeDeclaration>, Vec<Arc<Event>>) {
+        let attributes = vec![AttributeDeclaration {
+                                  name: "value".to_owned(),
+                                  ty: ty,
+                                  nullable: true,
+                              }];
+        let events = values.into_iter()
+            .enumerate()
+            .map(|(i, value)| {
+                Arc::new(Event {
+                    tuple: Tuple {
+                        ty_id: 0,
+                        data: vec![value],
+                    },
+                    time: UTC.timestamp(i as i64, 0),
+                    seq: 0,
+                })
+            })
+            .collect();
+        (attributes, events)
+    }
+
+    /// Checks that the running aggregates match the scanned ones over a sliding window.
+    fn check_running(aggregators: Vec<Aggregator>,
+                     attributes: &[AttributeDeclaration],
+                     events: &[Arc<Event>],
+                     window: usize) {
+        for aggregator in aggregators {
+            let mut running = RunningAggregate::new(&aggregator, attributes).unwrap();
+            for (i, event) in events.iter().enumerate() {
+                running.push(event);
+                if i >= window {
+                    running.pop(&events[i - window]);
+                }
+                let start = if i >= window { i - window + 1 } else { 0 };
+                let scanned = events[start..i + 1].iter();
+                assert_eq!(running.get(),
+                           compute_aggregate(&aggregator, scanned, attributes, None));
+            }
+        }
+    }
+
+    #[test]
+    fn statistics() {
+        let attributes = vec![AttributeDeclaration {
+                                  name: "value".to_owned(),
+                                  ty: BasicType::Int,
+                                  nullable: true,
+                              },
+                              AttributeDeclaration {
+                                  name: "alarm".to_owned(),
+                                  ty: BasicType::Bool,
+                                  nullable: false,
+                              }];
+        let data = vec![(Value::Int(2), false),
+                        (Value::Null, true),
+                        (Value::Int(4), false),
+                        (Value::Int(4), false),
+                        (Value::Int(6), false)];
+        let start = UTC.timestamp(0, 0);
+        let events = data.into_iter()
+            .enumerate()
+            .map(|(i, (value, alarm))| {
+                Arc::new(Event {
+                    tuple: Tuple {
+                        ty_id: 0,
+                        data: vec![value, Value::Bool(alarm)],
+                    },
+                    time: start + Duration::seconds(i as i64),
+                    seq: 0,
+                })
+            })
+            .collect::<Vec<_>>();
+        let aggr = |aggregator: Aggregator| {
+            compute_aggregate(&aggregator, events.iter(), &attributes, None)
+        };
+
+        let float = |aggregator: Aggregator| aggr(aggregator).unwrap().unwrap_float();
+        assert!((float(Aggregator::Variance(0)) - 2.0).abs() < 1e-9);
+        assert!((float(Aggregator::StdDev(0)) - 2f64.sqrt()).abs() < 1e-9);
+        assert_eq!(aggr(Aggregator::CountDistinct(0)), Some(Value::Int(3)));
+        assert_eq!(aggr(Aggregator::First(0)), Some(Value::Int(2)));
+        assert_eq!(aggr(Aggregator::Last(0)), Some(Value::Int(6)));
+        assert_eq!(aggr(Aggregator::Any(1)), Some(Value::Bool(true)));
+        assert_eq!(aggr(Aggregator::All(1)), Some(Value::Bool(false)));
+        assert_eq!(aggr(Aggregator::Median(0)), Some(Value::Float(4.0)));
+        assert_eq!(aggr(Aggregator::Percentile(0, 0.9)), Some(Value::Float(6.0)));
+        assert_eq!(compute_aggregate(&Aggregator::All(1), events[..0].iter(), &attributes, None),
+                   Some(Value::Bool(true)));
+        assert_eq!(compute_aggregate(&Aggregator::Variance(0),
+                                     events[1..2].iter(),
+                                     &attributes,
+                                     None),
+                   None);
+    }
+
+    #[test]
+    fn running_aggregates() {
+        let values = (0..60)
+            .map(|i| if i % 7 == 3 { Value::Null } else { Value::Int((i * 37) % 23 - 11) })
+            .collect();
+        let (attributes, events) = window(BasicType::Int, values);
+        let aggregators = vec![Aggregator::Count,
+                               Aggregator::Sum(0),
+                               Aggregator::Avg(0),
+                               Aggregator::Min(0),
+                               Aggregator::Max(0)];
+        check_running(aggregators, &attributes, &events, 8);
+        assert!(RunningAggregate::new(&Aggregator::Median(0), &attributes).is_none());
+    }
+
+    #[test]
+    fn int_overflow() {
+        let values = vec![i64::MAX, 1, -2, i64::MAX, i64::MIN, 3, i64::MIN, -1]
+            .into_iter()
+            .map(Value::Int)
+            .collect();
+        let (attributes, events) = window(BasicType::Int, values);
+        // Only the sums that do not fit have no value, whatever the partial sums
+        assert_eq!(compute_aggregate(&Aggregator::Sum(0), events[..2].iter(), &attributes, None),
+                   None);
+        assert_eq!(compute_aggregate(&Aggregator::Sum(0), events[..5].iter(), &attributes, None),
+                   Some(Value::Int(i64::MAX - 2)));
+        assert_eq!(compute_aggregate(&Aggregator::Avg(0), events[..2].iter(), &attributes, None),
+                   Some(Value::Float(2f64.powi(62))));
+        check_running(vec![Aggregator::Sum(0), Aggregator::Avg(0)], &attributes, &events, 2);
+    }
+
+    #[test]
+    fn float_aggregates() {
+        let values = (0..40)
+            .map(|i| match i % 5 {
+                0 => Value::Float(f64::NAN),
+                1 => Value::Null,
+                2 => Value::Float(1e20 * i as f64),
+                3 => Value::Float(1.0),
+                _ => Value::Float(-(i as f64) / 4.0),
+            })
+            .collect();
+        let (attributes, events) = window(BasicType::Float, values);
+        // NaN is skipped by the minimum and the maximum
+        check_running(vec![Aggregator::Count, Aggregator::Min(0), Aggregator::Max(0)],
+                      &attributes,
+                      &events,
+                      6);
+        assert_eq!(compute_aggregate(&Aggregator::Max(0), events[..1].iter(), &attributes, None),
+                   None);
+        assert_eq!(compute_aggregate(&Aggregator::CountDistinct(0),
+                                     events[..6].iter(),
+                                     &attributes,
+                                     None),
+                   Some(Value::Int(3)));
+        assert_eq!(compute_aggregate(&Aggregator::Median(0), events[..6].iter(), &attributes, None),
+                   Some(Value::Float(1.0)));
+        assert_eq!(compute_aggregate(&Aggregator::Median(0),
+                                     events[..2].iter(),
+                                     &attributes,
+                                     Some(0.1)),
+                   None);
+
+        // The sums are scanned, so a large value leaving the window does not cancel the others
+        assert!(RunningAggregate::new(&Aggregator::Sum(0), &attributes).is_none());
+        assert!(RunningAggregate::new(&Aggregator::Avg(0), &attributes).is_none());
+        assert_eq!(compute_aggregate(&Aggregator::Sum(0), events[3..4].iter(), &attributes, None),
+                   Some(Value::Float(1.0)));
     }
 }
@@ -0,0 +1,323 @@
+use std::error;
+use std::fmt;
+use tesla::expressions::{BasicType, BinaryOperator, Expression, UnaryOperator};
+use tesla::predicates::Aggregator;
+
+/// Position of an expression inside a rule.
+#[derive(Clone, Debug)]
+pub enum ExpressionLocation {
+    Parameter { predicate: usize, parameter: usize },
+    Constraint { predicate: usize, constraint: usize },
+    Filter { filter: usize },
+    Template { attribute: usize },
+}
+
+impl fmt::Display for ExpressionLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpressionLocation::Parameter { predicate, parameter } => {
+                write!(f, "parameter {} of predicate {}", parameter, predicate)
+            }
+            ExpressionLocation::Constraint { predicate, constraint } => {
+                write!(f, "constraint {} of predicate {}", constraint, predicate)
+            }
+            ExpressionLocation::Filter { filter } => write!(f, "filter {}", filter),
+            ExpressionLocation::Template { attribute } => {
+                write!(f, "attribute {} of the event template", attribute)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum TypeError {
+    UnknownAttribute { attribute: usize },
+    AttributeWithoutTuple,
+    MisplacedAggregate,
+    UnknownParameter { predicate: usize, parameter: usize },
+    UnknownTimestamp { predicate: usize },
+    InvalidCast { from: BasicType, to: BasicType },
+    UnaryOperand { operator: UnaryOperator, operand: BasicType },
+    BinaryOperands {
+        operator: BinaryOperator,
+        left: BasicType,
+        right: BasicType,
+    },
+    Mismatch { expected: BasicType, found: BasicType },
+    UnknownFunction { name: String },
+    FunctionArguments { name: String, found: Vec<BasicType> },
+    DynamicPattern,
+    InvalidPattern { pattern: String, reason: String },
+    UntypedNull,
+    NullableValue,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TypeError::UnknownAttribute { attribute } => {
+                write!(f, "attribute {} does not exist", attribute)
+            }
+            TypeError::AttributeWithoutTuple => write!(f, "attribute used outside of a tuple"),
+            TypeError::MisplacedAggregate => write!(f, "aggregate used outside of its parameter"),
+            TypeError::UnknownParameter { predicate, parameter } => {
+                write!(f,
+                       "parameter {} of predicate {} is not visible",
+                       parameter,
+                       predicate)
+            }
+            TypeError::UnknownTimestamp { predicate } => {
+                write!(f, "predicate {} does not select an event with a timestamp", predicate)
+            }
+            TypeError::InvalidCast { ref from, ref to } => {
+                write!(f, "cannot cast `{}` to `{}`", from, to)
+            }
+            TypeError::UnaryOperand { ref operator, ref operand } => {
+                write!(f, "wrong operand type `{}` for `{}`", operand, operator)
+            }
+            TypeError::BinaryOperands { ref operator, ref left, ref right } => {
+                write!(f,
+                       "wrong operand types `{}` and `{}` for `{}`",
+                       left,
+                       right,
+                       operator)
+            }
+            TypeError::Mismatch { ref expected, ref found } => {
+                write!(f, "expected `{}`, found `{}`", expected, found)
+            }
+            TypeError::UnknownFunction { ref name } => write!(f, "unknown function `{}`", name),
+            TypeError::FunctionArguments { ref name, ref found } => {
+                let found = found.iter().map(|ty| format!("`{}`", ty)).collect::<Vec<_>>();
+                write!(f,
+                       "wrong argument types ({}) for `{}`",
+                       found.join(", "),
+                       name)
+            }
+            TypeError::DynamicPattern => write!(f, "the pattern is not a string literal"),
+            TypeError::InvalidPattern { ref pattern, ref reason } => {
+                write!(f, "invalid pattern {:?}: {}", pattern, reason)
+            }
+            TypeError::UntypedNull => write!(f, "null cannot be used as a literal"),
+            TypeError::NullableValue => {
+                write!(f, "the value may be null, but the attribute is not nullable")
+            }
+        }
+    }
+}
+
+/// A type error together with the innermost sub-expression that caused it.
+#[derive(Clone, Debug)]
+pub struct ExpressionError {
+    pub expression: Expression,
+    pub error: TypeError,
+}
+
+impl ExpressionError {
+    pub fn new(expression: &Expression, error: TypeError) -> Self {
+        ExpressionError {
+            expression: expression.clone(),
+            error: error,
+        }
+    }
+}
+
+/// Failure in the evaluation of a well typed expression.
+///
+/// A constraint, parameter or template whose evaluation fails
+/// makes the candidate match it belongs to be discarded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EvaluationError {
+    DivisionByZero,
+    Overflow,
+    InvalidShift,
+    InvalidArgument,
+    InvalidResult,
+}
+
+impl fmt::Display for EvaluationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", error::Error::description(self))
+    }
+}
+
+impl error::Error for EvaluationError {
+    fn description(&self) -> &str {
+        match *self {
+            EvaluationError::DivisionByZero => "integer division by zero",
+            EvaluationError::Overflow => "integer overflow",
+            EvaluationError::InvalidShift => "shift amount out of range",
+            EvaluationError::InvalidArgument => "function argument out of its domain",
+            EvaluationError::InvalidResult => "function result of the wrong type",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Error {
+    DuplicateTuple { ty_id: usize },
+    DuplicateFunction { name: String },
+    UnknownTuple { ty_id: usize },
+    UnknownRule { rule_id: usize },
+    MissingTrigger,
+    MisplacedTrigger { predicate: usize },
+    WrongTupleType { predicate: usize },
+    InvalidTiming { predicate: usize },
+    EmptyWindow { predicate: usize },
+    InvalidAggregate { predicate: usize, aggregator: Aggregator },
+    InvalidQuantile { predicate: usize, quantile: f64 },
+    InvalidAccuracy { accuracy: f64 },
+    InvalidConsuming { predicate: usize },
+    StaticTemplate { ty_id: usize },
+    TemplateArity {
+        ty_id: usize,
+        expected: usize,
+        found: usize,
+    },
+    Type {
+        location: ExpressionLocation,
+        expression: Expression,
+        error: TypeError,
+    },
+    NoProvider { predicate: usize },
+    ProviderFailure { predicate: usize, reason: String },
+    RecursiveRule { cycle: Vec<usize> },
+    MaxDepthExceeded { max_depth: usize, ty_id: usize },
+}
+
+impl Error {
+    pub fn at(location: ExpressionLocation, error: ExpressionError) -> Self {
+        Error::Type {
+            location: location,
+            expression: error.expression,
+            error: error.error,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::DuplicateTuple { ty_id } => write!(f, "tuple {} is already declared", ty_id),
+            Error::DuplicateFunction { ref name } => {
+                write!(f, "function `{}` is already defined", name)
+            }
+            Error::UnknownTuple { ty_id } => write!(f, "tuple {} is not declared", ty_id),
+            Error::UnknownRule { rule_id } => write!(f, "rule {} is not defined", rule_id),
+            Error::MissingTrigger => write!(f, "the rule has no trigger predicate"),
+            Error::MisplacedTrigger { predicate } => {
+                write!(f, "predicate {} is a trigger but not the first one", predicate)
+            }
+            Error::WrongTupleType { predicate } => {
+                write!(f,
+                       "predicate {} does not match the kind (event or static) of its tuple",
+                       predicate)
+            }
+            Error::InvalidTiming { predicate } => {
+                write!(f,
+                       "timing of predicate {} does not refer to a previous predicate",
+                       predicate)
+            }
+            Error::EmptyWindow { predicate } => {
+                write!(f, "window of predicate {} cannot contain any event", predicate)
+            }
+            Error::InvalidAggregate { predicate, ref aggregator } => {
+                write!(f,
+                       "aggregate {:?} of predicate {} cannot be computed",
+                       aggregator,
+                       predicate)
+            }
+            Error::InvalidQuantile { predicate, quantile } => {
+                write!(f,
+                       "quantile {} of predicate {} is not between 0 and 1",
+                       quantile,
+                       predicate)
+            }
+            Error::InvalidAccuracy { accuracy } => {
+                write!(f, "accuracy {} is not strictly between 0 and 1", accuracy)
+            }
+            Error::InvalidConsuming { predicate } => {
+                write!(f,
+                       "predicate {} cannot be consumed, it is not an event selection",
+                       predicate)
+            }
+            Error::StaticTemplate { ty_id } => {
+                write!(f, "the rule produces the static tuple {}", ty_id)
+            }
+            Error::TemplateArity { ty_id, expected, found } => {
+                write!(f,
+                       "tuple {} has {} attributes but the template assigns {}",
+                       ty_id,
+                       expected,
+                       found)
+            }
+            Error::Type { ref location, ref error, .. } => {
+                write!(f, "type error in {}: {}", location, error)
+            }
+            Error::NoProvider { predicate } => {
+                write!(f, "no provider can process predicate {}", predicate)
+            }
+            Error::ProviderFailure { predicate, ref reason } => {
+                write!(f, "provider of predicate {} failed: {}", predicate, reason)
+            }
+            Error::RecursiveRule { ref cycle } => {
+                let cycle = cycle.iter().map(|ty| ty.to_string()).collect::<Vec<_>>();
+                write!(f, "the rule introduces the derivation cycle {}", cycle.join(" -> "))
+            }
+            Error::MaxDepthExceeded { max_depth, ty_id } => {
+                write!(f,
+                       "derivation of an event of tuple {} exceeded the maximum depth {}",
+                       ty_id,
+                       max_depth)
+            }
+        }
+    }
+}
+
+/// Failure in publishing an event through an ingest handle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IngestError {
+    Full,
+    Disconnected,
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", error::Error::description(self))
+    }
+}
+
+impl error::Error for IngestError {
+    fn description(&self) -> &str {
+        match *self {
+            IngestError::Full => "the ingest queue is full",
+            IngestError::Disconnected => "the engine dispatcher has stopped",
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        match *self {
+            Error::DuplicateTuple { .. } => "duplicate tuple declaration",
+            Error::DuplicateFunction { .. } => "duplicate function definition",
+            Error::UnknownTuple { .. } => "unknown tuple",
+            Error::UnknownRule { .. } => "unknown rule",
+            Error::MissingTrigger => "missing trigger predicate",
+            Error::MisplacedTrigger { .. } => "misplaced trigger predicate",
+            Error::WrongTupleType { .. } => "wrong tuple type",
+            Error::InvalidTiming { .. } => "invalid timing",
+            Error::EmptyWindow { .. } => "empty window",
+            Error::InvalidAggregate { .. } => "invalid aggregate",
+            Error::InvalidQuantile { .. } => "invalid quantile",
+            Error::InvalidAccuracy { .. } => "invalid accuracy",
+            Error::InvalidConsuming { .. } => "invalid consuming clause",
+            Error::StaticTemplate { .. } => "static event template",
+            Error::TemplateArity { .. } => "wrong event template arity",
+            Error::Type { .. } => "type error",
+            Error::NoProvider { .. } => "no suitable processor",
+            Error::ProviderFailure { .. } => "processor setup failure",
+            Error::RecursiveRule { .. } => "recursive rule",
+            Error::MaxDepthExceeded { .. } => "maximum derivation depth exceeded",
+        }
+    }
+}
@@ -1,13 +1,20 @@
+use chrono::{DateTime, UTC};
+use errors::EvaluationError;
 use rule_processor::PartialResult;
-use super::operations::{binary, unary};
+use std::sync::Arc;
+use super::functions::{self, Functions};
+use super::operations::{binary, cast, unary};
+use super::patterns::{self, Patterns};
 use tesla::*;
 use tesla::expressions::*;
 
-fn cast(value: &Value, ty: &BasicType) -> Value {
-    match (ty, value) {
-        (&BasicType::Float, &Value::Int(val)) => Value::Float(val as f64),
-        _ => panic!("Wrong casting"),
-    }
+/// Functions and compiled patterns the expressions of a rule refer to,
+/// along with the accuracy its percentiles are computed with.
+#[derive(Debug, Default)]
+pub struct Environment {
+    pub functions: Arc<Functions>,
+    pub patterns: Patterns,
+    pub accuracy: Option<f64>,
 }
 
 pub trait EvaluationContext {
@@ -17,40 +24,127 @@ pub trait EvaluationContext {
 
     fn get_parameter(&self, predicate: usize, parameter: usize) -> Value;
 
-    fn evaluate_expression(&self, expression: &Expression) -> Value {
+    fn get_timestamp(&self, predicate: usize) -> DateTime<UTC>;
+
+    fn get_environment(&self) -> &Environment;
+
+    fn evaluate_expression(&self, expression: &Expression) -> Result<Value, EvaluationError> {
         match *expression {
-            Expression::Immediate { ref value } => value.clone(),
-            Expression::Reference { attribute } => self.get_attribute(attribute),
-            Expression::Aggregate => self.get_aggregate(),
+            Expression::Immediate { ref value } => Ok(value.clone()),
+            Expression::Reference { attribute } => Ok(self.get_attribute(attribute)),
+            Expression::Aggregate => Ok(self.get_aggregate()),
+            Expression::Timestamp { predicate } => {
+                Ok(Value::Timestamp(self.get_timestamp(predicate)))
+            }
             Expression::Parameter { predicate, parameter } => {
-                self.get_parameter(predicate, parameter)
+                Ok(self.get_parameter(predicate, parameter))
             }
-            Expression::Cast { ref ty, ref expression } => {
-                cast(&self.evaluate_expression(expression), ty)
+            Expression::Cast { ref ty, ref rounding, ref expression } => {
+                cast::evaluate(&self.evaluate_expression(expression)?, ty, rounding)
             }
             Expression::UnaryOperation { ref operator, ref expression } => {
-                unary::evaluate(operator, &self.evaluate_expression(expression))
+                unary::evaluate(operator, &self.evaluate_expression(expression)?)
+            }
+            Expression::BinaryOperation { operator: BinaryOperator::And, ref left, ref right } => {
+                // Short-circuit: the right operand is evaluated only if needed,
+                // a false operand decides the result even if the other one is null
+                match self.evaluate_expression(left)? {
+                    Value::Bool(false) => Ok(Value::Bool(false)),
+                    Value::Null => {
+                        self.evaluate_expression(right).map(|right| match right {
+                            Value::Bool(false) => right,
+                            _ => Value::Null,
+                        })
+                    }
+                    _ => self.evaluate_expression(right),
+                }
+            }
+            Expression::BinaryOperation { operator: BinaryOperator::Or, ref left, ref right } => {
+                match self.evaluate_expression(left)? {
+                    Value::Bool(true) => Ok(Value::Bool(true)),
+                    Value::Null => {
+                        self.evaluate_expression(right).map(|right| match right {
+                            Value::Bool(true) => right,
+                            _ => Value::Null,
+                        })
+                    }
+                    _ => self.evaluate_expression(right),
+                }
+            }
+            Expression::BinaryOperation { ref operator, ref left, ref right }
+                if patterns::is_pattern_operator(operator) => {
+                let value = match self.evaluate_expression(left)? {
+                    Value::Str(value) => value,
+                    Value::Null => return Ok(Value::Null),
+                    _ => panic!("Wrong Value unwrap"),
+                };
+                let pattern = patterns::get_literal(right)
+                    .map_err(|_| EvaluationError::InvalidArgument)?;
+                // Patterns that could not be compiled (only in subscriptions) never match
+                self.get_environment()
+                    .patterns
+                    .get(operator, pattern)
+                    .map(|regex| Value::Bool(regex.is_match(&value)))
+                    .ok_or(EvaluationError::InvalidArgument)
             }
             Expression::BinaryOperation { ref operator, ref left, ref right } => {
                 binary::evaluate(operator,
-                                 &self.evaluate_expression(left),
-                                 &self.evaluate_expression(right))
+                                 &self.evaluate_expression(left)?,
+                                 &self.evaluate_expression(right)?)
+            }
+            Expression::Function { ref name, ref args } if name == "if" => {
+                // Only the selected branch is evaluated, a null condition selects the second one
+                if self.evaluate_expression(&args[0])? == Value::Bool(true) {
+                    self.evaluate_expression(&args[1])
+                } else {
+                    self.evaluate_expression(&args[2])
+                }
+            }
+            Expression::Function { ref name, ref args } if name == "coalesce" => {
+                // The arguments after the first non null one are not evaluated
+                for arg in args {
+                    let value = self.evaluate_expression(arg)?;
+                    if !value.is_null() {
+                        return Ok(value);
+                    }
+                }
+                Ok(Value::Null)
+            }
+            Expression::Function { ref name, ref args } => {
+                let args = args.iter()
+                    .map(|arg| self.evaluate_expression(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                functions::evaluate(&self.get_environment().functions, name, &args)
             }
         }
     }
+
+    /// Checks a boolean constraint, a null result or an evaluation error makes it unsatisfied.
+    fn is_satisfied(&self, constraint: &Expression) -> bool {
+        match self.evaluate_expression(constraint) {
+            Ok(Value::Bool(value)) => value,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct SimpleContext<'a> {
-    tuple: &'a Tuple,
+    event: &'a Event,
+    env: &'a Environment,
 }
 
 impl<'a> SimpleContext<'a> {
-    pub fn new(tuple: &'a Tuple) -> Self { SimpleContext { tuple: tuple } }
+    pub fn new(event: &'a Event, env: &'a Environment) -> Self {
+        SimpleContext {
+            event: event,
+            env: env,
+        }
+    }
 }
 
 impl<'a> EvaluationContext for SimpleContext<'a> {
-    fn get_attribute(&self, attribute: usize) -> Value { self.tuple.data[attribute].clone() }
+    fn get_attribute(&self, attribute: usize) -> Value { self.event.tuple.data[attribute].clone() }
 
     fn get_aggregate(&self) -> Value {
         panic!("SimpleContext cannot retrieve aggregates");
@@ -59,13 +153,18 @@ impl<'a> EvaluationContext for SimpleContext<'a> {
     fn get_parameter(&self, _: usize, _: usize) -> Value {
         panic!("SimpleContext cannot retrieve parameters");
     }
+
+    // The only event available is the one the expression is evaluated on
+    fn get_timestamp(&self, _: usize) -> DateTime<UTC> { self.event.time }
+
+    fn get_environment(&self) -> &Environment { self.env }
 }
 
 #[derive(Clone, Debug)]
 pub enum CurrentValue<'a> {
     Empty,
     Aggr(&'a Value),
-    Tuple(&'a Tuple),
+    Event(&'a Event),
 }
 
 impl<'a> From<()> for CurrentValue<'a> {
@@ -76,31 +175,33 @@ impl<'a> From<&'a Value> for CurrentValue<'a> {
     fn from(aggr: &'a Value) -> Self { CurrentValue::Aggr(aggr) }
 }
 
-impl<'a> From<&'a Tuple> for CurrentValue<'a> {
-    fn from(tuple: &'a Tuple) -> Self { CurrentValue::Tuple(tuple) }
+impl<'a> From<&'a Arc<Event>> for CurrentValue<'a> {
+    fn from(event: &'a Arc<Event>) -> Self { CurrentValue::Event(event) }
 }
 
 #[derive(Clone, Debug)]
 pub struct CompleteContext<'a> {
     result: &'a PartialResult,
     current: CurrentValue<'a>,
+    env: &'a Environment,
 }
 
 impl<'a> CompleteContext<'a> {
-    pub fn new<T>(result: &'a PartialResult, current: T) -> Self
+    pub fn new<T>(result: &'a PartialResult, current: T, env: &'a Environment) -> Self
         where T: Into<CurrentValue<'a>>
     {
         CompleteContext {
             result: result,
             current: current.into(),
+            env: env,
         }
     }
 }
 
 impl<'a> EvaluationContext for CompleteContext<'a> {
     fn get_attribute(&self, attribute: usize) -> Value {
-        if let CurrentValue::Tuple(tuple) = self.current {
-            tuple.data[attribute].clone()
+        if let CurrentValue::Event(event) = self.current {
+            event.tuple.data[attribute].clone()
         } else {
             panic!("Trying to get a tuple attribute on an aggregate")
         }
@@ -117,4 +218,15 @@ impl<'a> EvaluationContext for CompleteContext<'a> {
     fn get_parameter(&self, predicate: usize, parameter: usize) -> Value {
         self.result.get_parameter((predicate, parameter)).clone()
     }
+
+    fn get_timestamp(&self, predicate: usize) -> DateTime<UTC> {
+        // The current event is not part of the result until it is accepted
+        match (self.result.get_event(predicate), &self.current) {
+            (Some(event), _) => event.time,
+            (None, &CurrentValue::Event(event)) => event.time,
+            _ => panic!("Trying to get the timestamp of a missing event"),
+        }
+    }
+
+    fn get_environment(&self) -> &Environment { self.env }
 }
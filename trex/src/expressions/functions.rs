@@ -0,0 +1,297 @@
+//! Scalar functions available in rule expressions.
+//!
+//! Functions are strict: a null argument makes the result null, as in SQL.
+//! The exceptions are `if` and `coalesce`, whose arguments are not all evaluated:
+//! `EvaluationContext` evaluates just the selected branch of the former
+//! and stops at the first non null argument of the latter.
+//!
+//! The numeric arguments of `floor`, `ceil`, `round`, `sqrt`, `pow`, `min` and `max`
+//! are promoted as the operands of the operators, while the alternatives of `if`
+//! and `coalesce` must have the same type.
+
+use FnvHashMap;
+use errors::{EvaluationError, TypeError};
+use std::fmt;
+use std::sync::Arc;
+use super::operations::{binary, cast};
+use tesla::expressions::{BasicType, BinaryOperator, Rounding, Value};
+
+/// Scalar function registered by the user on the engine.
+///
+/// The closure is invoked only with arguments of the declared types
+/// and it must return a value of the declared output type.
+/// Since the arguments are never null, neither can the result be:
+/// any other value makes the call fail with `InvalidResult`.
+pub struct UserFunction {
+    pub args: Vec<BasicType>,
+    pub output: BasicType,
+    pub function: Box<Fn(&[Value]) -> Result<Value, EvaluationError> + Send + Sync>,
+}
+
+impl UserFunction {
+    pub fn new<F>(args: Vec<BasicType>, output: BasicType, function: F) -> Self
+        where F: Fn(&[Value]) -> Result<Value, EvaluationError> + Send + Sync + 'static
+    {
+        UserFunction {
+            args: args,
+            output: output,
+            function: Box::new(function),
+        }
+    }
+
+    pub fn call(&self, args: &[Value]) -> Result<Value, EvaluationError> {
+        let value = (self.function)(args)?;
+        if !value.is_null() && value.get_type() == self.output {
+            Ok(value)
+        } else {
+            Err(EvaluationError::InvalidResult)
+        }
+    }
+}
+
+impl fmt::Debug for UserFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("UserFunction")
+            .field("args", &self.args)
+            .field("output", &self.output)
+            .finish()
+    }
+}
+
+/// User functions known to the engine, indexed by name.
+pub type Functions = FnvHashMap<String, Arc<UserFunction>>;
+
+const BUILTINS: &'static [&'static str] = &["abs", "floor", "ceil", "round", "sqrt", "pow", "min",
+                                            "max", "len", "lower", "upper", "substr",
+                                            "contains", "starts_with", "if", "coalesce"];
+
+pub fn is_builtin(name: &str) -> bool { BUILTINS.contains(&name) }
+
+fn is_numeric(ty: &BasicType) -> bool {
+    match *ty {
+        BasicType::Int | BasicType::Float => true,
+        _ => false,
+    }
+}
+
+fn all_equal(args: &[BasicType]) -> bool { args.iter().all(|ty| *ty == args[0]) }
+
+/// Type all the arguments can be promoted to, as if they were compared with each other.
+fn common_type(args: &[BasicType]) -> Option<BasicType> {
+    args.iter().skip(1).fold(args.first().cloned(), |res, ty| {
+        res.and_then(|res| if res == *ty {
+            Some(res)
+        } else {
+            binary::promote(&BinaryOperator::LowerThan, &res, ty)
+        })
+    })
+}
+
+fn signature(name: &str, args: &[BasicType]) -> Option<BasicType> {
+    let is = |tys: &[BasicType]| args == tys;
+    match name {
+        "abs" if is(&[BasicType::Int]) => Some(BasicType::Int),
+        "abs" if is(&[BasicType::Float]) => Some(BasicType::Float),
+        "floor" | "ceil" | "round" | "sqrt" if args.len() == 1 && is_numeric(&args[0]) => {
+            Some(BasicType::Float)
+        }
+        "pow" if args.len() == 2 && args.iter().all(is_numeric) => Some(BasicType::Float),
+        "min" | "max" if args.len() >= 2 => {
+            common_type(args).and_then(|ty| if ty != BasicType::Bool { Some(ty) } else { None })
+        }
+        "len" if is(&[BasicType::Str]) => Some(BasicType::Int),
+        "lower" | "upper" if is(&[BasicType::Str]) => Some(BasicType::Str),
+        "substr" if is(&[BasicType::Str, BasicType::Int]) ||
+                    is(&[BasicType::Str, BasicType::Int, BasicType::Int]) => Some(BasicType::Str),
+        "contains" | "starts_with" if is(&[BasicType::Str, BasicType::Str]) => {
+            Some(BasicType::Bool)
+        }
+        "if" if args.len() == 3 && args[0] == BasicType::Bool && args[1] == args[2] => {
+            Some(args[1].clone())
+        }
+        "coalesce" if !args.is_empty() && all_equal(args) => Some(args[0].clone()),
+        _ => None,
+    }
+}
+
+/// Type of a function call, built-in functions shadow the user defined ones.
+pub fn get_type(functions: &Functions,
+                name: &str,
+                args: &[BasicType])
+                -> Result<BasicType, TypeError> {
+    let arguments_error = || {
+        TypeError::FunctionArguments {
+            name: name.to_owned(),
+            found: args.to_vec(),
+        }
+    };
+    if is_builtin(name) {
+        signature(name, args).ok_or_else(arguments_error)
+    } else if let Some(function) = functions.get(name) {
+        if function.args == args { Ok(function.output.clone()) } else { Err(arguments_error()) }
+    } else {
+        Err(TypeError::UnknownFunction { name: name.to_owned() })
+    }
+}
+
+fn as_float(value: &Value) -> f64 {
+    match *value {
+        Value::Int(x) => x as f64,
+        Value::Float(x) => x,
+        _ => panic!("Wrong use of numeric function"),
+    }
+}
+
+fn evaluate_abs(value: &Value) -> Result<Value, EvaluationError> {
+    match *value {
+        Value::Int(x) => x.checked_abs().map(Value::Int).ok_or(EvaluationError::Overflow),
+        Value::Float(x) => Ok(Value::Float(x.abs())),
+        _ => panic!("Wrong use of abs function"),
+    }
+}
+
+/// Substring of `length` characters (or up to the end) starting at the 1-based `start`,
+/// as in SQL.
+fn evaluate_substr(value: &str,
+                   start: i64,
+                   length: Option<i64>)
+                   -> Result<Value, EvaluationError> {
+    if start < 1 || length.map_or(false, |length| length < 0) {
+        return Err(EvaluationError::InvalidArgument);
+    }
+    let chars = value.chars().skip((start - 1) as usize);
+    Ok(Value::Str(match length {
+        Some(length) => chars.take(length as usize).collect(),
+        None => chars.collect(),
+    }))
+}
+
+/// Selects the first of the arguments ordered by `operator`, promoted to their common type.
+fn select(operator: BinaryOperator, args: &[Value]) -> Value {
+    let selected = args.iter().skip(1).fold(&args[0], |res, arg| {
+        let ordered = binary::evaluate(&operator, res, arg).map(|it| it.unwrap_bool());
+        if ordered.unwrap_or(true) { res } else { arg }
+    });
+    let types = args.iter().map(Value::get_type).collect::<Vec<_>>();
+    match common_type(&types) {
+        // Promotions never fail
+        Some(ty) => cast::evaluate(selected, &ty, &Rounding::Truncate).unwrap(),
+        None => selected.clone(),
+    }
+}
+
+pub fn evaluate(functions: &Functions,
+                name: &str,
+                args: &[Value])
+                -> Result<Value, EvaluationError> {
+    match name {
+        "if" => Ok(if args[0] == Value::Bool(true) { args[1].clone() } else { args[2].clone() }),
+        "coalesce" => Ok(args.iter().find(|arg| !arg.is_null()).unwrap_or(&Value::Null).clone()),
+        _ if args.iter().any(Value::is_null) => Ok(Value::Null),
+        "abs" => evaluate_abs(&args[0]),
+        "floor" => Ok(Value::Float(as_float(&args[0]).floor())),
+        "ceil" => Ok(Value::Float(as_float(&args[0]).ceil())),
+        "round" => Ok(Value::Float(as_float(&args[0]).round())),
+        "sqrt" => Ok(Value::Float(as_float(&args[0]).sqrt())),
+        "pow" => Ok(Value::Float(as_float(&args[0]).powf(as_float(&args[1])))),
+        "min" => Ok(select(BinaryOperator::LowerEqual, args)),
+        "max" => Ok(select(BinaryOperator::GreaterEqual, args)),
+        "len" => Ok(Value::Int(args[0].unwrap_string().chars().count() as i64)),
+        "lower" => Ok(Value::Str(args[0].unwrap_string().to_lowercase())),
+        "upper" => Ok(Value::Str(args[0].unwrap_string().to_uppercase())),
+        "substr" => {
+            evaluate_substr(&args[0].unwrap_string(),
+                            args[1].unwrap_int(),
+                            args.get(2).map(Value::unwrap_int))
+        }
+        "contains" => Ok(Value::Bool(args[0].unwrap_string().contains(&args[1].unwrap_string()))),
+        "starts_with" => {
+            Ok(Value::Bool(args[0].unwrap_string().starts_with(&args[1].unwrap_string())))
+        }
+        _ => {
+            functions.get(name)
+                .unwrap_or_else(|| panic!("Unknown function {}", name))
+                .call(args)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use errors::EvaluationError;
+    use std::sync::Arc;
+    use super::{Functions, UserFunction, evaluate, get_type};
+    use tesla::expressions::{BasicType, Value};
+
+    #[test]
+    fn builtins() {
+        let functions = Functions::default();
+        let text = Value::Str("Milano".to_owned());
+        assert_eq!(evaluate(&functions,
+                            "substr",
+                            &[text.clone(), Value::Int(2), Value::Int(3)]),
+                   Ok(Value::Str("ila".to_owned())));
+        assert!(evaluate(&functions, "substr", &[text.clone(), Value::Int(0)]).is_err());
+        assert_eq!(evaluate(&functions, "max", &[Value::Int(3), Value::Int(7), Value::Int(5)]),
+                   Ok(Value::Int(7)));
+        assert_eq!(evaluate(&functions, "abs", &[Value::Float(-1.5)]),
+                   Ok(Value::Float(1.5)));
+        assert_eq!(evaluate(&functions, "round", &[Value::Int(2)]), Ok(Value::Float(2.0)));
+        assert_eq!(evaluate(&functions, "max", &[Value::Int(3), Value::Float(2.5)]),
+                   Ok(Value::Float(3.0)));
+        assert_eq!(evaluate(&functions, "len", &[text]), Ok(Value::Int(6)));
+        assert_eq!(evaluate(&functions, "max", &[Value::Int(3), Value::Null]), Ok(Value::Null));
+        assert_eq!(evaluate(&functions, "coalesce", &[Value::Null, Value::Int(3), Value::Null]),
+                   Ok(Value::Int(3)));
+        assert_eq!(evaluate(&functions, "if", &[Value::Null, Value::Int(1), Value::Int(2)]),
+                   Ok(Value::Int(2)));
+
+        assert_eq!(get_type(&functions, "pow", &[BasicType::Int, BasicType::Float]).ok(),
+                   Some(BasicType::Float));
+        assert_eq!(get_type(&functions, "floor", &[BasicType::Int]).ok(),
+                   Some(BasicType::Float));
+        assert_eq!(get_type(&functions, "max", &[BasicType::Int, BasicType::Float]).ok(),
+                   Some(BasicType::Float));
+        assert!(get_type(&functions, "max", &[BasicType::Bool, BasicType::Bool]).is_err());
+        assert!(get_type(&functions, "max", &[BasicType::Str, BasicType::Int]).is_err());
+        assert!(get_type(&functions, "lower", &[BasicType::Int]).is_err());
+        assert!(get_type(&functions, "geohash", &[]).is_err());
+    }
+
+    #[test]
+    fn user_functions() {
+        let mut functions = Functions::default();
+        let checksum = UserFunction::new(vec![BasicType::Str], BasicType::Bool, |args| {
+            match args[0] {
+                Value::Str(ref code) if !code.is_empty() => {
+                    let sum = code.bytes().fold(0u32, |sum, byte| sum + byte as u32);
+                    Ok(Value::Bool(sum % 10 == 0))
+                }
+                _ => Err(EvaluationError::InvalidArgument),
+            }
+        });
+        functions.insert("checksum".to_owned(), Arc::new(checksum));
+
+        assert_eq!(get_type(&functions, "checksum", &[BasicType::Str]).ok(),
+                   Some(BasicType::Bool));
+        assert!(get_type(&functions, "checksum", &[BasicType::Int]).is_err());
+        assert_eq!(evaluate(&functions, "checksum", &[Value::Str("dd".to_owned())]),
+                   Ok(Value::Bool(true)));
+        assert!(evaluate(&functions, "checksum", &[Value::Str(String::new())]).is_err());
+        assert_eq!(evaluate(&functions, "checksum", &[Value::Null]), Ok(Value::Null));
+
+        let parity = UserFunction::new(vec![BasicType::Int], BasicType::Bool, |args| {
+            match args[0] {
+                Value::Int(0) => Ok(Value::Null),
+                Value::Int(x) if x > 0 => Ok(Value::Bool(x % 2 == 0)),
+                _ => Ok(Value::Str("negative".to_owned())),
+            }
+        });
+        functions.insert("parity".to_owned(), Arc::new(parity));
+        assert_eq!(evaluate(&functions, "parity", &[Value::Int(4)]), Ok(Value::Bool(true)));
+        assert_eq!(evaluate(&functions, "parity", &[Value::Int(0)]),
+                   Err(EvaluationError::InvalidResult));
+        assert_eq!(evaluate(&functions, "parity", &[Value::Int(-1)]),
+                   Err(EvaluationError::InvalidResult));
+    }
+}
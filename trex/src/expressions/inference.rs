@@ -1,11 +1,11 @@
+use errors::{ExpressionError, TypeError};
 use linear_map::LinearMap;
-use super::operations::{binary, unary};
+use super::functions::{self, Functions};
+use super::patterns;
+use super::operations::{binary, cast, unary};
 use tesla::TupleDeclaration;
 use tesla::expressions::*;
 
-// TODO improve error handling and more informative failure,
-// or switch completely to a panic!() approach and defer checks to parser
-
 #[derive(Clone, Debug)]
 pub enum CurrentType<'a> {
     Empty,
@@ -16,29 +16,55 @@ pub enum CurrentType<'a> {
 #[derive(Clone, Debug)]
 pub struct InferenceContext<'a> {
     params: LinearMap<(usize, usize), BasicType>,
+    /// Parameters that may be null
+    nullable: Vec<(usize, usize)>,
     current: CurrentType<'a>,
+    /// Predicates whose event is part of the result
+    timestamps: Vec<usize>,
+    /// Predicate of the event the current tuple belongs to
+    current_event: Option<usize>,
+    functions: &'a Functions,
 }
 
 impl<'a> InferenceContext<'a> {
-    pub fn new() -> Self {
+    pub fn new(functions: &'a Functions) -> Self {
         InferenceContext {
             params: LinearMap::new(),
+            nullable: Vec::new(),
             current: CurrentType::Empty,
+            timestamps: Vec::new(),
+            current_event: None,
+            functions: functions,
         }
     }
 
-    pub fn add_parameter(mut self, idx: (usize, usize), ty: BasicType) -> Self {
+    pub fn add_parameter(mut self, idx: (usize, usize), ty: BasicType, nullable: bool) -> Self {
         self.params.insert(idx, ty);
+        if nullable {
+            self.nullable.push(idx);
+        }
+        self
+    }
+
+    pub fn add_timestamp(mut self, predicate: usize) -> Self {
+        self.timestamps.push(predicate);
         self
     }
 
     pub fn set_current(mut self, current: CurrentType<'a>) -> Self {
         self.current = current;
+        self.current_event = None;
+        self
+    }
+
+    pub fn set_current_event(mut self, predicate: usize) -> Self {
+        self.current_event = Some(predicate);
         self
     }
 
     pub fn reset_current(mut self) -> Self {
         self.current = CurrentType::Empty;
+        self.current_event = None;
         self
     }
 
@@ -46,57 +72,119 @@ impl<'a> InferenceContext<'a> {
 }
 
 impl<'a> InferenceContext<'a> {
-    fn get_attribute_ty(&self, attribute: usize) -> Result<BasicType, String> {
+    fn get_attribute_ty(&self, attribute: usize) -> Result<BasicType, TypeError> {
         if let CurrentType::Tuple(tuple) = self.current {
             tuple.attributes
                 .get(attribute)
                 .map(|it| it.ty.clone())
-                .ok_or("Attribute out of bound".to_owned())
+                .ok_or(TypeError::UnknownAttribute { attribute: attribute })
         } else {
-            Err("Cannot get attribute without a tuple".to_owned())
+            Err(TypeError::AttributeWithoutTuple)
         }
     }
 
-    fn get_aggregate_ty(&self) -> Result<BasicType, String> {
+    fn get_aggregate_ty(&self) -> Result<BasicType, TypeError> {
         if let CurrentType::Aggr(ref aggr) = self.current {
             Ok(aggr.clone())
         } else {
-            Err("Cannot get aggregate".to_owned())
+            Err(TypeError::MisplacedAggregate)
         }
     }
 
-    fn get_parameter_ty(&self, predicate: usize, parameter: usize) -> Result<BasicType, String> {
+    fn get_parameter_ty(&self, predicate: usize, parameter: usize) -> Result<BasicType, TypeError> {
         self.params
             .get(&(predicate, parameter))
             .cloned()
-            .ok_or("No such parameter".to_owned())
+            .ok_or(TypeError::UnknownParameter {
+                predicate: predicate,
+                parameter: parameter,
+            })
+    }
+
+    fn get_timestamp_ty(&self, predicate: usize) -> Result<BasicType, TypeError> {
+        if self.current_event == Some(predicate) || self.timestamps.contains(&predicate) {
+            Ok(BasicType::Timestamp)
+        } else {
+            Err(TypeError::UnknownTimestamp { predicate: predicate })
+        }
     }
 
-    pub fn infer_expression(&self, expression: &Expression) -> Result<BasicType, String> {
+    /// Infers the type of an expression, on failure it reports
+    /// the innermost sub-expression that is not well typed.
+    pub fn infer_expression(&self, expression: &Expression) -> Result<BasicType, ExpressionError> {
+        let err = |error| ExpressionError::new(expression, error);
         match *expression {
+            Expression::Immediate { value: Value::Null } => Err(err(TypeError::UntypedNull)),
             Expression::Immediate { ref value } => Ok(value.get_type()),
-            Expression::Reference { attribute } => self.get_attribute_ty(attribute),
-            Expression::Aggregate => self.get_aggregate_ty(),
+            Expression::Reference { attribute } => self.get_attribute_ty(attribute).map_err(err),
+            Expression::Aggregate => self.get_aggregate_ty().map_err(err),
+            Expression::Timestamp { predicate } => self.get_timestamp_ty(predicate).map_err(err),
             Expression::Parameter { predicate, parameter } => {
-                self.get_parameter_ty(predicate, parameter)
+                self.get_parameter_ty(predicate, parameter).map_err(err)
             }
-            Expression::Cast { ref ty, ref expression } => {
-                self.infer_expression(expression).and_then(|inner| {
-                    if *ty == BasicType::Float && inner == BasicType::Int {
-                        Ok(BasicType::Float)
-                    } else {
-                        Err("Bad cast".to_owned())
-                    }
-                })
+            Expression::Cast { ref ty, expression: ref inner, .. } => {
+                self.infer_expression(inner)
+                    .and_then(|it| cast::get_type(&it, ty).map_err(err))
             }
-            Expression::UnaryOperation { ref operator, ref expression } => {
-                self.infer_expression(expression).and_then(|it| unary::get_type(operator, &it))
+            Expression::UnaryOperation { ref operator, expression: ref inner } => {
+                self.infer_expression(inner)
+                    .and_then(|it| unary::get_type(operator, &it).map_err(err))
             }
             Expression::BinaryOperation { ref operator, ref left, ref right } => {
                 self.infer_expression(left)
                     .and_then(|left| self.infer_expression(right).map(|right| (left, right)))
-                    .and_then(|(left, right)| binary::get_type(operator, &left, &right))
+                    .and_then(|(left, right)| {
+                        binary::get_type(operator, &left, &right).map_err(err)
+                    })
+                    .and_then(|ty| {
+                        if patterns::is_pattern_operator(operator) {
+                            patterns::check(operator, right)
+                                .map_err(|error| ExpressionError::new(right, error))?;
+                        }
+                        Ok(ty)
+                    })
+            }
+            Expression::Function { ref name, ref args } => {
+                args.iter()
+                    .map(|arg| self.infer_expression(arg))
+                    .collect::<Result<Vec<_>, _>>()
+                    .and_then(|args| functions::get_type(self.functions, name, &args).map_err(err))
+            }
+        }
+    }
+
+    /// Tells whether an expression may evaluate to null,
+    /// the analysis is conservative but it never misses a nullable one.
+    pub fn is_nullable(&self, expression: &Expression) -> bool {
+        match *expression {
+            Expression::Immediate { ref value } => value.is_null(),
+            Expression::Reference { attribute } => {
+                if let CurrentType::Tuple(tuple) = self.current {
+                    tuple.attributes.get(attribute).map_or(false, |it| it.nullable)
+                } else {
+                    false
+                }
+            }
+            // Null values are skipped by the aggregators
+            Expression::Aggregate |
+            Expression::Timestamp { .. } => false,
+            Expression::Parameter { predicate, parameter } => {
+                self.nullable.contains(&(predicate, parameter))
+            }
+            Expression::UnaryOperation { operator: UnaryOperator::IsNull, .. } |
+            Expression::UnaryOperation { operator: UnaryOperator::IsNotNull, .. } => false,
+            Expression::Cast { ref expression, .. } |
+            Expression::UnaryOperation { ref expression, .. } => self.is_nullable(expression),
+            Expression::BinaryOperation { ref left, ref right, .. } => {
+                self.is_nullable(left) || self.is_nullable(right)
+            }
+            Expression::Function { ref name, ref args } if name == "if" => {
+                self.is_nullable(&args[1]) || self.is_nullable(&args[2])
+            }
+            Expression::Function { ref name, ref args } if name == "coalesce" => {
+                args.iter().all(|arg| self.is_nullable(arg))
             }
+            Expression::Function { ref args, .. } => args.iter().any(|arg| self.is_nullable(arg)),
         }
     }
 }
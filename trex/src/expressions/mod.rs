@@ -1,3 +1,5 @@
-mod operations;
+pub mod functions;
+pub mod patterns;
+pub mod operations;
 pub mod evaluation;
 pub mod inference;
@@ -1,32 +1,43 @@
 pub mod unary {
+    use chrono::Duration;
+    use errors::{EvaluationError, TypeError};
     use tesla::expressions::{BasicType, UnaryOperator, Value};
 
-    fn get_type_minus(ty: &BasicType) -> Result<BasicType, String> {
+    fn get_type_minus(ty: &BasicType) -> Option<BasicType> {
         match *ty {
-            BasicType::Int | BasicType::Float => Ok(ty.clone()),
-            _ => Err("Wrong operand type in unary minus".to_owned()),
+            BasicType::Int | BasicType::Float | BasicType::Duration => Some(ty.clone()),
+            _ => None,
         }
     }
 
-    fn get_type_not(ty: &BasicType) -> Result<BasicType, String> {
-        if let BasicType::Bool = *ty {
-            Ok(ty.clone())
-        } else {
-            Err("Wrong operand type in unary not".to_owned())
-        }
+    fn get_type_not(ty: &BasicType) -> Option<BasicType> {
+        if let BasicType::Bool = *ty { Some(ty.clone()) } else { None }
     }
 
-    pub fn get_type(operator: &UnaryOperator, ty: &BasicType) -> Result<BasicType, String> {
+    pub fn get_type(operator: &UnaryOperator, ty: &BasicType) -> Result<BasicType, TypeError> {
         match *operator {
-            UnaryOperator::Minus => get_type_minus(ty),
-            UnaryOperator::Not => get_type_not(ty),
-        }
+                UnaryOperator::Minus => get_type_minus(ty),
+                UnaryOperator::Not => get_type_not(ty),
+                UnaryOperator::IsNull | UnaryOperator::IsNotNull => Some(BasicType::Bool),
+            }
+            .ok_or_else(|| {
+                TypeError::UnaryOperand {
+                    operator: operator.clone(),
+                    operand: ty.clone(),
+                }
+            })
     }
 
-    fn evaluate_minus(value: &Value) -> Value {
+    fn evaluate_minus(value: &Value) -> Result<Value, EvaluationError> {
         match *value {
-            Value::Int(x) => Value::Int(-x),
-            Value::Float(x) => Value::Float(-x),
+            Value::Int(x) => x.checked_neg().map(Value::Int).ok_or(EvaluationError::Overflow),
+            Value::Float(x) => Ok(Value::Float(-x)),
+            Value::Duration(x) => {
+                Duration::zero()
+                    .checked_sub(&x)
+                    .map(Value::Duration)
+                    .ok_or(EvaluationError::Overflow)
+            }
             _ => panic!("Wrong use of unary minus"),
         }
     }
@@ -38,97 +49,357 @@ pub mod unary {
         }
     }
 
-    pub fn evaluate(operator: &UnaryOperator, value: &Value) -> Value {
+    pub fn evaluate(operator: &UnaryOperator, value: &Value) -> Result<Value, EvaluationError> {
         match *operator {
+            UnaryOperator::IsNull => Ok(Value::Bool(value.is_null())),
+            UnaryOperator::IsNotNull => Ok(Value::Bool(!value.is_null())),
+            _ if value.is_null() => Ok(Value::Null),
             UnaryOperator::Minus => evaluate_minus(value),
-            UnaryOperator::Not => evaluate_not(value),
+            UnaryOperator::Not => Ok(evaluate_not(value)),
+        }
+    }
+}
+
+pub mod cast {
+    use errors::{EvaluationError, TypeError};
+    use tesla::expressions::{BasicType, Rounding, Value};
+
+    pub fn get_type(from: &BasicType, to: &BasicType) -> Result<BasicType, TypeError> {
+        match (from, to) {
+            (from, to) if from == to => Ok(to.clone()),
+            (&BasicType::Int, &BasicType::Float) |
+            (&BasicType::Float, &BasicType::Int) |
+            (&BasicType::Bool, &BasicType::Int) |
+            (&BasicType::Bool, &BasicType::Float) |
+            (&BasicType::Str, &BasicType::Int) |
+            (&BasicType::Str, &BasicType::Float) |
+            (&BasicType::Int, &BasicType::Str) |
+            (&BasicType::Float, &BasicType::Str) |
+            (&BasicType::Bool, &BasicType::Str) => Ok(to.clone()),
+            _ => {
+                Err(TypeError::InvalidCast {
+                    from: from.clone(),
+                    to: to.clone(),
+                })
+            }
+        }
+    }
+
+    fn float_to_int(value: f64, rounding: &Rounding) -> Result<Value, EvaluationError> {
+        let value = match *rounding {
+            Rounding::Truncate => value.trunc(),
+            Rounding::Round => value.round(),
+        };
+        // The upper bound is excluded, as 2^63 does not fit, while NaN fails both checks
+        if value >= i64::min_value() as f64 && value < -(i64::min_value() as f64) {
+            Ok(Value::Int(value as i64))
+        } else {
+            Err(EvaluationError::Overflow)
+        }
+    }
+
+    /// Formats a float so that it always reads as one, as float literals are printed.
+    fn float_to_string(value: f64) -> String {
+        let res = value.to_string();
+        if res.contains('.') || !value.is_finite() { res } else { res + ".0" }
+    }
+
+    /// Converts a value, the conversion from a string fails if it does not hold a number.
+    pub fn evaluate(value: &Value,
+                    ty: &BasicType,
+                    rounding: &Rounding)
+                    -> Result<Value, EvaluationError> {
+        match (value, ty) {
+            (&Value::Null, _) => Ok(Value::Null),
+            (&Value::Int(x), &BasicType::Float) => Ok(Value::Float(x as f64)),
+            (&Value::Float(x), &BasicType::Int) => float_to_int(x, rounding),
+            (&Value::Bool(x), &BasicType::Int) => Ok(Value::Int(x as i64)),
+            (&Value::Bool(x), &BasicType::Float) => Ok(Value::Float(if x { 1.0 } else { 0.0 })),
+            (&Value::Str(ref x), &BasicType::Int) => {
+                x.parse().map(Value::Int).map_err(|_| EvaluationError::InvalidArgument)
+            }
+            (&Value::Str(ref x), &BasicType::Float) => {
+                x.parse().map(Value::Float).map_err(|_| EvaluationError::InvalidArgument)
+            }
+            (&Value::Int(x), &BasicType::Str) => Ok(Value::Str(x.to_string())),
+            (&Value::Float(x), &BasicType::Str) => Ok(Value::Str(float_to_string(x))),
+            (&Value::Bool(x), &BasicType::Str) => Ok(Value::Str(x.to_string())),
+            (value, ty) if value.get_type() == *ty => Ok(value.clone()),
+            _ => panic!("Wrong casting"),
         }
     }
 }
 
 pub mod binary {
+    use chrono::Duration;
+    use errors::{EvaluationError, TypeError};
     use std::f64::EPSILON;
-    use tesla::expressions::{BasicType, BinaryOperator, Value};
+    use super::cast;
+    use tesla::expressions::{BasicType, BinaryOperator, Rounding, Value};
+
+    fn rank(ty: &BasicType) -> Option<usize> {
+        match *ty {
+            BasicType::Bool => Some(0),
+            BasicType::Int => Some(1),
+            BasicType::Float => Some(2),
+            _ => None,
+        }
+    }
 
-    fn get_type_arithmetic(left: &BasicType, right: &BasicType) -> Result<BasicType, String> {
+    /// Type the operands are implicitly converted to, if they differ: the least common one
+    /// along `Bool -> Int -> Float`, or a string when the other operand of `+` is a string.
+    pub fn promote(operator: &BinaryOperator,
+                   left: &BasicType,
+                   right: &BasicType)
+                   -> Option<BasicType> {
+        match (operator, left, right) {
+            (_, left, right) if left == right => None,
+            (&BinaryOperator::Plus, &BasicType::Str, other) |
+            (&BinaryOperator::Plus, other, &BasicType::Str) if rank(other).is_some() => {
+                Some(BasicType::Str)
+            }
+            _ => {
+                match (rank(left), rank(right)) {
+                    (Some(lhs), Some(rhs)) => Some(if lhs > rhs { left } else { right }.clone()),
+                    _ => None,
+                }
+            }
+        }
+    }
+
+    fn get_type_arithmetic(left: &BasicType, right: &BasicType) -> Option<BasicType> {
         match (left, right) {
-            (&BasicType::Int, &BasicType::Int) => Ok(BasicType::Int),
-            (&BasicType::Float, &BasicType::Float) => Ok(BasicType::Float),
-            _ => Err("Wrong operands types in arithmetic operation".to_owned()),
+            (&BasicType::Int, &BasicType::Int) => Some(BasicType::Int),
+            (&BasicType::Float, &BasicType::Float) => Some(BasicType::Float),
+            _ => None,
         }
     }
 
-    fn get_type_equality(left: &BasicType, right: &BasicType) -> Result<BasicType, String> {
-        if left == right {
-            Ok(BasicType::Bool)
-        } else {
-            Err("Wrong operands types in equality operation".to_owned())
+    fn get_type_concatenation(left: &BasicType, right: &BasicType) -> Option<BasicType> {
+        match (left, right) {
+            (&BasicType::Str, &BasicType::Str) => Some(BasicType::Str),
+            _ => None,
+        }
+    }
+
+    fn get_type_temporal(operator: &BinaryOperator,
+                         left: &BasicType,
+                         right: &BasicType)
+                         -> Option<BasicType> {
+        match (operator, left, right) {
+            (&BinaryOperator::Plus, &BasicType::Timestamp, &BasicType::Duration) |
+            (&BinaryOperator::Plus, &BasicType::Duration, &BasicType::Timestamp) |
+            (&BinaryOperator::Minus, &BasicType::Timestamp, &BasicType::Duration) => {
+                Some(BasicType::Timestamp)
+            }
+            (&BinaryOperator::Minus, &BasicType::Timestamp, &BasicType::Timestamp) |
+            (&BinaryOperator::Plus, &BasicType::Duration, &BasicType::Duration) |
+            (&BinaryOperator::Minus, &BasicType::Duration, &BasicType::Duration) |
+            (&BinaryOperator::Times, &BasicType::Duration, &BasicType::Int) |
+            (&BinaryOperator::Times, &BasicType::Int, &BasicType::Duration) |
+            (&BinaryOperator::Division, &BasicType::Duration, &BasicType::Int) => {
+                Some(BasicType::Duration)
+            }
+            _ => None,
+        }
+    }
+
+    fn get_type_integer(left: &BasicType, right: &BasicType) -> Option<BasicType> {
+        match (left, right) {
+            (&BasicType::Int, &BasicType::Int) => Some(BasicType::Int),
+            _ => None,
         }
     }
 
-    fn get_type_comparison(left: &BasicType, right: &BasicType) -> Result<BasicType, String> {
+    fn get_type_equality(left: &BasicType, right: &BasicType) -> Option<BasicType> {
+        if left == right { Some(BasicType::Bool) } else { None }
+    }
+
+    fn get_type_comparison(left: &BasicType, right: &BasicType) -> Option<BasicType> {
         match (left, right) {
             (&BasicType::Int, &BasicType::Int) |
             (&BasicType::Float, &BasicType::Float) |
-            (&BasicType::Str, &BasicType::Str) => Ok(BasicType::Bool),
-            _ => Err("Wrong operands types in comparison operation".to_owned()),
+            (&BasicType::Str, &BasicType::Str) |
+            (&BasicType::Timestamp, &BasicType::Timestamp) |
+            (&BasicType::Duration, &BasicType::Duration) => Some(BasicType::Bool),
+            _ => None,
+        }
+    }
+
+    fn get_type_pattern(left: &BasicType, right: &BasicType) -> Option<BasicType> {
+        match (left, right) {
+            (&BasicType::Str, &BasicType::Str) => Some(BasicType::Bool),
+            _ => None,
+        }
+    }
+
+    fn get_type_logical(left: &BasicType, right: &BasicType) -> Option<BasicType> {
+        match (left, right) {
+            (&BasicType::Bool, &BasicType::Bool) => Some(BasicType::Bool),
+            _ => None,
         }
     }
 
     pub fn get_type(operator: &BinaryOperator,
                     left: &BasicType,
                     right: &BasicType)
-                    -> Result<BasicType, String> {
+                    -> Result<BasicType, TypeError> {
+        let promoted = promote(operator, left, right);
+        let (lhs, rhs) = match promoted {
+            Some(ref ty) => (ty, ty),
+            None => (left, right),
+        };
         match *operator {
-            BinaryOperator::Plus | BinaryOperator::Minus | BinaryOperator::Times |
-            BinaryOperator::Division => get_type_arithmetic(left, right),
-            BinaryOperator::Equal | BinaryOperator::NotEqual => get_type_equality(left, right),
-            BinaryOperator::GreaterThan |
-            BinaryOperator::GreaterEqual |
-            BinaryOperator::LowerThan |
-            BinaryOperator::LowerEqual => get_type_comparison(left, right),
-        }
+                BinaryOperator::Plus => {
+                    get_type_arithmetic(lhs, rhs)
+                        .or_else(|| get_type_concatenation(lhs, rhs))
+                        .or_else(|| get_type_temporal(operator, lhs, rhs))
+                }
+                BinaryOperator::Minus | BinaryOperator::Times | BinaryOperator::Division => {
+                    get_type_arithmetic(lhs, rhs)
+                        .or_else(|| get_type_temporal(operator, lhs, rhs))
+                }
+                BinaryOperator::Remainder |
+                BinaryOperator::BitwiseAnd |
+                BinaryOperator::BitwiseOr |
+                BinaryOperator::BitwiseXor |
+                BinaryOperator::ShiftLeft |
+                BinaryOperator::ShiftRight => get_type_integer(lhs, rhs),
+                BinaryOperator::Equal | BinaryOperator::NotEqual => get_type_equality(lhs, rhs),
+                BinaryOperator::GreaterThan |
+                BinaryOperator::GreaterEqual |
+                BinaryOperator::LowerThan |
+                BinaryOperator::LowerEqual => get_type_comparison(lhs, rhs),
+                BinaryOperator::Matches | BinaryOperator::Like => get_type_pattern(lhs, rhs),
+                BinaryOperator::And | BinaryOperator::Or | BinaryOperator::Xor => {
+                    get_type_logical(lhs, rhs)
+                }
+            }
+            .ok_or_else(|| {
+                TypeError::BinaryOperands {
+                    operator: operator.clone(),
+                    left: left.clone(),
+                    right: right.clone(),
+                }
+            })
     }
 
-    fn evaluate_plus(left: &Value, right: &Value) -> Value {
+    fn evaluate_plus(left: &Value, right: &Value) -> Result<Value, EvaluationError> {
         match (left, right) {
-            (&Value::Int(lhs), &Value::Int(rhs)) => Value::Int(lhs + rhs),
-            (&Value::Float(lhs), &Value::Float(rhs)) => Value::Float(lhs + rhs),
-            (&Value::Str(ref lhs), &Value::Str(ref rhs)) => Value::Str(format!("{}{}", lhs, rhs)),
+            (&Value::Int(lhs), &Value::Int(rhs)) => {
+                lhs.checked_add(rhs).map(Value::Int).ok_or(EvaluationError::Overflow)
+            }
+            (&Value::Float(lhs), &Value::Float(rhs)) => Ok(Value::Float(lhs + rhs)),
+            (&Value::Str(ref lhs), &Value::Str(ref rhs)) => {
+                Ok(Value::Str(format!("{}{}", lhs, rhs)))
+            }
+            (&Value::Timestamp(lhs), &Value::Duration(rhs)) |
+            (&Value::Duration(rhs), &Value::Timestamp(lhs)) => {
+                lhs.checked_add(rhs).map(Value::Timestamp).ok_or(EvaluationError::Overflow)
+            }
+            (&Value::Duration(lhs), &Value::Duration(rhs)) => {
+                lhs.checked_add(&rhs).map(Value::Duration).ok_or(EvaluationError::Overflow)
+            }
             _ => panic!("Wrong use of plus operator"),
         }
     }
 
-    fn evaluate_minus(left: &Value, right: &Value) -> Value {
+    fn evaluate_minus(left: &Value, right: &Value) -> Result<Value, EvaluationError> {
         match (left, right) {
-            (&Value::Int(lhs), &Value::Int(rhs)) => Value::Int(lhs - rhs),
-            (&Value::Float(lhs), &Value::Float(rhs)) => Value::Float(lhs - rhs),
+            (&Value::Int(lhs), &Value::Int(rhs)) => {
+                lhs.checked_sub(rhs).map(Value::Int).ok_or(EvaluationError::Overflow)
+            }
+            (&Value::Float(lhs), &Value::Float(rhs)) => Ok(Value::Float(lhs - rhs)),
+            (&Value::Timestamp(lhs), &Value::Timestamp(rhs)) => Ok(Value::Duration(lhs - rhs)),
+            (&Value::Timestamp(lhs), &Value::Duration(rhs)) => {
+                lhs.checked_sub(rhs).map(Value::Timestamp).ok_or(EvaluationError::Overflow)
+            }
+            (&Value::Duration(lhs), &Value::Duration(rhs)) => {
+                lhs.checked_sub(&rhs).map(Value::Duration).ok_or(EvaluationError::Overflow)
+            }
             _ => panic!("Wrong use of minus operator"),
         }
     }
 
-    fn evaluate_times(left: &Value, right: &Value) -> Value {
+    fn evaluate_times(left: &Value, right: &Value) -> Result<Value, EvaluationError> {
         match (left, right) {
-            (&Value::Int(lhs), &Value::Int(rhs)) => Value::Int(lhs * rhs),
-            (&Value::Float(lhs), &Value::Float(rhs)) => Value::Float(lhs * rhs),
+            (&Value::Int(lhs), &Value::Int(rhs)) => {
+                lhs.checked_mul(rhs).map(Value::Int).ok_or(EvaluationError::Overflow)
+            }
+            (&Value::Float(lhs), &Value::Float(rhs)) => Ok(Value::Float(lhs * rhs)),
+            (&Value::Duration(lhs), &Value::Int(rhs)) |
+            (&Value::Int(rhs), &Value::Duration(lhs)) => {
+                // Durations are scaled at nanosecond precision, so the result can span
+                // up to about 292 years
+                lhs.num_nanoseconds()
+                    .and_then(|lhs| lhs.checked_mul(rhs))
+                    .map(|res| Value::Duration(Duration::nanoseconds(res)))
+                    .ok_or(EvaluationError::Overflow)
+            }
             _ => panic!("Wrong use of times operator"),
         }
     }
 
-    fn evaluate_division(left: &Value, right: &Value) -> Value {
+    fn evaluate_division(left: &Value, right: &Value) -> Result<Value, EvaluationError> {
         match (left, right) {
-            (&Value::Int(lhs), &Value::Int(rhs)) => Value::Int(lhs / rhs),
-            (&Value::Float(lhs), &Value::Float(rhs)) => Value::Float(lhs / rhs),
+            (&Value::Int(_), &Value::Int(0)) => Err(EvaluationError::DivisionByZero),
+            (&Value::Int(lhs), &Value::Int(rhs)) => {
+                lhs.checked_div(rhs).map(Value::Int).ok_or(EvaluationError::Overflow)
+            }
+            (&Value::Float(lhs), &Value::Float(rhs)) => Ok(Value::Float(lhs / rhs)),
+            (&Value::Duration(_), &Value::Int(0)) => Err(EvaluationError::DivisionByZero),
+            (&Value::Duration(lhs), &Value::Int(rhs)) => {
+                lhs.num_nanoseconds()
+                    .and_then(|lhs| lhs.checked_div(rhs))
+                    .map(|res| Value::Duration(Duration::nanoseconds(res)))
+                    .ok_or(EvaluationError::Overflow)
+            }
             _ => panic!("Wrong use of division operator"),
         }
     }
 
+    fn evaluate_remainder(left: &Value, right: &Value) -> Result<Value, EvaluationError> {
+        match (left, right) {
+            (&Value::Int(_), &Value::Int(0)) => Err(EvaluationError::DivisionByZero),
+            (&Value::Int(lhs), &Value::Int(rhs)) => {
+                lhs.checked_rem(rhs).map(Value::Int).ok_or(EvaluationError::Overflow)
+            }
+            _ => panic!("Wrong use of remainder operator"),
+        }
+    }
+
+    fn evaluate_bitwise(operator: &BinaryOperator,
+                        left: &Value,
+                        right: &Value)
+                        -> Result<Value, EvaluationError> {
+        let (lhs, rhs) = match (left, right) {
+            (&Value::Int(lhs), &Value::Int(rhs)) => (lhs, rhs),
+            _ => panic!("Wrong use of bitwise operator"),
+        };
+        // Negative or too wide shift amounts are rejected instead of being masked
+        let shift = || if 0 <= rhs && rhs < 64 {
+            Ok(rhs as u32)
+        } else {
+            Err(EvaluationError::InvalidShift)
+        };
+        match *operator {
+            BinaryOperator::BitwiseAnd => Ok(Value::Int(lhs & rhs)),
+            BinaryOperator::BitwiseOr => Ok(Value::Int(lhs | rhs)),
+            BinaryOperator::BitwiseXor => Ok(Value::Int(lhs ^ rhs)),
+            BinaryOperator::ShiftLeft => shift().map(|rhs| Value::Int(lhs << rhs)),
+            BinaryOperator::ShiftRight => shift().map(|rhs| Value::Int(lhs >> rhs)),
+            _ => panic!("Wrong use of bitwise operator"),
+        }
+    }
+
     fn evaluate_equal(left: &Value, right: &Value) -> Value {
         match (left, right) {
             (&Value::Int(lhs), &Value::Int(rhs)) => Value::Bool(lhs == rhs),
             (&Value::Float(lhs), &Value::Float(rhs)) => Value::Bool((lhs - rhs).abs() < EPSILON),
             (&Value::Bool(lhs), &Value::Bool(rhs)) => Value::Bool(lhs == rhs),
             (&Value::Str(ref lhs), &Value::Str(ref rhs)) => Value::Bool(lhs == rhs),
+            (&Value::Timestamp(lhs), &Value::Timestamp(rhs)) => Value::Bool(lhs == rhs),
+            (&Value::Duration(lhs), &Value::Duration(rhs)) => Value::Bool(lhs == rhs),
             _ => panic!("Wrong use of equal operator"),
         }
     }
@@ -139,6 +410,8 @@ pub mod binary {
             (&Value::Float(lhs), &Value::Float(rhs)) => Value::Bool((lhs - rhs).abs() >= EPSILON),
             (&Value::Bool(lhs), &Value::Bool(rhs)) => Value::Bool(lhs != rhs),
             (&Value::Str(ref lhs), &Value::Str(ref rhs)) => Value::Bool(lhs != rhs),
+            (&Value::Timestamp(lhs), &Value::Timestamp(rhs)) => Value::Bool(lhs != rhs),
+            (&Value::Duration(lhs), &Value::Duration(rhs)) => Value::Bool(lhs != rhs),
             _ => panic!("Wrong use of not_equal operator"),
         }
     }
@@ -148,6 +421,8 @@ pub mod binary {
             (&Value::Int(lhs), &Value::Int(rhs)) => Value::Bool(lhs > rhs),
             (&Value::Float(lhs), &Value::Float(rhs)) => Value::Bool(lhs - rhs >= EPSILON),
             (&Value::Str(ref lhs), &Value::Str(ref rhs)) => Value::Bool(lhs > rhs),
+            (&Value::Timestamp(lhs), &Value::Timestamp(rhs)) => Value::Bool(lhs > rhs),
+            (&Value::Duration(lhs), &Value::Duration(rhs)) => Value::Bool(lhs > rhs),
             _ => panic!("Wrong use of greater_than operator"),
         }
     }
@@ -157,6 +432,8 @@ pub mod binary {
             (&Value::Int(lhs), &Value::Int(rhs)) => Value::Bool(lhs >= rhs),
             (&Value::Float(lhs), &Value::Float(rhs)) => Value::Bool(lhs - rhs > -EPSILON),
             (&Value::Str(ref lhs), &Value::Str(ref rhs)) => Value::Bool(lhs >= rhs),
+            (&Value::Timestamp(lhs), &Value::Timestamp(rhs)) => Value::Bool(lhs >= rhs),
+            (&Value::Duration(lhs), &Value::Duration(rhs)) => Value::Bool(lhs >= rhs),
             _ => panic!("Wrong use of greater_equal operator"),
         }
     }
@@ -166,6 +443,8 @@ pub mod binary {
             (&Value::Int(lhs), &Value::Int(rhs)) => Value::Bool(lhs < rhs),
             (&Value::Float(lhs), &Value::Float(rhs)) => Value::Bool(rhs - lhs >= EPSILON),
             (&Value::Str(ref lhs), &Value::Str(ref rhs)) => Value::Bool(lhs < rhs),
+            (&Value::Timestamp(lhs), &Value::Timestamp(rhs)) => Value::Bool(lhs < rhs),
+            (&Value::Duration(lhs), &Value::Duration(rhs)) => Value::Bool(lhs < rhs),
             _ => panic!("Wrong use of lower_than operator"),
         }
     }
@@ -175,22 +454,192 @@ pub mod binary {
             (&Value::Int(lhs), &Value::Int(rhs)) => Value::Bool(lhs <= rhs),
             (&Value::Float(lhs), &Value::Float(rhs)) => Value::Bool(rhs - lhs > -EPSILON),
             (&Value::Str(ref lhs), &Value::Str(ref rhs)) => Value::Bool(lhs <= rhs),
+            (&Value::Timestamp(lhs), &Value::Timestamp(rhs)) => Value::Bool(lhs <= rhs),
+            (&Value::Duration(lhs), &Value::Duration(rhs)) => Value::Bool(lhs <= rhs),
             _ => panic!("Wrong use of lower_equal operator"),
         }
     }
 
-    pub fn evaluate(operator: &BinaryOperator, left: &Value, right: &Value) -> Value {
+    fn evaluate_and(left: &Value, right: &Value) -> Value {
+        match (left, right) {
+            (&Value::Bool(lhs), &Value::Bool(rhs)) => Value::Bool(lhs && rhs),
+            _ => panic!("Wrong use of and operator"),
+        }
+    }
+
+    fn evaluate_or(left: &Value, right: &Value) -> Value {
+        match (left, right) {
+            (&Value::Bool(lhs), &Value::Bool(rhs)) => Value::Bool(lhs || rhs),
+            _ => panic!("Wrong use of or operator"),
+        }
+    }
+
+    fn evaluate_xor(left: &Value, right: &Value) -> Value {
+        match (left, right) {
+            (&Value::Bool(lhs), &Value::Bool(rhs)) => Value::Bool(lhs != rhs),
+            _ => panic!("Wrong use of xor operator"),
+        }
+    }
+
+    pub fn evaluate(operator: &BinaryOperator,
+                    left: &Value,
+                    right: &Value)
+                    -> Result<Value, EvaluationError> {
+        // A null operand makes the result unknown, `EvaluationContext` takes care
+        // of the logical connectives that can be decided anyway
+        if left.is_null() || right.is_null() {
+            return Ok(Value::Null);
+        }
+        // Promotions never fail
+        let promoted = promote(operator, &left.get_type(), &right.get_type()).map(|ty| {
+            (cast::evaluate(left, &ty, &Rounding::Truncate).unwrap(),
+             cast::evaluate(right, &ty, &Rounding::Truncate).unwrap())
+        });
+        let (left, right) = match promoted {
+            Some((ref left, ref right)) => (left, right),
+            None => (left, right),
+        };
         match *operator {
             BinaryOperator::Plus => evaluate_plus(left, right),
             BinaryOperator::Minus => evaluate_minus(left, right),
             BinaryOperator::Times => evaluate_times(left, right),
             BinaryOperator::Division => evaluate_division(left, right),
-            BinaryOperator::Equal => evaluate_equal(left, right),
-            BinaryOperator::NotEqual => evaluate_not_equal(left, right),
-            BinaryOperator::GreaterThan => evaluate_greater_than(left, right),
-            BinaryOperator::GreaterEqual => evaluate_greater_equal(left, right),
-            BinaryOperator::LowerThan => evaluate_lower_than(left, right),
-            BinaryOperator::LowerEqual => evaluate_lower_equal(left, right),
+            BinaryOperator::Remainder => evaluate_remainder(left, right),
+            BinaryOperator::BitwiseAnd |
+            BinaryOperator::BitwiseOr |
+            BinaryOperator::BitwiseXor |
+            BinaryOperator::ShiftLeft |
+            BinaryOperator::ShiftRight => evaluate_bitwise(operator, left, right),
+            BinaryOperator::Equal => Ok(evaluate_equal(left, right)),
+            BinaryOperator::NotEqual => Ok(evaluate_not_equal(left, right)),
+            BinaryOperator::GreaterThan => Ok(evaluate_greater_than(left, right)),
+            BinaryOperator::GreaterEqual => Ok(evaluate_greater_equal(left, right)),
+            BinaryOperator::LowerThan => Ok(evaluate_lower_than(left, right)),
+            BinaryOperator::LowerEqual => Ok(evaluate_lower_equal(left, right)),
+            BinaryOperator::And => Ok(evaluate_and(left, right)),
+            BinaryOperator::Or => Ok(evaluate_or(left, right)),
+            BinaryOperator::Xor => Ok(evaluate_xor(left, right)),
+            // Patterns are compiled beforehand, so `EvaluationContext` matches them
+            BinaryOperator::Matches | BinaryOperator::Like => {
+                panic!("Wrong use of pattern operator")
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, UTC};
+    use errors::EvaluationError;
+    use super::{binary, cast, unary};
+    use tesla::expressions::{BasicType, BinaryOperator, Rounding, UnaryOperator, Value};
+
+    #[test]
+    fn integer_errors() {
+        let eval = |op, l, r| binary::evaluate(&op, &Value::Int(l), &Value::Int(r));
+        assert_eq!(eval(BinaryOperator::Division, 7, 2), Ok(Value::Int(3)));
+        assert_eq!(eval(BinaryOperator::Remainder, -7, 2), Ok(Value::Int(-1)));
+        assert_eq!(eval(BinaryOperator::Division, 1, 0), Err(EvaluationError::DivisionByZero));
+        assert_eq!(eval(BinaryOperator::Remainder, 1, 0), Err(EvaluationError::DivisionByZero));
+        assert_eq!(eval(BinaryOperator::Division, i64::min_value(), -1),
+                   Err(EvaluationError::Overflow));
+        assert_eq!(eval(BinaryOperator::BitwiseXor, 6, 3), Ok(Value::Int(5)));
+        assert_eq!(eval(BinaryOperator::ShiftLeft, 1, 4), Ok(Value::Int(16)));
+        assert_eq!(eval(BinaryOperator::ShiftRight, 1, 64), Err(EvaluationError::InvalidShift));
+    }
+
+    #[test]
+    fn temporal_arithmetic() {
+        let start = UTC.ymd(2016, 5, 1).and_hms(12, 0, 0);
+        let end = UTC.ymd(2016, 5, 1).and_hms(12, 0, 30);
+        let eval = |op, l: Value, r: Value| binary::evaluate(&op, &l, &r);
+        assert_eq!(eval(BinaryOperator::Minus, end.into(), start.into()),
+                   Ok(Value::Duration(Duration::seconds(30))));
+        assert_eq!(eval(BinaryOperator::Plus, Duration::seconds(30).into(), start.into()),
+                   Ok(Value::Timestamp(end)));
+        assert_eq!(eval(BinaryOperator::Times, Duration::seconds(10).into(), Value::Int(3)),
+                   Ok(Value::Duration(Duration::seconds(30))));
+        assert_eq!(eval(BinaryOperator::Division, Duration::seconds(1).into(), Value::Int(0)),
+                   Err(EvaluationError::DivisionByZero));
+        assert_eq!(eval(BinaryOperator::Times, Duration::weeks(1).into(), Value::Int(1 << 40)),
+                   Err(EvaluationError::Overflow));
+        assert_eq!(eval(BinaryOperator::LowerThan, start.into(), end.into()),
+                   Ok(Value::Bool(true)));
+        assert_eq!(unary::evaluate(&UnaryOperator::Minus, &Duration::seconds(1).into()),
+                   Ok(Value::Duration(Duration::seconds(-1))));
+
+        let ty = |op, l, r| binary::get_type(&op, &l, &r).ok();
+        assert_eq!(ty(BinaryOperator::Minus, BasicType::Timestamp, BasicType::Timestamp),
+                   Some(BasicType::Duration));
+        assert_eq!(ty(BinaryOperator::Minus, BasicType::Duration, BasicType::Timestamp), None);
+        assert_eq!(ty(BinaryOperator::Plus, BasicType::Timestamp, BasicType::Timestamp), None);
+    }
+
+    #[test]
+    fn null_propagation() {
+        let null = Value::Null;
+        let eval = |op, l: &Value, r: &Value| binary::evaluate(&op, l, r);
+        assert_eq!(eval(BinaryOperator::Plus, &Value::Int(1), &null), Ok(Value::Null));
+        assert_eq!(eval(BinaryOperator::Division, &null, &Value::Int(0)), Ok(Value::Null));
+        assert_eq!(eval(BinaryOperator::Equal, &null, &null), Ok(Value::Null));
+        assert_eq!(unary::evaluate(&UnaryOperator::Not, &null), Ok(Value::Null));
+        assert_eq!(unary::evaluate(&UnaryOperator::IsNull, &null), Ok(Value::Bool(true)));
+        assert_eq!(unary::evaluate(&UnaryOperator::IsNotNull, &Value::Int(0)),
+                   Ok(Value::Bool(true)));
+        assert_eq!(unary::get_type(&UnaryOperator::IsNull, &BasicType::Str).ok(),
+                   Some(BasicType::Bool));
+    }
+
+    #[test]
+    fn promotion() {
+        let ty = |op, l, r| binary::get_type(&op, &l, &r).ok();
+        assert_eq!(ty(BinaryOperator::Plus, BasicType::Int, BasicType::Float),
+                   Some(BasicType::Float));
+        assert_eq!(ty(BinaryOperator::Times, BasicType::Bool, BasicType::Int),
+                   Some(BasicType::Int));
+        assert_eq!(ty(BinaryOperator::LowerThan, BasicType::Float, BasicType::Int),
+                   Some(BasicType::Bool));
+        assert_eq!(ty(BinaryOperator::Plus, BasicType::Str, BasicType::Float),
+                   Some(BasicType::Str));
+        assert_eq!(ty(BinaryOperator::Minus, BasicType::Str, BasicType::Int), None);
+        assert_eq!(ty(BinaryOperator::Equal, BasicType::Str, BasicType::Int), None);
+        assert_eq!(ty(BinaryOperator::Times, BasicType::Duration, BasicType::Float), None);
+
+        let eval = |op, l: Value, r: Value| binary::evaluate(&op, &l, &r);
+        assert_eq!(eval(BinaryOperator::Division, Value::Int(3), Value::Float(2.0)),
+                   Ok(Value::Float(1.5)));
+        assert_eq!(eval(BinaryOperator::Plus, Value::Bool(true), Value::Int(1)),
+                   Ok(Value::Int(2)));
+        assert_eq!(eval(BinaryOperator::Equal, Value::Int(1), Value::Float(1.0)),
+                   Ok(Value::Bool(true)));
+        assert_eq!(eval(BinaryOperator::Plus, Value::Str("x = ".to_owned()), Value::Float(2.0)),
+                   Ok(Value::Str("x = 2.0".to_owned())));
+        assert_eq!(eval(BinaryOperator::Plus, Value::Bool(false), Value::Str("!".to_owned())),
+                   Ok(Value::Str("false!".to_owned())));
+    }
+
+    #[test]
+    fn casts() {
+        let eval = |value: Value, ty, rounding| cast::evaluate(&value, &ty, &rounding);
+        assert_eq!(eval(Value::Float(-2.5), BasicType::Int, Rounding::Truncate),
+                   Ok(Value::Int(-2)));
+        assert_eq!(eval(Value::Float(-2.5), BasicType::Int, Rounding::Round),
+                   Ok(Value::Int(-3)));
+        assert_eq!(eval(Value::Float(1e19), BasicType::Int, Rounding::Truncate),
+                   Err(EvaluationError::Overflow));
+        assert_eq!(eval(Value::Str("-12".to_owned()), BasicType::Int, Rounding::Truncate),
+                   Ok(Value::Int(-12)));
+        assert_eq!(eval(Value::Str("1.5e3".to_owned()), BasicType::Float, Rounding::Truncate),
+                   Ok(Value::Float(1500.0)));
+        assert_eq!(eval(Value::Str("12a".to_owned()), BasicType::Int, Rounding::Truncate),
+                   Err(EvaluationError::InvalidArgument));
+        assert_eq!(eval(Value::Float(3.0), BasicType::Str, Rounding::Truncate),
+                   Ok(Value::Str("3.0".to_owned())));
+        assert_eq!(eval(Value::Int(42), BasicType::Str, Rounding::Truncate),
+                   Ok(Value::Str("42".to_owned())));
+        assert_eq!(eval(Value::Null, BasicType::Str, Rounding::Truncate), Ok(Value::Null));
+
+        assert!(cast::get_type(&BasicType::Str, &BasicType::Bool).is_err());
+        assert!(cast::get_type(&BasicType::Duration, &BasicType::Int).is_err());
+    }
+}
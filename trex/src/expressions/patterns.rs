@@ -0,0 +1,182 @@
+//! Pattern matching operators on strings.
+//!
+//! Patterns must be string literals, so that they can be compiled
+//! just once, when the rule is defined.
+
+use FnvHashMap;
+use errors::TypeError;
+use regex::Regex;
+use tesla::Rule;
+use tesla::expressions::{BinaryOperator, Expression, Value};
+use tesla::predicates::PredicateType;
+
+pub fn is_pattern_operator(operator: &BinaryOperator) -> bool {
+    match *operator {
+        BinaryOperator::Matches | BinaryOperator::Like => true,
+        _ => false,
+    }
+}
+
+fn push_literal(res: &mut String, c: char) {
+    if "\\.+*?()|[]{}^$#".contains(c) {
+        res.push('\\');
+    }
+    res.push(c);
+}
+
+/// Translates a `Like` pattern to an anchored regular expression.
+fn like_to_regex(pattern: &str) -> Result<String, String> {
+    let mut res = "(?s)^".to_owned();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => res.push_str(".*"),
+            '_' => res.push('.'),
+            '\\' => {
+                match chars.next() {
+                    Some(c) => push_literal(&mut res, c),
+                    None => return Err("the pattern ends with an escape character".to_owned()),
+                }
+            }
+            c => push_literal(&mut res, c),
+        }
+    }
+    res.push('$');
+    Ok(res)
+}
+
+fn compile(operator: &BinaryOperator, pattern: &str) -> Result<Regex, TypeError> {
+    let source = match *operator {
+        BinaryOperator::Like => like_to_regex(pattern),
+        _ => Ok(pattern.to_owned()),
+    };
+    source.and_then(|source| Regex::new(&source).map_err(|err| err.to_string()))
+        .map_err(|reason| {
+            TypeError::InvalidPattern {
+                pattern: pattern.to_owned(),
+                reason: reason,
+            }
+        })
+}
+
+/// Retrieves the pattern operand of a pattern matching operator.
+pub fn get_literal(expression: &Expression) -> Result<&str, TypeError> {
+    match *expression {
+        Expression::Immediate { value: Value::Str(ref pattern) } => Ok(pattern),
+        _ => Err(TypeError::DynamicPattern),
+    }
+}
+
+/// Checks that the pattern operand is a literal that can be compiled.
+pub fn check(operator: &BinaryOperator, expression: &Expression) -> Result<(), TypeError> {
+    get_literal(expression).and_then(|pattern| compile(operator, pattern)).map(|_| ())
+}
+
+/// Compiled patterns used by a set of expressions.
+#[derive(Clone, Debug, Default)]
+pub struct Patterns {
+    matches: FnvHashMap<String, Regex>,
+    like: FnvHashMap<String, Regex>,
+}
+
+impl Patterns {
+    pub fn from_rule(rule: &Rule) -> Self {
+        let mut patterns = Patterns::default();
+        for pred in &rule.predicates {
+            match pred.ty {
+                PredicateType::Trigger { ref parameters } |
+                PredicateType::Event { ref parameters, .. } |
+                PredicateType::OrderedStatic { ref parameters, .. } |
+                PredicateType::UnorderedStatic { ref parameters } => {
+                    for param in parameters {
+                        patterns.add_expression(&param.expression);
+                    }
+                }
+                PredicateType::EventAggregate { ref parameter, .. } |
+                PredicateType::StaticAggregate { ref parameter, .. } => {
+                    patterns.add_expression(&parameter.expression);
+                }
+                _ => (),
+            }
+            for expr in &pred.tuple.constraints {
+                patterns.add_expression(expr);
+            }
+        }
+        for expr in rule.filters.iter().chain(&rule.event_template.attributes) {
+            patterns.add_expression(expr);
+        }
+        patterns
+    }
+
+    /// Compiles the patterns found in the expression, the invalid ones are skipped.
+    pub fn add_expression(&mut self, expression: &Expression) {
+        match *expression {
+            Expression::Cast { ref expression, .. } |
+            Expression::UnaryOperation { ref expression, .. } => self.add_expression(expression),
+            Expression::BinaryOperation { ref operator, ref left, ref right } => {
+                if is_pattern_operator(operator) {
+                    if let Ok(pattern) = get_literal(right) {
+                        let map = match *operator {
+                            BinaryOperator::Like => &mut self.like,
+                            _ => &mut self.matches,
+                        };
+                        if !map.contains_key(pattern) {
+                            if let Ok(regex) = compile(operator, pattern) {
+                                map.insert(pattern.to_owned(), regex);
+                            }
+                        }
+                    }
+                }
+                self.add_expression(left);
+                self.add_expression(right);
+            }
+            Expression::Function { ref args, .. } => {
+                for arg in args {
+                    self.add_expression(arg);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    pub fn get(&self, operator: &BinaryOperator, pattern: &str) -> Option<&Regex> {
+        match *operator {
+            BinaryOperator::Like => self.like.get(pattern),
+            _ => self.matches.get(pattern),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Patterns, check};
+    use tesla::expressions::{BinaryOperator, Expression, Value};
+
+    fn pattern(operator: BinaryOperator, value: &str, pattern: &str) -> Expression {
+        Expression::BinaryOperation {
+            operator: operator,
+            left: Box::new(Expression::Immediate { value: Value::Str(value.to_owned()) }),
+            right: Box::new(Expression::Immediate { value: Value::Str(pattern.to_owned()) }),
+        }
+    }
+
+    #[test]
+    fn like_patterns() {
+        let mut patterns = Patterns::default();
+        patterns.add_expression(&pattern(BinaryOperator::Like, "", "n_rth%"));
+        patterns.add_expression(&pattern(BinaryOperator::Like, "", "100\\%.*"));
+        let north = patterns.get(&BinaryOperator::Like, "n_rth%").unwrap();
+        assert!(north.is_match("north-east"));
+        assert!(!north.is_match("the north"));
+        assert!(!north.is_match("North"));
+        let percent = patterns.get(&BinaryOperator::Like, "100\\%.*").unwrap();
+        assert!(percent.is_match("100%.*"));
+        assert!(!percent.is_match("1000.x"));
+        assert!(patterns.get(&BinaryOperator::Matches, "n_rth%").is_none());
+
+        let literal = Expression::Immediate { value: Value::Str("50\\".to_owned()) };
+        assert!(check(&BinaryOperator::Like, &literal).is_err());
+        assert!(check(&BinaryOperator::Matches, &literal).is_err());
+        assert!(check(&BinaryOperator::Matches, &Expression::Aggregate).is_err());
+    }
+}
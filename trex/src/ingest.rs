@@ -0,0 +1,321 @@
+use TRex;
+use errors::{Error, IngestError};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use tesla::{Engine, Event};
+
+/// What to do when an event is published to a full ingest queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Wait for the dispatcher to make room.
+    Block,
+    /// Discard the published event.
+    DropNewest,
+    /// Discard the oldest queued event.
+    DropOldest,
+    /// Fail with `IngestError::Full`.
+    Error,
+}
+
+#[derive(Clone, Debug)]
+pub struct IngestConfig {
+    /// Maximum number of events waiting for the dispatcher, zero is taken as one.
+    pub capacity: usize,
+    pub backpressure: Backpressure,
+}
+
+impl Default for IngestConfig {
+    fn default() -> Self {
+        IngestConfig {
+            capacity: 1024,
+            backpressure: Backpressure::Block,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    events: VecDeque<Event>,
+    /// Number of live handles, the queue is closed when it gets to zero
+    handles: usize,
+    /// Whether the dispatcher has stopped, even by panicking
+    disconnected: bool,
+    dropped: usize,
+}
+
+#[derive(Debug)]
+struct Queue {
+    config: IngestConfig,
+    state: Mutex<State>,
+    /// Signalled when an event is pushed or the queue is closed
+    not_empty: Condvar,
+    /// Signalled when an event is popped or the dispatcher stops
+    not_full: Condvar,
+}
+
+impl Queue {
+    fn new(config: IngestConfig) -> Self {
+        // An empty queue would block or discard every event
+        let capacity = config.capacity.max(1);
+        Queue {
+            config: IngestConfig { capacity: capacity, ..config },
+            state: Mutex::new(State::default()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        // The state is consistent after every operation, even if a thread panicked holding it
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn push(&self, event: Event) -> Result<(), IngestError> {
+        let mut state = self.lock();
+        if state.disconnected {
+            return Err(IngestError::Disconnected);
+        }
+        if state.events.len() >= self.config.capacity {
+            match self.config.backpressure {
+                Backpressure::Block => {
+                    while state.events.len() >= self.config.capacity && !state.disconnected {
+                        state = self.not_full.wait(state).unwrap_or_else(|err| err.into_inner());
+                    }
+                    // The dispatcher may have stopped while waiting
+                    if state.disconnected {
+                        return Err(IngestError::Disconnected);
+                    }
+                }
+                Backpressure::DropNewest => {
+                    state.dropped += 1;
+                    return Ok(());
+                }
+                Backpressure::DropOldest => {
+                    state.events.pop_front();
+                    state.dropped += 1;
+                }
+                Backpressure::Error => return Err(IngestError::Full),
+            }
+        }
+        state.events.push_back(event);
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    /// Takes the oldest event, waiting for one unless all the handles have been dropped.
+    fn pop(&self) -> Option<Event> {
+        let mut state = self.lock();
+        loop {
+            if let Some(event) = state.events.pop_front() {
+                self.not_full.notify_one();
+                return Some(event);
+            } else if state.handles == 0 {
+                return None;
+            }
+            state = self.not_empty.wait(state).unwrap_or_else(|err| err.into_inner());
+        }
+    }
+}
+
+/// Marks the queue as disconnected when the dispatcher stops, unblocking the publishers.
+struct Disconnect(Arc<Queue>);
+
+impl Drop for Disconnect {
+    fn drop(&mut self) {
+        self.0.lock().disconnected = true;
+        self.0.not_full.notify_all();
+    }
+}
+
+/// Handle to publish events to an engine running on a dispatcher thread.
+///
+/// Publishing only waits for a place in the bounded queue (depending on the backpressure
+/// policy), the events are processed by the dispatcher in the order they are queued.
+/// The dispatcher stops when all the handles have been dropped and the queue is empty.
+#[derive(Debug)]
+pub struct Ingest {
+    queue: Arc<Queue>,
+}
+
+impl Ingest {
+    pub fn publish(&self, event: Event) -> Result<(), IngestError> { self.queue.push(event) }
+
+    /// Number of events discarded so far by the backpressure policy.
+    pub fn dropped(&self) -> usize { self.queue.lock().dropped }
+}
+
+impl Clone for Ingest {
+    fn clone(&self) -> Self {
+        self.queue.lock().handles += 1;
+        Ingest { queue: self.queue.clone() }
+    }
+}
+
+impl Drop for Ingest {
+    fn drop(&mut self) {
+        let closed = {
+            let mut state = self.queue.lock();
+            state.handles -= 1;
+            state.handles == 0
+        };
+        if closed {
+            self.queue.not_empty.notify_all();
+        }
+    }
+}
+
+/// Moves the engine to a dispatcher thread, that gives it back once all the handles have been
+/// dropped and the held back events flushed, along with the errors of the events whose
+/// processing failed.
+pub fn spawn(mut engine: TRex, config: IngestConfig) -> (Ingest, JoinHandle<(TRex, Vec<Error>)>) {
+    let queue = Arc::new(Queue::new(config));
+    queue.lock().handles = 1;
+    let disconnect = Disconnect(queue.clone());
+    let dispatcher = thread::spawn(move || {
+        let mut errors = Vec::new();
+        while let Some(event) = disconnect.0.pop() {
+            if let Err(err) = engine.publish(event) {
+                errors.push(err);
+            }
+        }
+        if let Err(err) = engine.flush() {
+            errors.push(err);
+        }
+        (engine, errors)
+    });
+    (Ingest { queue: queue }, dispatcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use {EngineConfig, TRex};
+    use chrono::{Duration, TimeZone, UTC};
+    use errors::IngestError;
+    use std::sync::{Arc, Mutex};
+    use super::{Backpressure, IngestConfig, Queue};
+    use tesla::{Engine, Event, Listener, SubscrFilter, Tuple};
+    use tesla::expressions::Value;
+
+    fn event(value: i64) -> Event {
+        Event {
+            tuple: Tuple {
+                ty_id: 0,
+                data: vec![value.into()],
+            },
+            time: UTC.timestamp(value, 0),
+            seq: 0,
+        }
+    }
+
+    struct Collector(Arc<Mutex<Vec<Value>>>);
+
+    impl Listener for Collector {
+        fn receive(&mut self, event: &Arc<Event>) {
+            self.0.lock().unwrap().push(event.tuple.data[0].clone());
+        }
+    }
+
+    struct Failure;
+
+    impl Listener for Failure {
+        fn receive(&mut self, _: &Arc<Event>) { panic!("listener failure") }
+    }
+
+    fn queued(queue: &Queue) -> Vec<Value> {
+        queue.lock().events.iter().map(|evt| evt.tuple.data[0].clone()).collect()
+    }
+
+    #[test]
+    fn backpressure() {
+        let config = |backpressure| {
+            IngestConfig {
+                capacity: 2,
+                backpressure: backpressure,
+            }
+        };
+
+        let queue = Queue::new(config(Backpressure::DropNewest));
+        for i in 0..3 {
+            assert!(queue.push(event(i)).is_ok());
+        }
+        assert_eq!(queued(&queue), vec![Value::Int(0), Value::Int(1)]);
+        assert_eq!(queue.lock().dropped, 1);
+
+        let queue = Queue::new(config(Backpressure::DropOldest));
+        for i in 0..3 {
+            assert!(queue.push(event(i)).is_ok());
+        }
+        assert_eq!(queued(&queue), vec![Value::Int(1), Value::Int(2)]);
+        assert_eq!(queue.lock().dropped, 1);
+
+        let queue = Queue::new(config(Backpressure::Error));
+        assert!(queue.push(event(0)).is_ok());
+        assert!(queue.push(event(1)).is_ok());
+        assert_eq!(queue.push(event(2)), Err(IngestError::Full));
+
+        // The pending events are still dispatched after the handles are gone
+        assert_eq!(queue.pop().map(|evt| evt.tuple.data[0].clone()), Some(Value::Int(0)));
+        assert_eq!(queue.pop().map(|evt| evt.tuple.data[0].clone()), Some(Value::Int(1)));
+        assert!(queue.pop().is_none());
+
+        let queue = Queue::new(config(Backpressure::Block));
+        queue.lock().disconnected = true;
+        assert_eq!(queue.push(event(0)), Err(IngestError::Disconnected));
+
+        // Nothing is discarded once the dispatcher has stopped
+        let queue = Queue::new(config(Backpressure::DropOldest));
+        assert!(queue.push(event(0)).is_ok());
+        assert!(queue.push(event(1)).is_ok());
+        queue.lock().disconnected = true;
+        assert_eq!(queue.push(event(2)), Err(IngestError::Disconnected));
+        assert_eq!(queued(&queue), vec![Value::Int(0), Value::Int(1)]);
+        assert_eq!(queue.lock().dropped, 0);
+
+        let queue = Queue::new(IngestConfig {
+            capacity: 0,
+            backpressure: Backpressure::Error,
+        });
+        assert!(queue.push(event(0)).is_ok());
+        assert_eq!(queue.push(event(1)), Err(IngestError::Full));
+    }
+
+    #[test]
+    fn dispatcher() {
+        let config = EngineConfig {
+            allowed_lateness: Some(Duration::seconds(10)),
+            ..EngineConfig::default()
+        };
+        let mut engine = TRex::with_config(1, Vec::new(), config);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        engine.subscribe(SubscrFilter::Any, Box::new(Collector(received.clone())));
+        let (ingest, dispatcher) = engine.spawn(IngestConfig::default());
+        let other = ingest.clone();
+        ingest.publish(event(1)).unwrap();
+        other.publish(event(2)).unwrap();
+        drop(ingest);
+        other.publish(event(3)).unwrap();
+        drop(other);
+
+        // The dispatcher stops with the last handle, after flushing the held back events
+        let (engine, errors) = dispatcher.join().unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(*received.lock().unwrap(), vec![Value::Int(1), Value::Int(2), Value::Int(3)]);
+        assert_eq!(engine.watermark(), Some(UTC.timestamp(3, 0)));
+    }
+
+    #[test]
+    fn dispatcher_panic() {
+        let mut engine = TRex::new(1, Vec::new());
+        engine.subscribe(SubscrFilter::Any, Box::new(Failure));
+        let config = IngestConfig {
+            capacity: 1,
+            backpressure: Backpressure::Block,
+        };
+        let (ingest, dispatcher) = engine.spawn(config);
+        // The publishers waiting for room are released when the dispatcher panics
+        let result = (0..).map(|i| ingest.publish(event(i))).find(|res| res.is_err());
+        assert_eq!(result, Some(Err(IngestError::Disconnected)));
+        assert!(dispatcher.join().is_err());
+    }
+}
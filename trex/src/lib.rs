@@ -8,42 +8,58 @@ extern crate fnv;
 extern crate lru_cache;
 extern crate lru_size_cache;
 extern crate owning_ref;
+extern crate regex;
 extern crate tesla;
 
+pub mod errors;
 mod expressions;
 pub mod stack;
 mod rule_processor;
 mod aggregators;
 pub mod sqlite;
 mod rule_checks;
+mod sketch;
 mod cache;
 pub mod listeners;
+pub mod ingest;
 
+use chrono::{DateTime, Duration, UTC};
+use errors::Error;
+use ingest::{Ingest, IngestConfig};
 use expressions::evaluation::{EvaluationContext, SimpleContext};
+use expressions::functions::is_builtin;
+use expressions::patterns::Patterns;
 use fnv::FnvHasher;
 use linear_map::LinearMap;
-use rule_checks::check_rule;
+use rule_checks::{check_rule, find_path};
 use rule_processor::*;
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::collections::hash_map::Entry;
 use std::hash::BuildHasherDefault;
+use std::mem;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread::JoinHandle;
 use tesla::{Engine, Event, Listener, Rule, SubscrFilter, TupleDeclaration};
 use tesla::expressions::BasicType;
 use tesla::predicates::Predicate;
 use threadpool::ThreadPool;
 
+pub use expressions::evaluation::Environment;
+pub use expressions::functions::{Functions, UserFunction};
+
 pub type FnvHashMap<K, V> = HashMap<K, V, BuildHasherDefault<FnvHasher>>;
 
-pub trait NodeProvider {
+pub trait NodeProvider: Send {
+    /// Builds the processor of a predicate, `None` if the provider cannot evaluate it.
     fn provide(&self,
                idx: usize,
                tuple: &TupleDeclaration,
                predicate: &Predicate,
-               parameters_ty: &LinearMap<(usize, usize), BasicType>)
-               -> Option<Box<EventProcessor>>;
+               parameters_ty: &LinearMap<(usize, usize), BasicType>,
+               env: &Arc<Environment>)
+               -> Result<Option<Box<EventProcessor>>, Error>;
 }
 
 struct GeneralProvider {
@@ -62,83 +78,238 @@ impl GeneralProvider {
     fn provide(&self,
                rule: Rule,
                tuples: &FnvHashMap<usize, TupleDeclaration>,
-               parameters_ty: &LinearMap<(usize, usize), BasicType>)
-               -> RuleStacks {
-        let trigger = Trigger::new(&rule.predicates[0]);
+               parameters_ty: &LinearMap<(usize, usize), BasicType>,
+               env: &Arc<Environment>)
+               -> Result<RuleStacks, Error> {
+        let trigger = Trigger::new(&rule.predicates[0], env.clone());
         let processors = rule.predicates
             .iter()
             .enumerate()
             .skip(1)
             .map(|(i, predicate)| {
                 let tuple = &tuples[&predicate.tuple.ty_id];
-                let processor = self.providers
-                    .iter()
-                    .filter_map(|provider| provider.provide(i, tuple, predicate, parameters_ty))
-                    .next()
-                    .expect("No suitable processor");
-                (i, processor)
+                for provider in &self.providers {
+                    if let Some(processor) =
+                        provider.provide(i, tuple, predicate, parameters_ty, env)? {
+                        return Ok((i, processor));
+                    }
+                }
+                Err(Error::NoProvider { predicate: i })
             })
-            .collect();
-        RuleStacks::new(trigger, processors, rule)
+            .collect::<Result<_, _>>()?;
+        Ok(RuleStacks::new(trigger, processors, rule, env.clone()))
+    }
+}
+
+/// Treatment of the events published when the watermark has already passed their time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LatePolicy {
+    /// Discard them.
+    Drop,
+    /// Set them aside, to be collected with `TRex::take_late_events`.
+    SideOutput,
+    /// Insert them in chronological order and evaluate the rules they trigger,
+    /// but not again the ones triggered by the events already processed.
+    Reevaluate,
+}
+
+/// Tunables of the engine behaviour.
+#[derive(Clone, Debug)]
+pub struct EngineConfig {
+    /// Maximum length of a chain of derived events originated by a published event,
+    /// the events beyond it are discarded and make `publish` fail once all the others
+    /// have been processed.
+    pub max_depth: usize,
+    /// Whether to accept rules whose output can (indirectly) trigger themselves.
+    pub allow_cycles: bool,
+    /// How much older than the latest published event an event can be and still be processed
+    /// in chronological order, events are held back until the watermark (the latest time
+    /// minus the lateness) passes them. If `None` they are processed as soon as they are
+    /// published, assuming they arrive in chronological order.
+    pub allowed_lateness: Option<Duration>,
+    /// Treatment of the events older than the watermark.
+    pub late_events: LatePolicy,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            max_depth: 64,
+            allow_cycles: false,
+            allowed_lateness: None,
+            late_events: LatePolicy::Drop,
+        }
     }
 }
 
+/// Identity of the stacks of a rule.
+fn stacks_key(stacks: &Arc<Mutex<RuleStacks>>) -> usize {
+    &**stacks as *const Mutex<RuleStacks> as usize
+}
+
+struct RuleEntry {
+    pred_ty_ids: Vec<usize>,
+    // Trigger and template types, the edge of the rules dependency graph
+    dependency: (usize, usize),
+    stacks: Arc<Mutex<RuleStacks>>,
+}
+
 pub struct TRex {
     tuples: FnvHashMap<usize, TupleDeclaration>,
+    functions: Arc<Functions>,
     provider: GeneralProvider,
+    config: EngineConfig,
+    rules: FnvHashMap<usize, RuleEntry>,
     reverse_index: FnvHashMap<usize, Vec<Arc<Mutex<RuleStacks>>>>,
-    listeners: BTreeMap<usize, (SubscrFilter, Box<Listener>)>,
+    listeners: BTreeMap<usize, (SubscrFilter, Box<Listener>, Environment)>,
     last_id: usize,
+    last_rule_id: usize,
+    last_seq: usize,
     threadpool: ThreadPool,
-    channel: (Sender<Vec<Arc<Event>>>, Receiver<Vec<Arc<Event>>>),
+    channel: (Sender<Vec<Event>>, Receiver<Vec<Event>>),
+    /// Events held back until the watermark passes them, by time and arrival
+    pending: BTreeMap<(DateTime<UTC>, usize), Event>,
+    arrivals: usize,
+    watermark: Option<DateTime<UTC>>,
+    late: Vec<Event>,
 }
 
 impl TRex {
     pub fn new(threads: usize, providers: Vec<Box<NodeProvider>>) -> TRex {
+        TRex::with_config(threads, providers, EngineConfig::default())
+    }
+
+    pub fn with_config(threads: usize,
+                       providers: Vec<Box<NodeProvider>>,
+                       config: EngineConfig)
+                       -> TRex {
         TRex {
             tuples: FnvHashMap::default(),
+            functions: Arc::new(Functions::default()),
             provider: GeneralProvider::with_providers(providers),
+            config: config,
+            rules: FnvHashMap::default(),
             reverse_index: FnvHashMap::default(),
             listeners: BTreeMap::new(),
             last_id: 0,
+            last_rule_id: 0,
+            last_seq: 0,
             threadpool: ThreadPool::new(threads),
             channel: channel(),
+            pending: BTreeMap::new(),
+            arrivals: 0,
+            watermark: None,
+            late: Vec::new(),
         }
     }
-}
 
-impl Engine for TRex {
-    fn declare(&mut self, tuple: TupleDeclaration) {
-        if let Entry::Vacant(entry) = self.tuples.entry(tuple.id) {
-            entry.insert(tuple);
-        } else {
-            panic!("Tuple already declared!");
+    /// Checks the rule and builds its stacks, `replaced` is the id of the rule
+    /// it is going to replace (if any) that is ignored by the cycle detection.
+    fn build_rule(&self, rule: Rule, replaced: Option<usize>) -> Result<RuleEntry, Error> {
+        let param_types = check_rule(&rule, &self.tuples, &self.functions)?;
+
+        let dependency = (rule.predicates[0].tuple.ty_id, rule.event_template.ty_id);
+        if !self.config.allow_cycles {
+            let dependencies = self.rules
+                .iter()
+                .filter(|&(&id, _)| Some(id) != replaced)
+                .map(|(_, entry)| entry.dependency);
+            let path = find_path(dependencies, dependency.1, dependency.0);
+            if let Some(path) = path {
+                let mut cycle = vec![dependency.0];
+                cycle.extend(path);
+                return Err(Error::RecursiveRule { cycle: cycle });
+            }
         }
-    }
-    fn define(&mut self, rule: Rule) {
-        // TODO handle error with result
-        let param_types = check_rule(&rule, &self.tuples).unwrap();
 
         let mut pred_ty_ids =
             rule.predicates.iter().map(|pred| pred.tuple.ty_id).collect::<Vec<_>>();
         pred_ty_ids.sort();
         pred_ty_ids.dedup();
 
-        let stack = Arc::new(Mutex::new(self.provider.provide(rule, &self.tuples, &param_types)));
-        for idx in pred_ty_ids {
-            self.reverse_index.entry(idx).or_insert_with(Vec::new).push(stack.clone());
+        let env = Arc::new(Environment {
+            functions: self.functions.clone(),
+            patterns: Patterns::from_rule(&rule),
+            accuracy: rule.accuracy,
+        });
+        let stacks = self.provider.provide(rule, &self.tuples, &param_types, &env)?;
+        Ok(RuleEntry {
+            pred_ty_ids: pred_ty_ids,
+            dependency: dependency,
+            stacks: Arc::new(Mutex::new(stacks)),
+        })
+    }
+
+    /// Makes the function `name` available to the expressions of the rules defined
+    /// from now on, names of built-in or already registered functions are rejected.
+    pub fn register_function(&mut self, name: &str, function: UserFunction) -> Result<(), Error> {
+        if is_builtin(name) || self.functions.contains_key(name) {
+            return Err(Error::DuplicateFunction { name: name.to_owned() });
         }
+        // Rules defined so far keep sharing the previous set of functions
+        let mut functions = (*self.functions).clone();
+        functions.insert(name.to_owned(), Arc::new(function));
+        self.functions = Arc::new(functions);
+        Ok(())
+    }
+
+    fn insert_rule(&mut self, rule_id: usize, entry: RuleEntry) {
+        for &idx in &entry.pred_ty_ids {
+            self.reverse_index.entry(idx).or_insert_with(Vec::new).push(entry.stacks.clone());
+        }
+        self.rules.insert(rule_id, entry);
+    }
 
+    fn remove_rule(&mut self, rule_id: usize) -> Option<RuleEntry> {
+        self.rules.remove(&rule_id).map(|entry| {
+            for idx in &entry.pred_ty_ids {
+                let is_empty = self.reverse_index
+                    .get_mut(idx)
+                    .map(|stacks| {
+                        stacks.retain(|it| !Arc::ptr_eq(it, &entry.stacks));
+                        stacks.is_empty()
+                    })
+                    .unwrap_or(false);
+                if is_empty {
+                    self.reverse_index.remove(idx);
+                }
+            }
+            entry
+        })
+    }
+
+    /// Replaces the rule `rule_id` with a new one, keeping the same id.
+    ///
+    /// If `keep_events` is set, the events buffered for the old rule are replayed
+    /// into the new one, where they are retained only if they satisfy its predicates.
+    /// On error the old rule is left untouched.
+    pub fn replace_rule(&mut self,
+                        rule_id: usize,
+                        rule: Rule,
+                        keep_events: bool)
+                        -> Result<(), Error> {
+        if !self.rules.contains_key(&rule_id) {
+            return Err(Error::UnknownRule { rule_id: rule_id });
+        }
+        let entry = self.build_rule(rule, Some(rule_id))?;
+        if let Some(old) = self.remove_rule(rule_id) {
+            if keep_events {
+                let events = old.stacks.lock().unwrap().buffered_events();
+                entry.stacks.lock().unwrap().replay(&events);
+            }
+        }
+        self.insert_rule(rule_id, entry);
+        Ok(())
     }
-    fn publish(&mut self, event: &Arc<Event>) {
-        for (_, &mut (ref condition, ref mut listener)) in &mut self.listeners {
+
+    fn notify_listeners(&mut self, event: &Arc<Event>) {
+        for (_, &mut (ref condition, ref mut listener, ref env)) in &mut self.listeners {
             let should_send = match *condition {
                 SubscrFilter::Content { ty, ref filters } => {
                     ty == event.tuple.ty_id &&
                     {
-                        let context = SimpleContext::new(&event.tuple);
-                        let check_expr = |expr| context.evaluate_expression(expr).unwrap_bool();
-                        filters.iter().all(check_expr)
+                        let context = SimpleContext::new(event, env);
+                        filters.iter().all(|expr| context.is_satisfied(expr))
                     }
                 }
                 SubscrFilter::Topic { ty } => ty == event.tuple.ty_id,
@@ -149,33 +320,582 @@ impl Engine for TRex {
                 listener.receive(event);
             }
         }
+    }
+
+    fn process_event(&self, event: &Arc<Event>) -> Vec<Vec<Event>> {
+        let empty = Vec::new();
+        let stacks = self.reverse_index.get(&event.tuple.ty_id).unwrap_or(&empty);
+        self.process_stacks(event, stacks)
+    }
+
+    /// Submits an event to the given rules, each in a task of the thread pool.
+    fn process_stacks<'a, I>(&self, event: &Arc<Event>, stacks: I) -> Vec<Vec<Event>>
+        where I: IntoIterator<Item = &'a Arc<Mutex<RuleStacks>>>
+    {
+        let (ref tx, ref rx) = self.channel;
+        let mut tasks = 0;
+        for stack in stacks {
+            let tx = tx.clone();
+            let stack = stack.clone();
+            let event = event.clone();
+            self.threadpool.execute(move || {
+                let mut stack = stack.lock().unwrap();
+                tx.send(stack.process(&event)).unwrap()
+            });
+            tasks += 1;
+        }
+        rx.iter().take(tasks).collect()
+    }
+
+    /// Assigns the next sequence number to an event.
+    fn stamp(&mut self, mut event: Event) -> Arc<Event> {
+        self.last_seq += 1;
+        event.seq = self.last_seq;
+        Arc::new(event)
+    }
+
+    /// Processes an event and all the events derived from it.
+    fn process_chain(&mut self, event: Event) -> Result<(), Error> {
+        self.process_queue(vec![(event, 0)])
+    }
+
+    /// Processes the events of the queue, from the last, along with their derived events.
+    ///
+    /// The chains exceeding the maximum depth are cut without interrupting the others,
+    /// the first of them is reported at the end.
+    fn process_queue(&mut self, mut queue: Vec<(Event, usize)>) -> Result<(), Error> {
+        let mut exceeded = None;
+        // Derived events are processed depth first, in the order they are generated
+        while let Some((event, depth)) = queue.pop() {
+            if depth > self.config.max_depth {
+                exceeded = exceeded.or(Some(Error::MaxDepthExceeded {
+                    max_depth: self.config.max_depth,
+                    ty_id: event.tuple.ty_id,
+                }));
+                continue;
+            }
+            let event = self.stamp(event);
+            self.notify_listeners(&event);
+            let events = self.process_event(&event);
+            let generated = events.into_iter().flat_map(|it| it).collect::<Vec<_>>();
+            queue.extend(generated.into_iter().rev().map(|evt| (evt, depth + 1)));
+        }
+        exceeded.map_or(Ok(()), Err)
+    }
 
-        let events = {
-            let (ref tx, ref rx) = self.channel;
-            let empty = Vec::new();
-            let stacks = self.reverse_index.get(&event.tuple.ty_id).unwrap_or(&empty);
-            for stack in stacks {
-                let tx = tx.clone();
-                let stack = stack.clone();
-                let event = event.clone();
-                self.threadpool.execute(move || {
-                    let mut stack = stack.lock().unwrap();
-                    tx.send(stack.process(&event)).unwrap()
-                });
+    /// Processes a sequence of events as `publish` would do with each of them in turn,
+    /// but submitting to each rule that cannot see derived events all the ones it is
+    /// interested in with a single task of the thread pool.
+    ///
+    /// The other rules are given the events one at a time, each followed by the events derived
+    /// from it. A chain exceeding the maximum depth does not stop the processing, the first
+    /// error is reported at the end. With an allowed lateness the events are published one at
+    /// a time, since they have to be reordered.
+    ///
+    /// The events are taken by value because, as with `publish`, the engine assigns them
+    /// their sequence numbers.
+    pub fn publish_batch(&mut self, events: Vec<Event>) -> Result<(), Error> {
+        let mut result = Ok(());
+        if self.config.allowed_lateness.is_some() {
+            for event in events {
+                result = result.and(self.publish(event));
             }
-            rx.iter().take(stacks.len()).collect::<Vec<_>>()
-        };
+            return result;
+        }
+
+        // The rules on the types that no rule generates only see the published events
+        let derived = self.rules.values().map(|entry| entry.dependency.1).collect::<HashSet<_>>();
+        let independent = self.rules
+            .values()
+            .filter(|entry| entry.pred_ty_ids.iter().all(|ty| !derived.contains(ty)))
+            .map(|entry| stacks_key(&entry.stacks))
+            .collect::<HashSet<_>>();
+
+        // A derived event follows the events stamped before it, even the ones at the same time,
+        // so to order them as `publish` does the events are stamped in runs of increasing
+        // times, each processed before stamping the next one
+        let mut run: Vec<Arc<Event>> = Vec::new();
+        for event in events {
+            if run.last().map_or(false, |last| event.time <= last.time) {
+                let previous = mem::replace(&mut run, Vec::new());
+                result = result.and(self.process_run(previous, &independent));
+            }
+            let event = self.stamp(event);
+            run.push(event);
+        }
+        result.and(self.process_run(run, &independent))
+    }
+
+    /// Processes a run of stamped events, see `publish_batch`.
+    fn process_run(&mut self,
+                   events: Vec<Arc<Event>>,
+                   independent: &HashSet<usize>)
+                   -> Result<(), Error> {
+        // Positions of the events each independent rule is interested in, by its stacks
+        let mut slices = FnvHashMap::default();
+        for (i, event) in events.iter().enumerate() {
+            let stacks = self.reverse_index.get(&event.tuple.ty_id).into_iter().flat_map(|it| it);
+            for stack in stacks.filter(|it| independent.contains(&stacks_key(it))) {
+                slices.entry(stacks_key(stack))
+                    .or_insert_with(|| (stack.clone(), Vec::new()))
+                    .1
+                    .push(i);
+            }
+        }
+
+        let (tx, rx) = channel();
+        let tasks = slices.len();
+        let events = Arc::new(events);
+        for (_, (stack, slice)) in slices {
+            let tx = tx.clone();
+            let events = events.clone();
+            self.threadpool.execute(move || {
+                let mut stack = stack.lock().unwrap();
+                let generated = slice.into_iter()
+                    .map(|i| (i, stack.process(&events[i])))
+                    .collect::<Vec<_>>();
+                tx.send(generated).unwrap()
+            });
+        }
+        let mut generated = vec![Vec::new(); events.len()];
+        for (i, evts) in rx.iter().take(tasks).flat_map(|it| it) {
+            generated[i].extend(evts);
+        }
+
+        let mut result = Ok(());
+        for (event, mut derived) in events.iter().zip(generated) {
+            self.notify_listeners(event);
+            let dependent = {
+                let stacks =
+                    self.reverse_index.get(&event.tuple.ty_id).into_iter().flat_map(|it| it);
+                self.process_stacks(event,
+                                    stacks.filter(|it| !independent.contains(&stacks_key(it))))
+            };
+            derived.extend(dependent.into_iter().flat_map(|it| it));
+            let queue = derived.into_iter().rev().map(|evt| (evt, 1)).collect();
+            result = result.and(self.process_queue(queue));
+        }
+        result
+    }
+
+    /// Processes in chronological order the held back events up to `time` included.
+    ///
+    /// A chain exceeding the maximum depth does not hold back the following events,
+    /// the first error is reported at the end.
+    fn release(&mut self, time: DateTime<UTC>) -> Result<(), Error> {
+        let mut result = Ok(());
+        while let Some(key) = self.pending.keys().next().cloned() {
+            if key.0 > time {
+                break;
+            }
+            let event = self.pending.remove(&key).unwrap();
+            result = result.and(self.process_chain(event));
+        }
+        result
+    }
+
+    /// Processes all the held back events, moving the watermark to the latest of them.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if let Some(&(time, _)) = self.pending.keys().next_back() {
+            self.watermark = Some(self.watermark.map_or(time, |it| it.max(time)));
+        }
+        match self.watermark {
+            Some(watermark) => self.release(watermark),
+            None => Ok(()),
+        }
+    }
+
+    /// Time before which the published events are late, if any has been published.
+    pub fn watermark(&self) -> Option<DateTime<UTC>> { self.watermark }
+
+    /// Takes the late events set aside with the `SideOutput` policy.
+    pub fn take_late_events(&mut self) -> Vec<Event> {
+        mem::replace(&mut self.late, Vec::new())
+    }
+
+    /// Moves the engine to a dispatcher thread fed by the returned ingest handle,
+    /// see `ingest::spawn`.
+    pub fn spawn(self, config: IngestConfig) -> (Ingest, JoinHandle<(TRex, Vec<Error>)>) {
+        ingest::spawn(self, config)
+    }
+}
 
-        for event in events.iter().flat_map(|it| it) {
-            // TODO change recursion into loop and detect infinite loop
-            self.publish(event)
+impl Engine for TRex {
+    type Error = Error;
+    fn declare(&mut self, tuple: TupleDeclaration) -> Result<(), Error> {
+        if let Entry::Vacant(entry) = self.tuples.entry(tuple.id) {
+            entry.insert(tuple);
+            Ok(())
+        } else {
+            Err(Error::DuplicateTuple { ty_id: tuple.id })
         }
     }
+    fn define(&mut self, rule: Rule) -> Result<usize, Error> {
+        let entry = self.build_rule(rule, None)?;
+        self.last_rule_id += 1;
+        let rule_id = self.last_rule_id;
+        self.insert_rule(rule_id, entry);
+        Ok(rule_id)
+    }
+    fn undefine(&mut self, rule_id: usize) -> Result<(), Error> {
+        self.remove_rule(rule_id).map(|_| ()).ok_or(Error::UnknownRule { rule_id: rule_id })
+    }
+    fn redefine(&mut self, rule_id: usize, rule: Rule) -> Result<(), Error> {
+        self.replace_rule(rule_id, rule, true)
+    }
+    fn publish(&mut self, event: Event) -> Result<(), Error> {
+        let lateness = match self.config.allowed_lateness {
+            Some(lateness) => lateness,
+            None => return self.process_chain(event),
+        };
+        if self.watermark.map_or(false, |watermark| event.time < watermark) {
+            return match self.config.late_events {
+                LatePolicy::Drop => Ok(()),
+                LatePolicy::SideOutput => {
+                    self.late.push(event);
+                    Ok(())
+                }
+                LatePolicy::Reevaluate => self.process_chain(event),
+            };
+        }
+        let watermark = event.time - lateness;
+        self.pending.insert((event.time, self.arrivals), event);
+        self.arrivals += 1;
+        let watermark = self.watermark.map_or(watermark, |it| it.max(watermark));
+        self.watermark = Some(watermark);
+        self.release(watermark)
+    }
     fn subscribe(&mut self, condition: SubscrFilter, listener: Box<Listener>) -> usize {
         self.last_id += 1;
         // TODO typecheck condition expressions and check that they are local (no parameters!)
-        self.listeners.insert(self.last_id, (condition, listener));
+        let mut env = Environment { functions: self.functions.clone(), ..Environment::default() };
+        if let SubscrFilter::Content { ref filters, .. } = condition {
+            for expr in filters {
+                env.patterns.add_expression(expr);
+            }
+        }
+        self.listeners.insert(self.last_id, (condition, listener, env));
         self.last_id
     }
     fn unsubscribe(&mut self, listener_id: usize) { self.listeners.remove(&listener_id); }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, UTC};
+    use stack::StackProvider;
+    use std::sync::{Arc, Mutex};
+    use errors::{Error, ExpressionLocation, TypeError};
+    use super::{EngineConfig, LatePolicy, TRex};
+    use tesla::{Engine, Event, Listener, Rule, SubscrFilter, Tuple};
+    use tesla::expressions::{BasicType, Value};
+    use tesla::parser::{Parser, Statement, parse};
+
+    const DECLARATIONS: &'static str = "
+        declare reading(value: int) with id 0
+        declare probe(value: int) with id 1
+        declare alarm(value: int) with id 2
+        declare total(value: int) with id 3
+    ";
+
+    /// Engine with the test declarations and the rules of `source`.
+    fn engine(config: EngineConfig, source: &str) -> TRex {
+        let mut engine = TRex::with_config(2, vec![Box::new(StackProvider::new())], config);
+        for statement in parse(&format!("{}{}", DECLARATIONS, source)).unwrap() {
+            match statement {
+                Statement::Declaration(decl) => engine.declare(decl).unwrap(),
+                Statement::Rule(rule) => {
+                    engine.define(rule).unwrap();
+                }
+            }
+        }
+        engine
+    }
+
+    fn rule(source: &str) -> Rule {
+        let mut parser = Parser::new();
+        parser.parse(DECLARATIONS).unwrap();
+        parser.parse_rule(source).unwrap()
+    }
+
+    struct Collector(Arc<Mutex<Vec<Vec<Value>>>>);
+
+    impl Listener for Collector {
+        fn receive(&mut self, event: &Arc<Event>) {
+            self.0.lock().unwrap().push(event.tuple.data.clone());
+        }
+    }
+
+    /// Collects the attributes of the events of tuple `ty_id` notified by the engine.
+    fn collect(engine: &mut TRex, ty_id: usize) -> Arc<Mutex<Vec<Vec<Value>>>> {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        engine.subscribe(SubscrFilter::Topic { ty: ty_id }, Box::new(Collector(received.clone())));
+        received
+    }
+
+    fn event(ty_id: usize, value: i64, time: i64) -> Event {
+        Event {
+            tuple: Tuple {
+                ty_id: ty_id,
+                data: vec![Value::Int(value)],
+            },
+            time: UTC.timestamp(time, 0),
+            seq: 0,
+        }
+    }
+
+    fn values(received: &Arc<Mutex<Vec<Vec<Value>>>>) -> Vec<i64> {
+        received.lock()
+            .unwrap()
+            .iter()
+            .map(|data| match data[0] {
+                Value::Int(value) => value,
+                ref other => panic!("unexpected value {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn definition_errors() {
+        let mut engine = engine(EngineConfig::default(), "");
+        let mut parser = Parser::new();
+        parser.parse("declare other(value: string) with id 1").unwrap();
+        match engine.declare(parser.declarations()[0].clone()) {
+            Err(Error::DuplicateTuple { ty_id: 1 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let result = engine.define(rule("define alarm(value = $v)
+                                         from probe
+                                         and last reading[$v = value](value > 0, value > \"low\")
+                                         within 1min from probe"));
+        match result {
+            Err(Error::Type { location: ExpressionLocation::Constraint { predicate: 1,
+                                                                         constraint: 1 },
+                              error: TypeError::BinaryOperands { left: BasicType::Int,
+                                                                 right: BasicType::Str,
+                                                                 .. },
+                              .. }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut engine = TRex::new(1, Vec::new());
+        for statement in parse(DECLARATIONS).unwrap() {
+            if let Statement::Declaration(decl) = statement {
+                engine.declare(decl).unwrap();
+            }
+        }
+        // Only the predicates after the trigger need a provider
+        assert!(engine.define(rule("define alarm(value = 1) from probe")).is_ok());
+        match engine.define(rule("define alarm(value = $v)
+                                  from probe
+                                  and last reading[$v = value] within 1min from probe")) {
+            Err(Error::NoProvider { predicate: 1 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn arrival_order_ties() {
+        let mut engine = engine(EngineConfig::default(),
+                                "define alarm(value = $v)
+                                 from probe
+                                 and last reading[$v = value] within 1min from probe");
+        let alarms = collect(&mut engine, 2);
+        engine.publish(event(0, 1, 10)).unwrap();
+        engine.publish(event(0, 2, 10)).unwrap();
+        engine.publish(event(1, 0, 10)).unwrap();
+        assert_eq!(values(&alarms), vec![2]);
+    }
+
+    #[test]
+    fn watermarks() {
+        let config = EngineConfig {
+            allowed_lateness: Some(Duration::seconds(2)),
+            ..EngineConfig::default()
+        };
+        let mut engine = engine(config,
+                                "define alarm(value = $v)
+                                 from probe
+                                 and last reading[$v = value] within 1min from probe");
+        let alarms = collect(&mut engine, 2);
+        assert_eq!(engine.watermark(), None);
+        engine.publish(event(0, 1, 5)).unwrap();
+        engine.publish(event(1, 0, 4)).unwrap();
+        engine.publish(event(0, 2, 3)).unwrap();
+        assert_eq!(engine.watermark(), Some(UTC.timestamp(3, 0)));
+        assert!(values(&alarms).is_empty());
+
+        // The events are processed in time order once the watermark passes them
+        engine.publish(event(1, 0, 6)).unwrap();
+        assert_eq!(engine.watermark(), Some(UTC.timestamp(4, 0)));
+        assert_eq!(values(&alarms), vec![2]);
+
+        engine.flush().unwrap();
+        assert_eq!(engine.watermark(), Some(UTC.timestamp(6, 0)));
+        assert_eq!(values(&alarms), vec![2, 1]);
+    }
+
+    #[test]
+    fn late_policies() {
+        let run = |policy| {
+            let config = EngineConfig {
+                allowed_lateness: Some(Duration::seconds(2)),
+                late_events: policy,
+                ..EngineConfig::default()
+            };
+            let mut engine = engine(config, "");
+            let readings = collect(&mut engine, 0);
+            engine.publish(event(0, 1, 10)).unwrap();
+            engine.publish(event(0, 2, 5)).unwrap();
+            engine.flush().unwrap();
+            let late = engine.take_late_events()
+                .into_iter()
+                .map(|event| event.tuple.data[0].clone())
+                .collect::<Vec<_>>();
+            (values(&readings), late)
+        };
+        assert_eq!(run(LatePolicy::Drop), (vec![1], vec![]));
+        assert_eq!(run(LatePolicy::SideOutput), (vec![1], vec![Value::Int(2)]));
+        // The late event is processed right away, before the held back one
+        assert_eq!(run(LatePolicy::Reevaluate), (vec![2, 1], vec![]));
+    }
+
+    #[test]
+    fn released_overflow() {
+        let config = EngineConfig {
+            max_depth: 1,
+            allowed_lateness: Some(Duration::seconds(2)),
+            ..EngineConfig::default()
+        };
+        let mut engine = engine(config,
+                                "define alarm(value = $v) from reading[$v = value];
+                                 define total(value = $v) from alarm[$v = value]");
+        let probes = collect(&mut engine, 1);
+        engine.publish(event(0, 1, 5)).unwrap();
+        engine.publish(event(1, 2, 6)).unwrap();
+        match engine.flush() {
+            Err(Error::MaxDepthExceeded { max_depth: 1, ty_id: 3 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // The probe after the overflowing reading is processed anyway
+        assert_eq!(values(&probes), vec![2]);
+        assert!(engine.pending.is_empty());
+    }
+
+    #[test]
+    fn derivation_cycles() {
+        const CYCLE: &'static str = "define reading(value = $v + 1) from reading[$v = value]";
+        let mut engine = engine(EngineConfig::default(), "");
+        match engine.define(rule(CYCLE)) {
+            Err(Error::RecursiveRule { cycle }) => assert_eq!(cycle, vec![0, 0]),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let config = EngineConfig {
+            max_depth: 3,
+            allow_cycles: true,
+            ..EngineConfig::default()
+        };
+        let mut engine = engine(config,
+                                &format!("{}\ndefine alarm(value = $v) from reading[$v = value]",
+                                         CYCLE));
+        let readings = collect(&mut engine, 0);
+        let alarms = collect(&mut engine, 2);
+        match engine.publish(event(0, 0, 10)) {
+            // Either the reading or the alarm derived from the deepest reading
+            Err(Error::MaxDepthExceeded { max_depth: 3, ty_id }) => assert!(ty_id != 1),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // Only the events deeper than the limit are discarded
+        assert_eq!(values(&readings), vec![0, 1, 2, 3]);
+        let mut alarms = values(&alarms);
+        alarms.sort();
+        assert_eq!(alarms, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn rule_replacement() {
+        const RULE: &'static str = "define alarm(value = $v)
+                                    from probe
+                                    and last reading[$v = value] within 1min from probe";
+        let mut engine = engine(EngineConfig::default(),
+                                &format!("{}\ndefine total(value = $v) from reading[$v = value]",
+                                         RULE));
+        assert_eq!(engine.reverse_index[&0].len(), 2);
+        engine.undefine(1).unwrap();
+        assert_eq!(engine.reverse_index[&0].len(), 1);
+        assert!(!engine.reverse_index.contains_key(&1));
+        match engine.undefine(1) {
+            Err(Error::UnknownRule { rule_id: 1 }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let mut engine = engine(EngineConfig::default(), RULE);
+        let alarms = collect(&mut engine, 2);
+        engine.publish(event(0, 1, 1)).unwrap();
+        engine.replace_rule(1, rule(RULE), false).unwrap();
+        engine.publish(event(1, 0, 2)).unwrap();
+        engine.publish(event(0, 2, 3)).unwrap();
+        engine.redefine(1, rule(RULE)).unwrap();
+        engine.publish(event(1, 0, 4)).unwrap();
+        assert_eq!(values(&alarms), vec![2]);
+
+        // A failed replacement keeps the old rule and its events
+        match engine.redefine(1, rule("define alarm(value = \"high\") from probe")) {
+            Err(Error::Type { .. }) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(engine.reverse_index[&0].len(), 1);
+        assert_eq!(engine.reverse_index[&1].len(), 1);
+        engine.publish(event(1, 0, 5)).unwrap();
+        assert_eq!(values(&alarms), vec![2, 2]);
+    }
+
+    #[test]
+    fn redefine_consuming_rule() {
+        const RULE: &'static str = "define alarm(value = $v)
+                                    from probe
+                                    and last reading[$v = value] within 1min from probe
+                                    consuming reading";
+        let mut engine = engine(EngineConfig::default(), RULE);
+        let alarms = collect(&mut engine, 2);
+        engine.publish(event(0, 1, 1)).unwrap();
+        engine.publish(event(0, 2, 2)).unwrap();
+        engine.publish(event(1, 0, 3)).unwrap();
+        // The reading consumed by the old rule is not given back to the new one
+        engine.redefine(1, rule(RULE)).unwrap();
+        engine.publish(event(1, 0, 4)).unwrap();
+        assert_eq!(values(&alarms), vec![2, 1]);
+    }
+
+    #[test]
+    fn batch_publishing() {
+        const RULES: &'static str = "
+            define alarm(value = $v) from reading[$v = value](value > 10)
+            define total(value = $n) from probe and alarm[$n = count()] within 1min from probe
+        ";
+        let batch = vec![event(0, 11, 1),
+                         event(1, 0, 1),
+                         event(0, 12, 2),
+                         event(0, 5, 2),
+                         event(1, 0, 2),
+                         event(1, 0, 3)];
+
+        let mut sequential = engine(EngineConfig::default(), RULES);
+        let expected = Arc::new(Mutex::new(Vec::new()));
+        sequential.subscribe(SubscrFilter::Any, Box::new(Collector(expected.clone())));
+        let totals = collect(&mut sequential, 3);
+        for event in batch.clone() {
+            sequential.publish(event).unwrap();
+        }
+        // Each probe sees the alarms derived from the readings before it
+        assert_eq!(values(&totals), vec![1, 2, 2]);
+
+        let mut batched = engine(EngineConfig::default(), RULES);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        batched.subscribe(SubscrFilter::Any, Box::new(Collector(received.clone())));
+        batched.publish_batch(batch).unwrap();
+        assert_eq!(*received.lock().unwrap(), *expected.lock().unwrap());
+    }
+}
@@ -1,99 +1,249 @@
 use FnvHashMap;
+use errors::{Error, ExpressionLocation, TypeError};
+use expressions::functions::Functions;
 use expressions::inference::{CurrentType, InferenceContext};
 use linear_map::LinearMap;
+use std::collections::HashSet;
 use tesla::{EventTemplate, Rule, TupleDeclaration, TupleType};
 use tesla::expressions::*;
 use tesla::predicates::*;
 
-// TODO improve error handling and more informative failure,
-// or switch completely to a panic!() approach and defer checks to parser
-
-mod aggregate {
-    use tesla::TupleDeclaration;
+pub mod aggregate {
+    use tesla::{TupleDeclaration, TupleType};
     use tesla::expressions::BasicType;
     use tesla::predicates::Aggregator;
 
-    pub fn get_type(aggregator: &Aggregator,
-                    tuple: &TupleDeclaration)
-                    -> Result<BasicType, String> {
+    pub fn get_type(aggregator: &Aggregator, tuple: &TupleDeclaration) -> Option<BasicType> {
         match *aggregator {
-                Aggregator::Avg(i) => {
-                    match tuple.attributes[i].ty {
-                        BasicType::Int | BasicType::Float => Some(BasicType::Float),
-                        _ => None,
-                    }
-                }
-                Aggregator::Sum(i) |
-                Aggregator::Min(i) |
-                Aggregator::Max(i) => {
-                    match tuple.attributes[i].ty {
-                        ref ty @ BasicType::Int |
-                        ref ty @ BasicType::Float => Some(ty.clone()),
-                        _ => None,
-                    }
-                }
-                Aggregator::Count => Some(BasicType::Int),
+            Aggregator::Avg(i) |
+            Aggregator::StdDev(i) |
+            Aggregator::Variance(i) |
+            Aggregator::Percentile(i, _) |
+            Aggregator::Median(i) => {
+                tuple.attributes.get(i).and_then(|attr| match attr.ty {
+                    BasicType::Int | BasicType::Float => Some(BasicType::Float),
+                    _ => None,
+                })
+            }
+            Aggregator::Sum(i) |
+            Aggregator::Min(i) |
+            Aggregator::Max(i) => {
+                tuple.attributes.get(i).and_then(|attr| match attr.ty {
+                    ref ty @ BasicType::Int |
+                    ref ty @ BasicType::Float => Some(ty.clone()),
+                    _ => None,
+                })
             }
-            .ok_or("Wrong attribute type in aggregate computation".to_owned())
+            Aggregator::Count => Some(BasicType::Int),
+            Aggregator::CountDistinct(i) => tuple.attributes.get(i).map(|_| BasicType::Int),
+            // Static tuples have no timestamp to order them
+            Aggregator::First(_) |
+            Aggregator::Last(_) if tuple.ty == TupleType::Static => None,
+            Aggregator::First(i) |
+            Aggregator::Last(i) => tuple.attributes.get(i).map(|attr| attr.ty.clone()),
+            Aggregator::Any(i) |
+            Aggregator::All(i) => {
+                tuple.attributes.get(i).and_then(|attr| match attr.ty {
+                    BasicType::Bool => Some(BasicType::Bool),
+                    _ => None,
+                })
+            }
+        }
     }
 }
 
-fn type_check_constraints<'a>(constraints: &'a [Expression],
+fn type_check_constraints<'a>(i: usize,
+                              constraints: &'a [Expression],
                               ctx: InferenceContext<'a>)
-                              -> Result<InferenceContext<'a>, String> {
-    constraints.iter().fold(Ok(ctx), |ctx, expr| {
+                              -> Result<InferenceContext<'a>, Error> {
+    constraints.iter().enumerate().fold(Ok(ctx), |ctx, (j, expr)| {
         ctx.and_then(|ctx| {
-            ctx.infer_expression(expr).and_then(|ty| {
-                if let BasicType::Bool = ty {
-                    Ok(ctx)
-                } else {
-                    Err("Non boolean contraint".to_owned())
-                }
-            })
+            let location = ExpressionLocation::Constraint {
+                predicate: i,
+                constraint: j,
+            };
+            check_boolean(&ctx, expr, location).map(|_| ctx)
         })
     })
 }
 
+fn type_check_filters<'a>(filters: &'a [Expression],
+                          ctx: InferenceContext<'a>)
+                          -> Result<InferenceContext<'a>, Error> {
+    filters.iter().enumerate().fold(Ok(ctx), |ctx, (i, expr)| {
+        ctx.and_then(|ctx| {
+            check_boolean(&ctx, expr, ExpressionLocation::Filter { filter: i }).map(|_| ctx)
+        })
+    })
+}
+
+fn check_boolean(ctx: &InferenceContext,
+                 expr: &Expression,
+                 location: ExpressionLocation)
+                 -> Result<(), Error> {
+    match ctx.infer_expression(expr) {
+        Ok(BasicType::Bool) => Ok(()),
+        Ok(ty) => {
+            Err(Error::Type {
+                location: location,
+                expression: expr.clone(),
+                error: TypeError::Mismatch {
+                    expected: BasicType::Bool,
+                    found: ty,
+                },
+            })
+        }
+        Err(err) => Err(Error::at(location, err)),
+    }
+}
+
+fn check_structure(i: usize, pred: &Predicate, tuple: &TupleDeclaration) -> Result<(), Error> {
+    let expected_ty = match pred.ty {
+        PredicateType::Trigger { .. } |
+        PredicateType::Event { .. } |
+        PredicateType::EventAggregate { .. } |
+        PredicateType::EventNegation { .. } => TupleType::Event,
+        PredicateType::OrderedStatic { .. } |
+        PredicateType::UnorderedStatic { .. } |
+        PredicateType::StaticAggregate { .. } |
+        PredicateType::StaticNegation => TupleType::Static,
+    };
+    let timing = match pred.ty {
+        PredicateType::Event { ref timing, .. } |
+        PredicateType::EventAggregate { ref timing, .. } |
+        PredicateType::EventNegation { ref timing } => Some(timing),
+        _ => None,
+    };
+    let quantile = match pred.ty {
+        PredicateType::EventAggregate { aggregator: Aggregator::Percentile(_, quantile), .. } |
+        PredicateType::StaticAggregate { aggregator: Aggregator::Percentile(_, quantile), .. } => {
+            Some(quantile)
+        }
+        _ => None,
+    };
+    let is_trigger = if let PredicateType::Trigger { .. } = pred.ty { true } else { false };
+
+    if is_trigger != (i == 0) {
+        if i == 0 {
+            Err(Error::MissingTrigger)
+        } else {
+            Err(Error::MisplacedTrigger { predicate: i })
+        }
+    } else if tuple.ty != expected_ty {
+        Err(Error::WrongTupleType { predicate: i })
+    } else if timing.map_or(false, |timing| {
+        let lower = match timing.bound {
+            TimingBound::Within { .. } |
+            TimingBound::Last { .. } => 0,
+            TimingBound::Between { lower } => lower,
+        };
+        timing.upper >= i || lower >= i
+    }) {
+        Err(Error::InvalidTiming { predicate: i })
+    } else if timing.map_or(false, |timing| match timing.bound {
+        TimingBound::Last { count } => count == 0,
+        _ => false,
+    }) {
+        Err(Error::EmptyWindow { predicate: i })
+    } else {
+        match quantile {
+            // Written so that NaN is rejected as well
+            Some(quantile) if !(quantile >= 0.0 && quantile <= 1.0) => {
+                Err(Error::InvalidQuantile {
+                    predicate: i,
+                    quantile: quantile,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn type_check_parameter<'a>(i: usize,
+                            j: usize,
+                            param: &'a ParameterDeclaration,
+                            ctx: InferenceContext<'a>)
+                            -> Result<InferenceContext<'a>, Error> {
+    match ctx.infer_expression(&param.expression) {
+        Ok(ty) => {
+            let nullable = ctx.is_nullable(&param.expression);
+            Ok(ctx.add_parameter((i, j), ty, nullable))
+        }
+        Err(err) => {
+            let location = ExpressionLocation::Parameter {
+                predicate: i,
+                parameter: j,
+            };
+            Err(Error::at(location, err))
+        }
+    }
+}
+
 fn type_check_predicate<'a>(i: usize,
                             pred: &'a Predicate,
                             tuples: &'a FnvHashMap<usize, TupleDeclaration>,
                             ctx: InferenceContext<'a>)
-                            -> Result<InferenceContext<'a>, String> {
+                            -> Result<InferenceContext<'a>, Error> {
     tuples.get(&pred.tuple.ty_id)
-        .ok_or("Predicate refers to unknown tuple".to_owned())
+        .ok_or(Error::UnknownTuple { ty_id: pred.tuple.ty_id })
+        .and_then(|tuple| check_structure(i, pred, tuple).map(|_| tuple))
         .and_then(|tuple| {
-            // TODO check that a static predicate refers to a static tuple
             match pred.ty {
                 PredicateType::Trigger { ref parameters, .. } |
-                PredicateType::Event { ref parameters, .. } |
+                PredicateType::Event { ref parameters, .. } => {
+                    // The selected event is available from now on
+                    let ctx = ctx.add_timestamp(i);
+                    parameters.iter()
+                        .enumerate()
+                        .fold(Ok(ctx.set_current(CurrentType::Tuple(tuple))),
+                              |ctx, (j, param)| {
+                                  ctx.and_then(|ctx| type_check_parameter(i, j, param, ctx))
+                              })
+                        .and_then(|ctx| type_check_constraints(i, &pred.tuple.constraints, ctx))
+                }
                 PredicateType::OrderedStatic { ref parameters, .. } |
                 PredicateType::UnorderedStatic { ref parameters, .. } => {
                     parameters.iter()
                         .enumerate()
                         .fold(Ok(ctx.set_current(CurrentType::Tuple(tuple))),
                               |ctx, (j, param)| {
-                                  ctx.and_then(|ctx| {
-                                      ctx.infer_expression(&param.expression)
-                                          .map(|ty| ctx.add_parameter((i, j), ty))
-                                  })
+                                  ctx.and_then(|ctx| type_check_parameter(i, j, param, ctx))
                               })
-                        .and_then(|ctx| type_check_constraints(&pred.tuple.constraints, ctx))
+                        .and_then(|ctx| type_check_constraints(i, &pred.tuple.constraints, ctx))
                 }
                 PredicateType::EventAggregate { ref aggregator, ref parameter, .. } |
                 PredicateType::StaticAggregate { ref aggregator, ref parameter } => {
-                    type_check_constraints(&pred.tuple.constraints,
-                                           ctx.set_current(CurrentType::Tuple(tuple)))
+                    let ctx = ctx.set_current(CurrentType::Tuple(tuple));
+                    // Only the constraints can refer to the time of the aggregated events
+                    let ctx = if tuple.ty == TupleType::Event {
+                        ctx.set_current_event(i)
+                    } else {
+                        ctx
+                    };
+                    type_check_constraints(i, &pred.tuple.constraints, ctx)
                         .and_then(|ctx| {
-                            aggregate::get_type(aggregator, tuple).and_then(|ty| {
-                                let ctx = ctx.set_current(CurrentType::Aggr(ty));
-                                ctx.infer_expression(&parameter.expression)
-                                    .map(|ty| ctx.add_parameter((i, 0), ty))
-                            })
+                            aggregate::get_type(aggregator, tuple)
+                                .ok_or_else(|| {
+                                    Error::InvalidAggregate {
+                                        predicate: i,
+                                        aggregator: aggregator.clone(),
+                                    }
+                                })
+                                .and_then(|ty| {
+                                    let ctx = ctx.set_current(CurrentType::Aggr(ty));
+                                    type_check_parameter(i, 0, parameter, ctx)
+                                })
                         })
                 }
-                PredicateType::EventNegation { .. } |
+                PredicateType::EventNegation { .. } => {
+                    type_check_constraints(i,
+                                           &pred.tuple.constraints,
+                                           ctx.set_current(CurrentType::Tuple(tuple))
+                                               .set_current_event(i))
+                }
                 PredicateType::StaticNegation => {
-                    type_check_constraints(&pred.tuple.constraints,
+                    type_check_constraints(i,
+                                           &pred.tuple.constraints,
                                            ctx.set_current(CurrentType::Tuple(tuple)))
                 }
             }
@@ -103,48 +253,189 @@ fn type_check_predicate<'a>(i: usize,
 fn type_check_template<'a>(template: &'a EventTemplate,
                            tuples: &'a FnvHashMap<usize, TupleDeclaration>,
                            ctx: InferenceContext<'a>)
-                           -> Result<InferenceContext<'a>, String> {
+                           -> Result<InferenceContext<'a>, Error> {
     tuples.get(&template.ty_id)
-        .ok_or("The rule produce an unknown event".to_owned())
+        .ok_or(Error::UnknownTuple { ty_id: template.ty_id })
         .and_then(|tuple| {
             if let TupleType::Event = tuple.ty {
                 if tuple.attributes.len() == template.attributes.len() {
                     template.attributes
                         .iter()
-                        .zip(tuple.attributes.iter().map(|it| &it.ty))
-                        .fold(Ok(ctx), |ctx, (expr, ty)| {
+                        .zip(&tuple.attributes)
+                        .enumerate()
+                        .fold(Ok(ctx), |ctx, (i, (expr, attr))| {
                             ctx.and_then(|ctx| {
-                                ctx.infer_expression(expr)
-                                    .and_then(|res| {
-                                        if *ty == res {
-                                            Ok(ctx)
-                                        } else {
-                                            Err("Wrong attribute assignment".to_owned())
-                                        }
-                                    })
+                                let location = ExpressionLocation::Template { attribute: i };
+                                match ctx.infer_expression(expr) {
+                                    Ok(ref res) if *res != attr.ty => {
+                                        Err(Error::Type {
+                                            location: location,
+                                            expression: expr.clone(),
+                                            error: TypeError::Mismatch {
+                                                expected: attr.ty.clone(),
+                                                found: res.clone(),
+                                            },
+                                        })
+                                    }
+                                    Ok(_) if !attr.nullable && ctx.is_nullable(expr) => {
+                                        Err(Error::Type {
+                                            location: location,
+                                            expression: expr.clone(),
+                                            error: TypeError::NullableValue,
+                                        })
+                                    }
+                                    Ok(_) => Ok(ctx),
+                                    Err(err) => Err(Error::at(location, err)),
+                                }
                             })
                         })
                 } else {
-                    Err("Wrong number of attributes in event template".to_owned())
+                    Err(Error::TemplateArity {
+                        ty_id: template.ty_id,
+                        expected: tuple.attributes.len(),
+                        found: template.attributes.len(),
+                    })
                 }
             } else {
-                Err("The rule produce a static tuple".to_owned())
+                Err(Error::StaticTemplate { ty_id: template.ty_id })
+            }
+        })
+}
+
+fn check_consuming(rule: &Rule) -> Result<(), Error> {
+    rule.consuming
+        .iter()
+        .map(|&i| {
+            match rule.predicates.get(i).map(|pred| &pred.ty) {
+                Some(&PredicateType::Event { .. }) => Ok(()),
+                _ => Err(Error::InvalidConsuming { predicate: i }),
             }
         })
+        .collect()
 }
 
 // TODO think of a better name or maybe separate funtionality to get params types
 pub fn check_rule(rule: &Rule,
-                  tuples: &FnvHashMap<usize, TupleDeclaration>)
-                  -> Result<LinearMap<(usize, usize), BasicType>, String> {
+                  tuples: &FnvHashMap<usize, TupleDeclaration>,
+                  functions: &Functions)
+                  -> Result<LinearMap<(usize, usize), BasicType>, Error> {
+    if rule.predicates.is_empty() {
+        return Err(Error::MissingTrigger);
+    }
+    match rule.accuracy {
+        Some(accuracy) if !(accuracy > 0.0 && accuracy < 1.0) => {
+            return Err(Error::InvalidAccuracy { accuracy: accuracy });
+        }
+        _ => (),
+    }
     rule.predicates
         .iter()
         .enumerate()
-        .fold(Ok(InferenceContext::new()), |ctx, (i, pred)| {
+        .fold(Ok(InferenceContext::new(functions)), |ctx, (i, pred)| {
             ctx.and_then(|ctx| type_check_predicate(i, pred, tuples, ctx.reset_current()))
         })
-        .and_then(|ctx| type_check_constraints(&rule.filters, ctx.reset_current()))
+        .and_then(|ctx| type_check_filters(&rule.filters, ctx.reset_current()))
         .and_then(|ctx| type_check_template(&rule.event_template, tuples, ctx.reset_current()))
-        // TODO check consuming!
+        .and_then(|ctx| check_consuming(rule).map(|_| ctx))
         .map(|ctx| ctx.get_params())
 }
+
+/// Looks for a path from `from` to `to` in the graph described by the edges in
+/// `dependencies`, returning the types it traverses (both ends included).
+pub fn find_path<I>(dependencies: I, from: usize, to: usize) -> Option<Vec<usize>>
+    where I: IntoIterator<Item = (usize, usize)>
+{
+    let mut edges = FnvHashMap::default();
+    for (source, target) in dependencies {
+        edges.entry(source).or_insert_with(Vec::new).push(target);
+    }
+
+    let mut visited = HashSet::new();
+    let mut path = vec![from];
+    let mut pending = vec![edges.get(&from).map_or(&[][..], |it| &it[..]).iter()];
+    if from == to {
+        return Some(path);
+    }
+    visited.insert(from);
+    while let Some(next) = pending.last_mut().map(|it| it.next()) {
+        match next {
+            Some(&ty) if ty == to => {
+                path.push(ty);
+                return Some(path);
+            }
+            Some(&ty) => {
+                if visited.insert(ty) {
+                    path.push(ty);
+                    pending.push(edges.get(&ty).map_or(&[][..], |it| &it[..]).iter());
+                }
+            }
+            None => {
+                pending.pop();
+                path.pop();
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use FnvHashMap;
+    use errors::Error;
+    use expressions::functions::Functions;
+    use std::f64;
+    use super::{check_rule, find_path};
+    use tesla::Rule;
+    use tesla::parser::Parser;
+    use tesla::predicates::{Aggregator, PredicateType};
+
+    #[test]
+    fn derivation_paths() {
+        let edges = vec![(0, 1), (1, 2), (2, 3), (1, 4), (4, 0)];
+        assert_eq!(find_path(edges.clone(), 0, 0), Some(vec![0]));
+        assert_eq!(find_path(edges.clone(), 1, 0), Some(vec![1, 4, 0]));
+        assert_eq!(find_path(edges.clone(), 2, 0), None);
+        assert_eq!(find_path(edges.clone(), 3, 1), None);
+        assert_eq!(find_path(edges, 5, 0), None);
+    }
+
+    #[test]
+    fn percentile_parameters() {
+        let mut parser = Parser::new();
+        parser.parse("declare temperature(value: int) with id 0
+                      declare stats(value: float) with id 1")
+            .unwrap();
+        let tuples = parser.declarations()
+            .iter()
+            .map(|decl| (decl.id, decl.clone()))
+            .collect::<FnvHashMap<_, _>>();
+        let functions = Functions::default();
+        let rule = parser.parse_rule("
+                define stats(value = $p)
+                from temperature as t
+                and temperature[$p = percentile(value, 0.9)] within 1min from t
+                with accuracy 0.01
+            ")
+            .unwrap();
+        assert!(check_rule(&rule, &tuples, &functions).is_ok());
+
+        for &accuracy in &[0.0, -0.5, 1.0, f64::NAN] {
+            let rule = Rule { accuracy: Some(accuracy), ..rule.clone() };
+            match check_rule(&rule, &tuples, &functions) {
+                Err(Error::InvalidAccuracy { .. }) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        for &quantile in &[-0.1, 1.5, f64::NAN] {
+            let mut rule = rule.clone();
+            if let PredicateType::EventAggregate { aggregator: Aggregator::Percentile(_, ref mut q),
+                                                   .. } = rule.predicates[1].ty {
+                *q = quantile;
+            }
+            match check_rule(&rule, &tuples, &functions) {
+                Err(Error::InvalidQuantile { predicate: 1, .. }) => (),
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+    }
+}
@@ -36,7 +36,9 @@ impl PartialResult {
     pub fn get_parameter(&self, idx: (usize, usize)) -> &Value { &self.parameters[&idx] }
 
     #[inline(always)]
-    pub fn get_time(&self, idx: usize) -> DateTime<UTC> { self.events[&idx].time }
+    pub fn get_order(&self, idx: usize) -> (DateTime<UTC>, usize) { self.events[&idx].order() }
+
+    pub fn get_event(&self, idx: usize) -> Option<&Arc<Event>> { self.events.get(&idx) }
 }
 
 pub trait EventProcessor: Send {
@@ -44,42 +46,65 @@ pub trait EventProcessor: Send {
     fn process(&mut self, event: &Arc<Event>) {}
     #[allow(unused_variables)]
     fn consume(&mut self, event: &Arc<Event>) {}
+    /// Feeds the processor with past events, that are the only ones it has to retain.
+    fn replay(&mut self, events: &[Arc<Event>]) {
+        for event in events {
+            self.process(event);
+        }
+    }
+    /// Removes the events that cannot be matched anymore, given the positions in the stream
+    /// (see `Event::order`) before which the events of the previous predicates are not needed,
+    /// and returns the one for this predicate.
     #[allow(unused_variables)]
-    fn remove_old(&mut self, times: &FnvHashMap<usize, DateTime<UTC>>) -> Option<DateTime<UTC>> {
+    fn remove_old(&mut self,
+                  orders: &FnvHashMap<usize, (DateTime<UTC>, usize)>)
+                  -> Option<(DateTime<UTC>, usize)> {
         None
     }
+    /// Events currently retained by the processor, used to carry them over
+    /// when a rule is replaced.
+    fn buffered_events(&self) -> Vec<Arc<Event>> { Vec::new() }
     fn evaluate(&self, result: &PartialResult) -> Vec<PartialResult>;
 }
 
 #[derive(Clone, Debug)]
 pub struct Trigger {
     predicate: Predicate,
+    env: Arc<Environment>,
 }
 
 impl Trigger {
-    pub fn new(predicate: &Predicate) -> Self { Trigger { predicate: predicate.clone() } }
+    pub fn new(predicate: &Predicate, env: Arc<Environment>) -> Self {
+        Trigger {
+            predicate: predicate.clone(),
+            env: env,
+        }
+    }
 
     fn is_satisfied(&self, context: &CompleteContext) -> bool {
-        let check_expr = |expr| context.evaluate_expression(expr).unwrap_bool();
-        self.predicate.tuple.constraints.iter().all(check_expr)
+        self.predicate.tuple.constraints.iter().all(|expr| context.is_satisfied(expr))
     }
 
     fn evaluate(&self, event: &Arc<Event>) -> Option<PartialResult> {
         if event.tuple.ty_id == self.predicate.tuple.ty_id {
             let res = if let PredicateType::Trigger { ref parameters } = self.predicate.ty {
-                parameters.iter().enumerate().fold(PartialResult::new(), |res, (i, param)| {
-                    let val = CompleteContext::new(&res, &event.tuple)
-                        .evaluate_expression(&param.expression);
-                    res.insert_parameter((0, i), val)
+                parameters.iter().enumerate().fold(Ok(PartialResult::new()), |res, (i, param)| {
+                    res.and_then(|res| {
+                        let val = CompleteContext::new(&res, event, &self.env)
+                            .evaluate_expression(&param.expression);
+                        val.map(|val| res.insert_parameter((0, i), val))
+                    })
                 })
             } else {
                 panic!("Unexpected predicate type")
             };
-            if self.is_satisfied(&CompleteContext::new(&res, &event.tuple)) {
-                Some(res.insert_event(0, event.clone()))
-            } else {
-                None
-            }
+            res.ok().and_then(|res| {
+                if self.is_satisfied(&CompleteContext::new(&res, event, &self.env)) {
+                    Some(res.insert_event(0, event.clone()))
+                } else {
+                    None
+                }
+            })
         } else {
             None
         }
@@ -90,26 +115,47 @@ pub struct RuleStacks {
     trigger: Trigger,
     processors: LinearMap<usize, Box<EventProcessor>>,
     rule: Rule,
+    env: Arc<Environment>,
 }
 
 impl RuleStacks {
     pub fn new(trigger: Trigger,
                processors: LinearMap<usize, Box<EventProcessor>>,
-               rule: Rule)
+               rule: Rule,
+               env: Arc<Environment>)
                -> Self {
         RuleStacks {
             trigger: trigger,
             processors: processors,
             rule: rule,
+            env: env,
         }
     }
 
-    fn remove_old_events(&mut self, trigger_time: &DateTime<UTC>) {
-        let mut times = FnvHashMap::default();
-        times.insert(0, *trigger_time);
+    /// Collects the events buffered by all the processors in stream order.
+    pub fn buffered_events(&self) -> Vec<Arc<Event>> {
+        let mut events = self.processors
+            .values()
+            .flat_map(|processor| processor.buffered_events())
+            .collect::<Vec<_>>();
+        events.sort_by_key(|evt| (evt.order(), &**evt as *const Event as usize));
+        events.dedup_by(|a, b| Arc::ptr_eq(a, b));
+        events
+    }
+
+    /// Feeds the processors with past events without evaluating the trigger.
+    pub fn replay(&mut self, events: &[Arc<Event>]) {
+        for (_, processor) in &mut self.processors {
+            processor.replay(events);
+        }
+    }
+
+    fn remove_old_events(&mut self, trigger: &Event) {
+        let mut orders = FnvHashMap::default();
+        orders.insert(0, trigger.order());
         for (&i, processor) in &mut self.processors {
-            let time = processor.remove_old(&times).unwrap_or(*trigger_time);
-            times.insert(i, time);
+            let order = processor.remove_old(&orders).unwrap_or(trigger.order());
+            orders.insert(i, order);
         }
     }
 
@@ -122,47 +168,148 @@ impl RuleStacks {
             })
     }
 
-    fn generate_events<'a, T>(&self, event: &Arc<Event>, results: T) -> Vec<Arc<Event>>
+    /// Removes the events matched by the consumed predicates from their processors.
+    ///
+    /// All the results of an evaluation are generated before anything is consumed,
+    /// so if an `Each` selection matches several events they all contribute
+    /// to the output and then they are all consumed.
+    fn consume_events(&mut self, results: &[&PartialResult]) {
+        for &idx in &self.rule.consuming {
+            if let Some(processor) = self.processors.get_mut(&idx) {
+                let mut events =
+                    results.iter().filter_map(|res| res.get_event(idx)).collect::<Vec<_>>();
+                events.sort_by_key(|evt| &***evt as *const Event as usize);
+                events.dedup_by(|a, b| Arc::ptr_eq(a, b));
+                for event in events {
+                    processor.consume(event);
+                }
+            }
+        }
+    }
+
+    fn generate_events<'a, T>(&self, event: &Arc<Event>, results: T) -> Vec<Event>
         where T: IntoIterator<Item = &'a PartialResult>
     {
+        // Results whose template fails to evaluate do not generate any event
         results.into_iter()
-            .map(|res| {
-                let context = CompleteContext::new(res, ());
+            .filter_map(|res| {
+                let context = CompleteContext::new(res, (), &self.env);
                 let template = &self.rule.event_template;
-                Arc::new(Event {
-                    tuple: Tuple {
-                        ty_id: template.ty_id,
-                        data: template.attributes
-                            .iter()
-                            .map(|expr| context.evaluate_expression(expr))
-                            .collect(),
-                    },
-                    time: event.time,
-                })
+                template.attributes
+                    .iter()
+                    .map(|expr| context.evaluate_expression(expr))
+                    .collect::<Result<Vec<_>, _>>()
+                    .ok()
+                    .map(|data| {
+                        Event {
+                            tuple: Tuple {
+                                ty_id: template.ty_id,
+                                data: data,
+                            },
+                            time: event.time,
+                            // Assigned by the engine when it processes the event
+                            seq: 0,
+                        }
+                    })
             })
             .collect()
     }
 
-    pub fn process(&mut self, event: &Arc<Event>) -> Vec<Arc<Event>> {
+    pub fn process(&mut self, event: &Arc<Event>) -> Vec<Event> {
         for (_, processor) in &mut self.processors {
             processor.process(event);
         }
 
         if let Some(initial) = self.trigger.evaluate(event) {
-            self.remove_old_events(&event.time);
+            self.remove_old_events(event);
             let partial_results = self.get_partial_results(initial);
             // TODO move filter as early as possible in the partial_results generation
-            let filtered = partial_results.iter().filter(|res| {
-                let context = CompleteContext::new(res, ());
-                self.rule
-                    .filters
-                    .iter()
-                    .all(|expr| context.evaluate_expression(expr).unwrap_bool())
-            });
-            // TODO consuming clause
+            let filtered = partial_results.iter()
+                .filter(|res| {
+                    let context = CompleteContext::new(res, (), &self.env);
+                    self.rule
+                        .filters
+                        .iter()
+                        .all(|expr| context.is_satisfied(expr))
+                })
+                .collect::<Vec<_>>();
+            self.consume_events(&filtered);
             self.generate_events(event, filtered)
         } else {
             Vec::new()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use NodeProvider;
+    use chrono::{TimeZone, UTC};
+    use expressions::evaluation::Environment;
+    use linear_map::LinearMap;
+    use stack::StackProvider;
+    use std::sync::Arc;
+    use super::{RuleStacks, Trigger};
+    use tesla::{Event, Tuple};
+    use tesla::expressions::Value;
+    use tesla::parser::Parser;
+
+    fn stacks(source: &str, provider: &StackProvider) -> RuleStacks {
+        let mut parser = Parser::new();
+        parser.parse("declare reading(value: int) with id 0
+                      declare probe(value: int) with id 1
+                      declare alarm(value: int) with id 2")
+            .unwrap();
+        let rule = parser.parse_rule(source).unwrap();
+        let env = Arc::new(Environment::default());
+        let trigger = Trigger::new(&rule.predicates[0], env.clone());
+        let processors = rule.predicates
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(i, predicate)| {
+                let tuple = &parser.declarations()[predicate.tuple.ty_id];
+                let processor = provider.provide(i, tuple, predicate, &LinearMap::new(), &env);
+                (i, processor.unwrap().unwrap())
+            })
+            .collect();
+        RuleStacks::new(trigger, processors, rule, env)
+    }
+
+    #[test]
+    fn consume_events() {
+        const RULE: &'static str = "define alarm(value = $v)
+                                    from probe
+                                    and each reading[$v = value] within 1min from probe";
+        let provider = StackProvider::new();
+        let mut consuming = stacks(&format!("{} consuming reading", RULE), &provider);
+        let mut other = stacks(RULE, &provider);
+        let mut publish = |ty_id, value, time| {
+            let event = Arc::new(Event {
+                tuple: Tuple {
+                    ty_id: ty_id,
+                    data: vec![Value::Int(value)],
+                },
+                time: UTC.timestamp(time, 0),
+                seq: 0,
+            });
+            let values = |events: Vec<Event>| {
+                let mut values = events.into_iter()
+                    .map(|evt| match evt.tuple.data[0] {
+                        Value::Int(value) => value,
+                        ref other => panic!("unexpected value {:?}", other),
+                    })
+                    .collect::<Vec<_>>();
+                values.sort();
+                values
+            };
+            (values(consuming.process(&event)), values(other.process(&event)))
+        };
+        assert_eq!(publish(0, 1, 1), (vec![], vec![]));
+        assert_eq!(publish(0, 2, 2), (vec![], vec![]));
+        // Every match contributes before they are all consumed
+        assert_eq!(publish(1, 0, 3), (vec![1, 2], vec![1, 2]));
+        // The consumption is local to the consuming rule
+        assert_eq!(publish(1, 0, 4), (vec![], vec![1, 2]));
+    }
+}
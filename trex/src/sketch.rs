@@ -0,0 +1,164 @@
+use std::cmp::Ordering;
+use std::mem;
+
+// Each level of compaction adds a rank error of at most `n / capacity`,
+// so this many levels are enough to keep it bounded up to 2^32 values
+const MAX_LEVELS: f64 = 32.0;
+
+// NaN is never inserted, so every pair of values is ordered
+fn compare(a: &f64, b: &f64) -> Ordering { a.partial_cmp(b).unwrap_or(Ordering::Equal) }
+
+/// Mergeable summary of a set of values to compute their quantiles with bounded memory.
+///
+/// It is a hierarchy of compactors (as described by Manku, Rajagopalan and Lindsay):
+/// each value of the i-th level stands for `2^i` inserted ones, and a full level is sorted
+/// and halved, promoting every other value to the next one.
+/// Until the first level fills up the quantiles are exact.
+#[derive(Clone, Debug)]
+pub struct QuantileSketch {
+    capacity: usize,
+    levels: Vec<Vec<f64>>,
+    // Alternates the half promoted by the compactions, so that their errors partially cancel out
+    odd: bool,
+}
+
+impl QuantileSketch {
+    /// Creates a sketch that keeps all the values, computing exact quantiles.
+    pub fn exact() -> Self {
+        QuantileSketch {
+            capacity: usize::max_value(),
+            levels: vec![Vec::new()],
+            odd: false,
+        }
+    }
+
+    /// Creates a sketch whose rank error is at most `accuracy` times the number of values,
+    /// that must be strictly between 0 and 1 (as checked on the rules).
+    pub fn with_accuracy(accuracy: f64) -> Self {
+        let capacity = (MAX_LEVELS / accuracy).ceil() as usize;
+        QuantileSketch {
+            capacity: capacity + capacity % 2,
+            levels: vec![Vec::new()],
+            odd: false,
+        }
+    }
+
+    fn compact(&mut self, level: usize) {
+        if self.levels.len() == level + 1 {
+            self.levels.push(Vec::new());
+        }
+        let mut values = mem::replace(&mut self.levels[level], Vec::new());
+        values.sort_by(compare);
+        // With an odd number of values the greatest one stays where it is
+        if values.len() % 2 == 1 {
+            self.levels[level].extend(values.pop());
+        }
+        let odd = self.odd;
+        self.odd = !self.odd;
+        let promoted = values.into_iter()
+            .enumerate()
+            .filter(|&(i, _)| (i % 2 == 1) == odd)
+            .map(|(_, value)| value);
+        self.levels[level + 1].extend(promoted);
+    }
+
+    fn compress(&mut self) {
+        let mut level = 0;
+        while level < self.levels.len() {
+            if self.levels[level].len() >= self.capacity {
+                self.compact(level);
+            }
+            level += 1;
+        }
+    }
+
+    /// Adds a value to the set, `NaN` has no rank and is ignored.
+    pub fn insert(&mut self, value: f64) {
+        if !value.is_nan() {
+            self.levels[0].push(value);
+            if self.levels[0].len() >= self.capacity {
+                self.compress();
+            }
+        }
+    }
+
+    /// Adds all the values summarized by another sketch,
+    /// the result is as accurate as the least accurate of the two.
+    #[cfg(test)]
+    pub fn merge(&mut self, other: &QuantileSketch) {
+        self.capacity = self.capacity.min(other.capacity);
+        while self.levels.len() < other.levels.len() {
+            self.levels.push(Vec::new());
+        }
+        for (level, values) in self.levels.iter_mut().zip(other.levels.iter()) {
+            level.extend(values.iter().cloned());
+        }
+        self.compress();
+    }
+
+    /// Number of summarized values.
+    pub fn len(&self) -> usize {
+        self.levels.iter().enumerate().map(|(i, values)| values.len() << i).sum()
+    }
+
+    /// Smallest value whose rank is at least `quantile` times the number of values,
+    /// or `None` if the set is empty.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        let mut weighted = self.levels
+            .iter()
+            .enumerate()
+            .flat_map(|(i, values)| values.iter().map(move |&value| (value, 1 << i)))
+            .collect::<Vec<(f64, usize)>>();
+        weighted.sort_by(|a, b| compare(&a.0, &b.0));
+        let rank = ((quantile * self.len() as f64).ceil() as usize).max(1);
+        let mut seen = 0;
+        weighted.into_iter()
+            .find(|&(_, weight)| {
+                seen += weight;
+                seen >= rank
+            })
+            .map(|(value, _)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QuantileSketch;
+
+    #[test]
+    fn exact_quantiles() {
+        let mut sketch = QuantileSketch::exact();
+        assert_eq!(sketch.quantile(0.5), None);
+        for &value in &[5.0, 1.0, 4.0, 2.0, 3.0, 6.0] {
+            sketch.insert(value);
+        }
+        assert_eq!(sketch.quantile(0.0), Some(1.0));
+        assert_eq!(sketch.quantile(0.5), Some(3.0));
+        assert_eq!(sketch.quantile(0.95), Some(6.0));
+        assert_eq!(sketch.quantile(1.0), Some(6.0));
+    }
+
+    #[test]
+    fn approximate_quantiles() {
+        let accuracy = 0.01;
+        let count = 100000;
+        let mut first = QuantileSketch::with_accuracy(accuracy);
+        let mut second = QuantileSketch::with_accuracy(accuracy);
+        // A permutation of `0..count`, as 7919 is a prime
+        for i in 0..count {
+            let value = ((i * 7919) % count) as f64;
+            if i % 3 == 0 { first.insert(value) } else { second.insert(value) }
+        }
+        first.merge(&second);
+        assert_eq!(first.len(), count);
+        assert!(first.levels.iter().map(Vec::len).sum::<usize>() < count / 4);
+        for &quantile in &[0.01, 0.25, 0.5, 0.95, 0.99] {
+            let exact = quantile * count as f64 - 1.0;
+            let error = (first.quantile(quantile).unwrap() - exact).abs();
+            assert!(error <= accuracy * count as f64,
+                    "quantile {} off by {}",
+                    quantile,
+                    error);
+        }
+    }
+}
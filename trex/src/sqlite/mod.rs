@@ -1,22 +1,27 @@
 mod query_builder;
 
-use NodeProvider;
+use {Environment, FnvHashMap, Functions, NodeProvider, UserFunction};
 use cache::{Cache, CachedFetcher, CollisionCache, DummyCache, Fetcher, HitMissCounter};
 use cache::gds1_cache::GDS1Cache;
 use cache::gdsf_cache::{GDSFCache, HasCost, HasSize};
-use chrono::UTC;
-use expressions::evaluation::*;
+use chrono::{DateTime, Duration, TimeZone, UTC};
+use errors::Error;
 use linear_map::LinearMap;
 use lru_cache::LruCache;
 use lru_size_cache::{HasSize as LruHasSize, LruSizeCache};
-use r2d2::{Config, Pool};
+use r2d2::{Config, CustomizeConnection, Pool};
 use r2d2_sqlite::SqliteConnectionManager;
+use regex::Regex;
 use rule_processor::*;
-use rusqlite::Row;
-use rusqlite::types::{ToSql, Value as SqlValue};
-use self::query_builder::SqlContext;
+use rusqlite::{Connection, Error as SqlError, Result as SqlResult, Row};
+use rusqlite::functions::{Context, ToResult};
+use rusqlite::types::{Null, ToSql, Value as SqlValue};
+use expressions::functions;
+use expressions::operations::cast;
+use self::query_builder::{SqlContext, builtin_function, cast_function};
 use std::collections::HashMap;
 use std::iter;
+use std::os::raw::c_int;
 use std::sync::{Arc, Mutex};
 use std::usize;
 use tesla::*;
@@ -73,10 +78,9 @@ struct SqlFetcher {
 
 impl SqlFetcher {
     fn prepare_key(&self, result: &PartialResult) -> CacheKey {
-        let context = CompleteContext::new(result, ());
         let input_params = self.input_params
             .iter()
-            .map(|&(pred, par)| context.get_parameter(pred, par).clone())
+            .map(|&(pred, par)| result.get_parameter((pred, par)).clone())
             .collect::<Vec<_>>();
         CacheKey {
             statement: self.statement.clone(),
@@ -163,6 +167,7 @@ impl<C: SqlCache + ?Sized> SQLiteDriver<C> {
                tuple: &TupleDeclaration,
                predicate: &Predicate,
                parameters_ty: &LinearMap<(usize, usize), BasicType>,
+               functions: &Functions,
                pool: Pool<SqliteConnectionManager>,
                cache: Arc<Mutex<C>>,
                stat: Arc<HitMissCounter>)
@@ -178,19 +183,21 @@ impl<C: SqlCache + ?Sized> SQLiteDriver<C> {
                 PredicateType::StaticAggregate { .. } => vec![parameters_ty[&(idx, 0)].clone()],
                 _ => Vec::new(),
             };
-            let statement = SqlContext::new(idx, tuple).encode_predicate(predicate);
-
-            let fetcher = SqlFetcher {
-                predicate: predicate.clone(),
-                statement: statement,
-                input_params: input_params,
-                output_params: output_params,
-                pool: pool,
-            };
+            // Predicates using functions that SQLite lacks are left to other providers
+            let mut context = SqlContext::new(idx, tuple, parameters_ty, functions);
+            context.encode_predicate(predicate).map(|statement| {
+                let fetcher = SqlFetcher {
+                    predicate: predicate.clone(),
+                    statement: statement,
+                    input_params: input_params,
+                    output_params: output_params,
+                    pool: pool,
+                };
 
-            Some(SQLiteDriver {
-                idx: idx,
-                fetcher: CachedFetcher::with_cache(cache, fetcher, stat),
+                SQLiteDriver {
+                    idx: idx,
+                    fetcher: CachedFetcher::with_cache(cache, fetcher, stat),
+                }
             })
         } else {
             None
@@ -198,6 +205,14 @@ impl<C: SqlCache + ?Sized> SQLiteDriver<C> {
     }
 }
 
+// Timestamps are stored as the milliseconds elapsed since the Unix epoch,
+// and durations as milliseconds too, any finer precision is truncated.
+fn to_millis(time: &DateTime<UTC>) -> i64 { (*time - UTC.timestamp(0, 0)).num_milliseconds() }
+
+fn from_millis(millis: i64) -> DateTime<UTC> {
+    UTC.timestamp(0, 0) + Duration::milliseconds(millis)
+}
+
 // FIXME shouldn't be needed as soon as rusqlite is updated with the new ToSql trait
 fn to_sql_value(value: &Value) -> SqlValue {
     match *value {
@@ -205,26 +220,205 @@ fn to_sql_value(value: &Value) -> SqlValue {
         Value::Float(x) => SqlValue::Real(x.into()),
         Value::Bool(x) => SqlValue::Integer(if x { 1 } else { 0 }),
         Value::Str(ref x) => SqlValue::Text(x.clone()),
+        Value::Timestamp(ref x) => SqlValue::Integer(to_millis(x)),
+        Value::Duration(x) => SqlValue::Integer(x.num_milliseconds()),
+        Value::Null => SqlValue::Null,
     }
 }
 
+static NULL: Null = Null;
+
 // FIXME shouldn't be needed as soon as rusqlite is updated with the new ToSql trait
 fn to_sql_ref(value: &SqlValue) -> &ToSql {
     match *value {
         SqlValue::Integer(ref x) => x,
         SqlValue::Real(ref x) => x,
         SqlValue::Text(ref x) => x,
+        SqlValue::Null => &NULL,
         _ => unreachable!(),
     }
 }
 
+fn get_arg(ctx: &Context, i: usize, ty: &BasicType) -> SqlResult<Value> {
+    Ok(match *ty {
+            BasicType::Int => ctx.get::<Option<i64>>(i)?.map(Value::Int),
+            BasicType::Float => ctx.get::<Option<f64>>(i)?.map(Value::Float),
+            BasicType::Bool => ctx.get::<Option<i64>>(i)?.map(|x| Value::Bool(x != 0)),
+            BasicType::Str => ctx.get::<Option<String>>(i)?.map(Value::Str),
+            BasicType::Timestamp => {
+                ctx.get::<Option<i64>>(i)?.map(|x| Value::Timestamp(from_millis(x)))
+            }
+            BasicType::Duration => {
+                ctx.get::<Option<i64>>(i)?.map(|x| Value::Duration(Duration::milliseconds(x)))
+            }
+        }
+        .unwrap_or(Value::Null))
+}
+
+fn create_typed_function<T, F>(conn: &Connection,
+                               name: &str,
+                               function: &Arc<UserFunction>,
+                               convert: F)
+                               -> SqlResult<()>
+    where T: ToResult,
+          F: Fn(&Value) -> Option<T> + Send + 'static
+{
+    let function = function.clone();
+    conn.create_scalar_function(name, function.args.len() as c_int, true, move |ctx| {
+        let args = function.args
+            .iter()
+            .enumerate()
+            .map(|(i, ty)| get_arg(ctx, i, ty))
+            .collect::<SqlResult<Vec<_>>>()?;
+        // Functions are strict, as they are in the engine
+        if args.iter().any(Value::is_null) {
+            return Ok(None);
+        }
+        Ok(function.call(&args).ok().and_then(|value| convert(&value)))
+    })
+}
+
+/// Registers a user function on a connection.
+///
+/// A failed evaluation results in NULL, that makes the constraints using it unsatisfied,
+/// in the same way a failed evaluation discards a match in the engine.
+/// A result that is NULL or of an unexpected type is returned as NULL as well,
+/// since panicking would unwind through SQLite.
+fn create_function(conn: &Connection, name: &str, function: &Arc<UserFunction>) -> SqlResult<()> {
+    match function.output {
+        BasicType::Int => {
+            create_typed_function(conn, name, function, |value| match *value {
+                Value::Int(x) => Some(x),
+                _ => None,
+            })
+        }
+        BasicType::Float => {
+            create_typed_function(conn, name, function, |value| match *value {
+                Value::Float(x) => Some(x),
+                _ => None,
+            })
+        }
+        BasicType::Bool => {
+            create_typed_function(conn, name, function, |value| match *value {
+                Value::Bool(x) => Some(x),
+                _ => None,
+            })
+        }
+        BasicType::Str => {
+            create_typed_function(conn, name, function, |value| match *value {
+                Value::Str(ref x) => Some(x.clone()),
+                _ => None,
+            })
+        }
+        BasicType::Timestamp => {
+            create_typed_function(conn, name, function, |value| match *value {
+                Value::Timestamp(ref x) => Some(to_millis(x)),
+                _ => None,
+            })
+        }
+        BasicType::Duration => {
+            create_typed_function(conn, name, function, |value| match *value {
+                Value::Duration(x) => Some(x.num_milliseconds()),
+                _ => None,
+            })
+        }
+    }
+}
+
+/// Defines the function behind the `REGEXP` operator,
+/// patterns are compiled once per connection.
+fn create_regexp(conn: &Connection) -> SqlResult<()> {
+    let mut regexes = FnvHashMap::<String, Regex>::default();
+    conn.create_scalar_function("regexp", 2, true, move |ctx| {
+        let pattern = ctx.get::<String>(0)?;
+        let value = match ctx.get::<Option<String>>(1)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if !regexes.contains_key(&pattern) {
+            let regex = Regex::new(&pattern)
+                .map_err(|err| SqlError::UserFunctionError(Box::new(err)))?;
+            regexes.insert(pattern.clone(), regex);
+        }
+        Ok(Some(regexes[&pattern].is_match(&value)))
+    })
+}
+
+/// Defines the casts that SQLite performs differently, as it saturates out of range floats,
+/// formats them in another way and reads the leading number of any string.
+fn create_casts(conn: &Connection) -> SqlResult<()> {
+    let casts = [(BasicType::Float, BasicType::Int, Rounding::Truncate),
+                 (BasicType::Float, BasicType::Int, Rounding::Round),
+                 (BasicType::Float, BasicType::Str, Rounding::Truncate),
+                 (BasicType::Bool, BasicType::Str, Rounding::Truncate),
+                 (BasicType::Str, BasicType::Int, Rounding::Truncate),
+                 (BasicType::Str, BasicType::Float, Rounding::Truncate)];
+    casts.iter()
+        .map(|&(ref from, ref to, ref rounding)| {
+            let (ty, mode) = (to.clone(), rounding.clone());
+            let function = UserFunction::new(vec![from.clone()],
+                                             to.clone(),
+                                             move |args| cast::evaluate(&args[0], &ty, &mode));
+            create_function(conn, &cast_function(from, to, rounding), &Arc::new(function))
+        })
+        .collect()
+}
+
+/// Defines the built-in functions that SQLite lacks or evaluates differently,
+/// with the arguments promoted to the declared types by `SqlContext`.
+fn create_builtins(conn: &Connection) -> SqlResult<()> {
+    let builtins = [("lower", vec![BasicType::Str], BasicType::Str),
+                    ("upper", vec![BasicType::Str], BasicType::Str),
+                    ("substr", vec![BasicType::Str, BasicType::Int], BasicType::Str),
+                    ("substr",
+                     vec![BasicType::Str, BasicType::Int, BasicType::Int],
+                     BasicType::Str),
+                    ("sqrt", vec![BasicType::Float], BasicType::Float),
+                    ("pow", vec![BasicType::Float, BasicType::Float], BasicType::Float)];
+    builtins.iter()
+        .map(|&(name, ref args, ref output)| {
+            let function = UserFunction::new(args.clone(), output.clone(), move |args| {
+                functions::evaluate(&Functions::default(), name, args)
+            });
+            create_function(conn, &builtin_function(name), &Arc::new(function))
+        })
+        .collect()
+}
+
+/// Sets up the connections created by the pool,
+/// defining pattern matching, the casts, the built-in and the user functions.
+#[derive(Debug)]
+struct ConnectionCustomizer {
+    functions: Arc<Mutex<Functions>>,
+}
+
+impl CustomizeConnection<Connection, SqlError> for ConnectionCustomizer {
+    fn on_acquire(&self, conn: &mut Connection) -> Result<(), SqlError> {
+        // `Like` is case sensitive in the engine
+        conn.execute_batch("PRAGMA case_sensitive_like = ON")?;
+        create_regexp(conn)?;
+        create_casts(conn)?;
+        create_builtins(conn)?;
+        let functions = self.functions.lock().unwrap();
+        functions.iter().map(|(name, function)| create_function(conn, name, function)).collect()
+    }
+}
+
+/// Converts a column of the results, the static tables can contain NULL anywhere.
 fn get_res(row: &Row, i: i32, ty: &BasicType) -> Value {
     match *ty {
-        BasicType::Int => Value::Int(row.get::<_, i64>(i)),
-        BasicType::Float => Value::Float(row.get::<_, f64>(i)),
-        BasicType::Bool => Value::Bool(row.get::<_, i64>(i) != 0),
-        BasicType::Str => Value::Str(row.get(i)),
-    }
+            BasicType::Int => row.get::<_, Option<i64>>(i).map(Value::Int),
+            BasicType::Float => row.get::<_, Option<f64>>(i).map(Value::Float),
+            BasicType::Bool => row.get::<_, Option<i64>>(i).map(|x| Value::Bool(x != 0)),
+            BasicType::Str => row.get::<_, Option<String>>(i).map(Value::Str),
+            BasicType::Timestamp => {
+                row.get::<_, Option<i64>>(i).map(|x| Value::Timestamp(from_millis(x)))
+            }
+            BasicType::Duration => {
+                row.get::<_, Option<i64>>(i).map(|x| Value::Duration(Duration::milliseconds(x)))
+            }
+        }
+        .unwrap_or(Value::Null)
 }
 
 impl<C: SqlCache + ?Sized> EventProcessor for SQLiteDriver<C> {
@@ -244,6 +438,8 @@ impl<C: SqlCache + ?Sized> EventProcessor for SQLiteDriver<C> {
                     .collect()
             }
             CacheEntryValue::Count(count) => iter::repeat(result).cloned().take(count).collect(),
+            // SQLite aggregates no rows to NULL, while the engine gives no result
+            CacheEntryValue::Aggr(Value::Null) => Vec::new(),
             CacheEntryValue::Aggr(ref value) => {
                 vec![result.clone().insert_parameter((self.idx, 0), value.clone())]
             }
@@ -303,13 +499,20 @@ fn make_cache(ty: CacheType,
 
 pub struct SqliteProvider {
     pool: Pool<SqliteConnectionManager>,
+    pool_size: u32,
+    functions: Arc<Mutex<Functions>>,
     cache: Result<Arc<Mutex<Cache<K = CacheKey, V = CacheEntry> + Send>>, (CacheType, usize)>,
     stat: Arc<HitMissCounter>,
 }
 
 impl SqliteProvider {
     pub fn new(cfg: SqliteConfig) -> Self {
-        let config = Config::builder().pool_size(cfg.pool_size).build();
+        let functions = Arc::new(Mutex::new(Functions::default()));
+        let customizer = ConnectionCustomizer { functions: functions.clone() };
+        let config = Config::builder()
+            .pool_size(cfg.pool_size)
+            .connection_customizer(Box::new(customizer))
+            .build();
         let manager = SqliteConnectionManager::new(&cfg.db_file);
 
         let cache = match cfg.cache_ownership {
@@ -319,10 +522,45 @@ impl SqliteProvider {
 
         SqliteProvider {
             pool: Pool::new(config, manager).unwrap(),
+            pool_size: cfg.pool_size,
+            functions: functions,
             cache: cache,
             stat: Default::default(),
         }
     }
+
+    /// Registers on the pooled connections the user functions they still lack,
+    /// on error they are left to be registered by the next predicate.
+    fn register_functions(&self, idx: usize, functions: &Functions) -> Result<(), Error> {
+        let missing = {
+            let registered = self.functions.lock().unwrap();
+            functions.iter()
+                .filter(|&(name, _)| !registered.contains_key(name))
+                .map(|(name, function)| (name.clone(), function.clone()))
+                .collect::<Vec<_>>()
+        };
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let failure = |reason: String| {
+            Error::ProviderFailure {
+                predicate: idx,
+                reason: reason,
+            }
+        };
+        // The existing connections are all taken at once to update them, the ones opened
+        // meanwhile are updated as well, and after that the customizer sets up the new ones
+        let connections = (0..self.pool_size)
+            .map(|_| self.pool.get().map_err(|err| failure(err.to_string())))
+            .collect::<Result<Vec<_>, _>>()?;
+        for conn in &connections {
+            for &(ref name, ref function) in &missing {
+                create_function(conn, name, function).map_err(|err| failure(err.to_string()))?;
+            }
+        }
+        self.functions.lock().unwrap().extend(missing);
+        Ok(())
+    }
 }
 
 impl NodeProvider for SqliteProvider {
@@ -330,20 +568,101 @@ impl NodeProvider for SqliteProvider {
                idx: usize,
                tuple: &TupleDeclaration,
                predicate: &Predicate,
-               parameters_ty: &LinearMap<(usize, usize), BasicType>)
-               -> Option<Box<EventProcessor>> {
+               parameters_ty: &LinearMap<(usize, usize), BasicType>,
+               env: &Arc<Environment>)
+               -> Result<Option<Box<EventProcessor>>, Error> {
         let cache = match self.cache {
             Ok(ref cache) => cache.clone(),
             Err((ty, capacity)) => make_cache(ty, capacity),
         };
         let pool = self.pool.clone();
-        SQLiteDriver::new(idx,
-                          tuple,
-                          predicate,
-                          parameters_ty,
-                          pool,
-                          cache,
-                          self.stat.clone())
-            .map(|it| Box::new(it) as Box<EventProcessor>)
+        let driver = SQLiteDriver::new(idx,
+                                       tuple,
+                                       predicate,
+                                       parameters_ty,
+                                       &env.functions,
+                                       pool,
+                                       cache,
+                                       self.stat.clone());
+        match driver {
+            Some(driver) => {
+                // Only the predicates evaluated by SQLite need the functions
+                self.register_functions(idx, &env.functions)?;
+                Ok(Some(Box::new(driver) as Box<EventProcessor>))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {NodeProvider, TRex};
+    use chrono::{TimeZone, UTC};
+    use rusqlite::Connection;
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+    use super::{CacheOwnership, CacheType, SqliteConfig, SqliteProvider};
+    use tesla::{Engine, Event, Listener, SubscrFilter, Tuple};
+    use tesla::expressions::Value;
+    use tesla::parser::{Statement, parse};
+
+    struct Collector(Arc<Mutex<Vec<Value>>>);
+
+    impl Listener for Collector {
+        fn receive(&mut self, event: &Arc<Event>) {
+            self.0.lock().unwrap().push(event.tuple.data[0].clone());
+        }
+    }
+
+    #[test]
+    fn empty_aggregates() {
+        let path = env::temp_dir().join("trex-empty-aggregates.db");
+        let _ = fs::remove_file(&path);
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch("CREATE TABLE sensors (area TEXT, value INTEGER);
+                            INSERT INTO sensors VALUES ('north', 3), ('north', 4);")
+            .unwrap();
+        let provider = SqliteProvider::new(SqliteConfig {
+            db_file: path.to_string_lossy().into_owned(),
+            pool_size: 1,
+            cache_size: 0,
+            cache_ownership: CacheOwnership::Shared,
+            cache_type: CacheType::Dummy,
+        });
+        let mut engine = TRex::new(1, vec![Box::new(provider) as Box<NodeProvider>]);
+        let statements = parse("declare probe(area: string) with id 0
+                                declare static sensors(area: string, value: int) with id 1
+                                declare alarm(value: int) with id 2
+                                define alarm(value = $s)
+                                from probe[$a = area]
+                                and sensors[$s = sum(value)](area == $a)")
+            .unwrap();
+        for statement in statements {
+            match statement {
+                Statement::Declaration(decl) => engine.declare(decl).unwrap(),
+                Statement::Rule(rule) => {
+                    engine.define(rule).unwrap();
+                }
+            }
+        }
+        let alarms = Arc::new(Mutex::new(Vec::new()));
+        engine.subscribe(SubscrFilter::Topic { ty: 2 }, Box::new(Collector(alarms.clone())));
+        for (i, area) in ["north", "south"].iter().enumerate() {
+            let event = Event {
+                tuple: Tuple {
+                    ty_id: 0,
+                    data: vec![Value::Str((*area).to_owned())],
+                },
+                time: UTC.timestamp(i as i64, 0),
+                seq: 0,
+            };
+            engine.publish(event).unwrap();
+        }
+        // There are no sensors to sum in the south
+        assert_eq!(*alarms.lock().unwrap(), vec![Value::Int(7)]);
+        let _ = fs::remove_file(&path);
     }
 }
@@ -1,71 +1,122 @@
+use expressions::functions::Functions;
+use expressions::inference::{CurrentType, InferenceContext};
+use linear_map::LinearMap;
+use rule_checks::aggregate;
+use super::to_millis;
 use tesla::*;
 use tesla::expressions::*;
 use tesla::predicates::*;
 
+/// Name of the function the pooled connections define for a cast
+/// that SQLite would perform with different semantics than the engine.
+pub fn cast_function(from: &BasicType, to: &BasicType, rounding: &Rounding) -> String {
+    match (from, rounding) {
+        (&BasicType::Float, &Rounding::Round) => format!("trex_cast_{}_{}_round", from, to),
+        _ => format!("trex_cast_{}_{}", from, to),
+    }
+}
+
+/// Name of the function the pooled connections define for a built-in function
+/// that SQLite lacks or evaluates with different semantics than the engine.
+pub fn builtin_function(name: &str) -> String { format!("trex_{}", name) }
+
 pub struct SqlContext<'a> {
     idx: usize,
     tuple: &'a TupleDeclaration,
+    functions: &'a Functions,
+    types: InferenceContext<'a>,
     parameters: Vec<String>,
     aggregate: Option<String>,
 }
 
 impl<'a> SqlContext<'a> {
-    pub fn new(idx: usize, tuple: &'a TupleDeclaration) -> Self {
+    pub fn new(idx: usize,
+               tuple: &'a TupleDeclaration,
+               parameters_ty: &LinearMap<(usize, usize), BasicType>,
+               functions: &'a Functions)
+               -> Self {
+        // Nullability is irrelevant, types are only needed to translate conversions
+        let types = parameters_ty.iter()
+            .fold(InferenceContext::new(functions),
+                  |ctx, (idx, ty)| ctx.add_parameter(*idx, ty.clone(), false))
+            .set_current(CurrentType::Tuple(tuple));
         SqlContext {
             idx: idx,
             tuple: tuple,
+            functions: functions,
+            types: types,
             parameters: Vec::new(),
             aggregate: None,
         }
     }
 
+    /// Sets the SQL computing the aggregate, if SQLite has an equivalent for it.
     fn set_aggregate(&mut self, aggr: &Aggregator) {
+        let ty = aggregate::get_type(aggr, self.tuple).unwrap();
+        self.types = self.types.clone().set_current(CurrentType::Aggr(ty));
         let sql = match *aggr {
-            Aggregator::Avg(attribute) => {
-                format!("AVG({}.{})",
-                        self.tuple.name,
-                        self.tuple.attributes[attribute].name)
-            }
-            Aggregator::Sum(attribute) => {
-                format!("SUM({}.{})",
-                        self.tuple.name,
-                        self.tuple.attributes[attribute].name)
-            }
-            Aggregator::Max(attribute) => {
-                format!("MAX({}.{})",
-                        self.tuple.name,
-                        self.tuple.attributes[attribute].name)
-            }
-            Aggregator::Min(attribute) => {
-                format!("MIN({}.{})",
-                        self.tuple.name,
-                        self.tuple.attributes[attribute].name)
-            }
+            Aggregator::Avg(attribute) => format!("AVG({})", self.encode_attribute(attribute)),
+            Aggregator::Sum(attribute) => format!("SUM({})", self.encode_attribute(attribute)),
+            Aggregator::Max(attribute) => format!("MAX({})", self.encode_attribute(attribute)),
+            Aggregator::Min(attribute) => format!("MIN({})", self.encode_attribute(attribute)),
             Aggregator::Count => "COUNT(*)".to_owned(),
+            Aggregator::CountDistinct(attribute) => {
+                format!("COUNT(DISTINCT {})", self.encode_attribute(attribute))
+            }
+            // Booleans are stored as integers, and the engine answers even without values
+            Aggregator::Any(attribute) => {
+                format!("COALESCE(MAX({}), 0)", self.encode_attribute(attribute))
+            }
+            Aggregator::All(attribute) => {
+                format!("COALESCE(MIN({}), 1)", self.encode_attribute(attribute))
+            }
+            Aggregator::StdDev(_) |
+            Aggregator::Variance(_) |
+            Aggregator::First(_) |
+            Aggregator::Last(_) |
+            Aggregator::Percentile(..) |
+            Aggregator::Median(_) => return,
         };
         self.aggregate = Some(sql);
     }
 
-    fn insert_parameter(&mut self, param: &ParameterDeclaration) -> String {
+    fn insert_parameter(&mut self, param: &ParameterDeclaration) -> Option<String> {
         let sql = self.encode_expression(&param.expression);
-        self.parameters.push(sql.clone());
+        if let Some(ref sql) = sql {
+            self.parameters.push(sql.clone());
+        }
         sql
     }
 
-    fn encode_value(&self, value: &Value) -> String {
-        match *value {
+    /// Translates a literal, NaN has no SQL equivalent as SQLite turns it into NULL.
+    fn encode_value(&self, value: &Value) -> Option<String> {
+        let sql = match *value {
             Value::Int(value) => format!("{}", value),
-            Value::Float(value) => format!("{}", value),
+            Value::Float(value) if value.is_nan() => return None,
+            // SQLite has no literal for them, but it reads the overflowing ones as infinite
+            Value::Float(value) if value.is_infinite() => {
+                if value > 0.0 { "9e999" } else { "-9e999" }.to_owned()
+            }
+            // Otherwise it would be read as an integer
+            Value::Float(value) => {
+                let res = format!("{}", value);
+                if res.contains('.') || res.contains('e') { res } else { res + ".0" }
+            }
             Value::Bool(value) => format!("{}", value),
-            // TODO check excaping for SQL injection
-            Value::Str(ref value) => format!("{:?}", value),
-        }
+            Value::Str(ref value) => format!("'{}'", value.replace('\'', "''")),
+            Value::Timestamp(ref value) => format!("{}", to_millis(value)),
+            Value::Duration(value) => format!("{}", value.num_milliseconds()),
+            Value::Null => "NULL".to_owned(),
+        };
+        // Negative numbers follow a minus sign when negated, and `--` starts a comment
+        Some(if sql.starts_with('-') { format!("({})", sql) } else { sql })
     }
 
     fn encode_unary(&self, op: &UnaryOperator) -> String {
         match *op {
                 UnaryOperator::Minus => "-",
-                UnaryOperator::Not => "!",
+                UnaryOperator::Not => "NOT ",
+                UnaryOperator::IsNull | UnaryOperator::IsNotNull => unreachable!(),
             }
             .to_owned()
     }
@@ -76,16 +127,46 @@ impl<'a> SqlContext<'a> {
                 BinaryOperator::Minus => "-",
                 BinaryOperator::Times => "*",
                 BinaryOperator::Division => "/",
+                BinaryOperator::Remainder => "%",
+                BinaryOperator::BitwiseAnd => "&",
+                BinaryOperator::BitwiseOr => "|",
+                BinaryOperator::BitwiseXor => unreachable!(),
+                BinaryOperator::ShiftLeft => "<<",
+                BinaryOperator::ShiftRight => ">>",
                 BinaryOperator::Equal => "=",
                 BinaryOperator::NotEqual => "!=",
                 BinaryOperator::GreaterThan => ">",
                 BinaryOperator::GreaterEqual => ">=",
                 BinaryOperator::LowerThan => "<",
                 BinaryOperator::LowerEqual => "<=",
+                BinaryOperator::Like => unreachable!(),
+                // The pooled connections define the function behind it
+                BinaryOperator::Matches => "REGEXP",
+                BinaryOperator::And => "AND",
+                BinaryOperator::Or => "OR",
+                // Booleans are stored as integers, so `<>` is their exclusive or
+                BinaryOperator::Xor => "<>",
             }
             .to_owned()
     }
 
+    fn encode_cast(&self,
+                   sql: String,
+                   from: &BasicType,
+                   to: &BasicType,
+                   rounding: &Rounding)
+                   -> String {
+        match (from, to) {
+            (from, to) if from == to => sql,
+            // Booleans are stored as integers
+            (&BasicType::Bool, &BasicType::Int) => sql,
+            (&BasicType::Int, &BasicType::Float) |
+            (&BasicType::Bool, &BasicType::Float) => format!("CAST({} AS REAL)", sql),
+            (&BasicType::Int, &BasicType::Str) => format!("CAST({} AS TEXT)", sql),
+            _ => format!("{}({})", cast_function(from, to, rounding), sql),
+        }
+    }
+
     fn encode_attribute(&self, attribute: usize) -> String {
         format!("{}.{}",
                 self.tuple.name,
@@ -100,25 +181,130 @@ impl<'a> SqlContext<'a> {
         }
     }
 
-    fn encode_expression(&self, expr: &Expression) -> String {
+    /// Translates a function call to SQL, if SQLite has an equivalent for it.
+    ///
+    /// User functions are registered with the same name on the pooled connections.
+    fn encode_function(&self, name: &str, args: &[String]) -> Option<String> {
+        let sql = match name {
+            "abs" => format!("ABS({})", args[0]),
+            "floor" => {
+                format!("CAST(CAST({0} AS INTEGER) - ({0} < CAST({0} AS INTEGER)) AS REAL)",
+                        args[0])
+            }
+            "ceil" => {
+                format!("CAST(CAST({0} AS INTEGER) + ({0} > CAST({0} AS INTEGER)) AS REAL)",
+                        args[0])
+            }
+            "round" => format!("ROUND({})", args[0]),
+            // The scalar versions of the aggregates take at least two arguments
+            "min" => format!("MIN({})", args.join(", ")),
+            "max" => format!("MAX({})", args.join(", ")),
+            "len" => format!("LENGTH({})", args[0]),
+            // SQLite converts only ASCII letters, accepts positions before the first character
+            // and lacks the others
+            "lower" | "upper" | "substr" | "sqrt" | "pow" => {
+                format!("{}({})", builtin_function(name), args.join(", "))
+            }
+            "contains" => format!("(INSTR({}, {}) > 0)", args[0], args[1]),
+            "starts_with" => format!("(SUBSTR({0}, 1, LENGTH({1})) = {1})", args[0], args[1]),
+            "if" => format!("(CASE WHEN {} THEN {} ELSE {} END)", args[0], args[1], args[2]),
+            "coalesce" => format!("COALESCE({})", args.join(", ")),
+            _ if self.functions.contains_key(name) => format!("{}({})", name, args.join(", ")),
+            _ => return None,
+        };
+        Some(sql)
+    }
+
+    fn encode_expression(&self, expr: &Expression) -> Option<String> {
         match *expr {
             Expression::Immediate { ref value } => self.encode_value(value),
-            Expression::Reference { attribute } => self.encode_attribute(attribute),
+            Expression::Reference { attribute } => Some(self.encode_attribute(attribute)),
             Expression::Parameter { predicate, parameter } => {
-                self.get_parameter(predicate, parameter)
+                Some(self.get_parameter(predicate, parameter))
+            }
+            Expression::Aggregate => self.aggregate.clone(),
+            // Static tuples have no time, and the event ones are not known when building the query
+            Expression::Timestamp { .. } => None,
+            Expression::Cast { ref ty, ref rounding, ref expression } => {
+                self.types
+                    .infer_expression(expression)
+                    .ok()
+                    .and_then(|from| {
+                        self.encode_expression(expression)
+                            .map(|sql| self.encode_cast(sql, &from, ty, rounding))
+                    })
+            }
+            Expression::UnaryOperation { operator: UnaryOperator::IsNull, ref expression } => {
+                self.encode_expression(expression).map(|expression| {
+                    format!("({} IS NULL)", expression)
+                })
+            }
+            Expression::UnaryOperation { operator: UnaryOperator::IsNotNull, ref expression } => {
+                self.encode_expression(expression).map(|expression| {
+                    format!("({} IS NOT NULL)", expression)
+                })
             }
-            Expression::Aggregate => self.aggregate.clone().unwrap(),
-            Expression::Cast { ref expression, .. } => self.encode_expression(expression),
             Expression::UnaryOperation { ref operator, ref expression } => {
-                format!("({}{})",
-                        self.encode_unary(operator),
-                        self.encode_expression(expression))
+                self.encode_expression(expression)
+                    .map(|expression| format!("({}{})", self.encode_unary(operator), expression))
+            }
+            Expression::BinaryOperation { operator: BinaryOperator::BitwiseXor,
+                                          ref left,
+                                          ref right } => {
+                // SQLite lacks a bitwise exclusive or, so it is built from `|` and `&`
+                self.encode_expression(left)
+                    .and_then(|left| self.encode_expression(right).map(|right| (left, right)))
+                    .map(|(left, right)| format!("(({0} | {1}) - ({0} & {1}))", left, right))
+            }
+            Expression::BinaryOperation { operator: BinaryOperator::Like, ref left, ref right } => {
+                // It is case sensitive, as the pooled connections enable `case_sensitive_like`
+                self.encode_expression(left)
+                    .and_then(|left| self.encode_expression(right).map(|right| (left, right)))
+                    .map(|(left, right)| format!("({} LIKE {} ESCAPE '\\')", left, right))
+            }
+            Expression::BinaryOperation { operator: BinaryOperator::Plus, ref left, ref right }
+                if self.types.infer_expression(expr).ok() == Some(BasicType::Str) => {
+                // Strings are concatenated after converting the other operand
+                let encode_str = |expr: &Expression| {
+                    self.types.infer_expression(expr).ok().and_then(|ty| {
+                        self.encode_expression(expr).map(|sql| {
+                            self.encode_cast(sql, &ty, &BasicType::Str, &Rounding::Truncate)
+                        })
+                    })
+                };
+                encode_str(left)
+                    .and_then(|left| encode_str(right).map(|right| (left, right)))
+                    .map(|(left, right)| format!("({} || {})", left, right))
             }
             Expression::BinaryOperation { ref operator, ref left, ref right } => {
-                format!("({} {} {})",
-                        self.encode_expression(left),
-                        self.encode_binary(operator),
-                        self.encode_expression(right))
+                self.encode_expression(left)
+                    .and_then(|left| self.encode_expression(right).map(|right| (left, right)))
+                    .map(|(left, right)| {
+                        format!("({} {} {})", left, self.encode_binary(operator), right)
+                    })
+            }
+            Expression::Function { ref name, ref args } => {
+                // The numeric arguments are promoted to the type of the result
+                let promoted = match &name[..] {
+                    "min" | "max" | "sqrt" | "pow" => self.types.infer_expression(expr).ok(),
+                    _ => None,
+                };
+                args.iter()
+                    .map(|arg| {
+                        let sql = self.encode_expression(arg);
+                        match promoted {
+                            Some(ref ty) => {
+                                self.types.infer_expression(arg).ok().and_then(|from| {
+                                    sql.map(|sql| {
+                                        self.encode_cast(sql, &from, ty, &Rounding::Truncate)
+                                    })
+                                })
+                            }
+                            None => sql,
+                        }
+                    })
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|args| self.encode_function(name, &args))
             }
         }
     }
@@ -138,25 +324,26 @@ impl<'a> SqlContext<'a> {
                 self.encode_order(&ord.direction))
     }
 
-    pub fn encode_predicate(&mut self, pred: &Predicate) -> String {
+    /// Builds the query for a static predicate, if all its expressions can be translated.
+    pub fn encode_predicate(&mut self, pred: &Predicate) -> Option<String> {
         let selection;
         let filters = pred.tuple
             .constraints
             .iter()
             .map(|expr| self.encode_expression(expr))
-            .collect::<Vec<_>>()
-            .join(" AND ");
+            .collect::<Option<Vec<_>>>();
+        let filters = match filters {
+            Some(filters) => filters.join(" AND "),
+            None => return None,
+        };
         let mut rest = String::new();
 
         match pred.ty {
             PredicateType::OrderedStatic { ref parameters, ref ordering } => {
-                selection = parameters.iter()
-                    .map(|par| {
-                        let sql = self.insert_parameter(par);
-                        format!("{} AS {}", sql, par.name)
-                    })
-                    .collect::<Vec<_>>()
-                    .join(", ");
+                selection = match self.encode_selection(parameters) {
+                    Some(selection) => selection,
+                    None => return None,
+                };
                 let order_by = ordering.iter()
                     .map(|ord| self.encode_ordering(ord))
                     .collect::<Vec<_>>()
@@ -164,18 +351,17 @@ impl<'a> SqlContext<'a> {
                 rest = format!("ORDER BY {} LIMIT 1", order_by);
             }
             PredicateType::UnorderedStatic { ref parameters } => {
-                selection = parameters.iter()
-                    .map(|par| {
-                        let sql = self.insert_parameter(par);
-                        format!("{} AS {}", sql, par.name)
-                    })
-                    .collect::<Vec<_>>()
-                    .join(", ");
+                selection = match self.encode_selection(parameters) {
+                    Some(selection) => selection,
+                    None => return None,
+                };
             }
             PredicateType::StaticAggregate { ref aggregator, ref parameter } => {
                 self.set_aggregate(aggregator);
-                let sql = self.insert_parameter(parameter);
-                selection = format!("{} AS {}", sql, parameter.name);
+                selection = match self.insert_parameter(parameter) {
+                    Some(sql) => format!("{} AS {}", sql, parameter.name),
+                    None => return None,
+                };
             }
             PredicateType::StaticNegation => {
                 selection = "1".to_owned();
@@ -185,10 +371,45 @@ impl<'a> SqlContext<'a> {
         }
 
         // FIXME guard against an empty selection!
-        format!("SELECT {} FROM {} WHERE {} {}",
-                if !selection.is_empty() { selection } else { "COUNT(1)".to_owned() },
-                self.tuple.name,
-                filters,
-                rest)
+        Some(format!("SELECT {} FROM {} WHERE {} {}",
+                     if !selection.is_empty() { selection } else { "COUNT(1)".to_owned() },
+                     self.tuple.name,
+                     filters,
+                     rest))
+    }
+
+    fn encode_selection(&mut self, parameters: &[ParameterDeclaration]) -> Option<String> {
+        parameters.iter()
+            .map(|par| self.insert_parameter(par).map(|sql| format!("{} AS {}", sql, par.name)))
+            .collect::<Option<Vec<_>>>()
+            .map(|selection| selection.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expressions::functions::Functions;
+    use linear_map::LinearMap;
+    use std::f64;
+    use super::SqlContext;
+    use tesla::{TupleDeclaration, TupleType};
+    use tesla::expressions::Value;
+
+    #[test]
+    fn float_literals() {
+        let tuple = TupleDeclaration {
+            ty: TupleType::Static,
+            id: 0,
+            name: "sensors".to_owned(),
+            attributes: Vec::new(),
+        };
+        let functions = Functions::default();
+        let context = SqlContext::new(0, &tuple, &LinearMap::new(), &functions);
+        let encode = |value| context.encode_value(&Value::Float(value));
+        assert_eq!(encode(2.0), Some("2.0".to_owned()));
+        assert_eq!(encode(-0.5), Some("(-0.5)".to_owned()));
+        assert_eq!(encode(f64::INFINITY), Some("9e999".to_owned()));
+        assert_eq!(encode(f64::NEG_INFINITY), Some("(-9e999)".to_owned()));
+        assert_eq!(encode(f64::NAN), None);
     }
 }
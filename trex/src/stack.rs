@@ -1,18 +1,257 @@
 use {FnvHashMap, NodeProvider};
-use aggregators::compute_aggregate;
+use aggregators::{RunningAggregate, compute_aggregate};
 use chrono::{DateTime, UTC};
+use errors::Error;
 use expressions::evaluation::*;
 use linear_map::LinearMap;
 use rule_processor::{EventProcessor, PartialResult};
 use std::cmp::Ordering as CmpOrd;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock, Weak};
 use tesla::{Event, TupleDeclaration};
 use tesla::expressions::*;
 use tesla::predicates::*;
 
 fn ptr_eq<T>(a: *const T, b: *const T) -> bool { a == b }
 
+/// Position of an event in a list sorted by `Event::order`.
+fn find_event(events: &[Arc<Event>], event: &Arc<Event>) -> Option<usize> {
+    let order = event.order();
+    let start = find_order(events, &order, true);
+    events[start..]
+        .iter()
+        .take_while(|evt| evt.order() == order)
+        .position(|evt| ptr_eq(&**evt, &**event))
+        .map(|pos| pos + start)
+}
+
+/// Position of the first event after `order` in a list sorted by `Event::order`,
+/// or of the first one at `order` if `inclusive`.
+fn find_order(events: &[Arc<Event>], order: &(DateTime<UTC>, usize), inclusive: bool) -> usize {
+    events.binary_search_by(|evt| {
+            let current = evt.order();
+            if current < *order || !inclusive && current == *order {
+                CmpOrd::Less
+            } else {
+                CmpOrd::Greater
+            }
+        })
+        .unwrap_err()
+}
+
+/// Whether an expression can be evaluated without the event of the `idx`-th predicate.
+fn is_independent(expression: &Expression, idx: usize) -> bool {
+    match *expression {
+        Expression::Immediate { .. } => true,
+        Expression::Reference { .. } |
+        Expression::Aggregate => false,
+        Expression::Parameter { predicate, .. } |
+        Expression::Timestamp { predicate } => predicate != idx,
+        Expression::Cast { ref expression, .. } |
+        Expression::UnaryOperation { ref expression, .. } => is_independent(expression, idx),
+        Expression::BinaryOperation { ref left, ref right, .. } => {
+            is_independent(left, idx) && is_independent(right, idx)
+        }
+        Expression::Function { ref args, .. } => args.iter().all(|arg| is_independent(arg, idx)),
+    }
+}
+
+/// Events grouped by the value of an attribute that a constraint compares for equality
+/// with an expression independent of the event, so that only one group has to be searched.
 #[derive(Clone, Debug)]
+struct Index {
+    attribute: usize,
+    ty: BasicType,
+    key: Expression,
+    /// Chronologically ordered events by value, null values never satisfy an equality
+    groups: FnvHashMap<Value, Vec<Arc<Event>>>,
+}
+
+impl Index {
+    fn new(idx: usize, tuple: &TupleDeclaration, constraints: &[Expression]) -> Option<Index> {
+        constraints.iter()
+            .filter_map(|expr| match *expr {
+                Expression::BinaryOperation { operator: BinaryOperator::Equal,
+                                              ref left,
+                                              ref right } => {
+                    match (&**left, &**right) {
+                        (&Expression::Reference { attribute }, key) |
+                        (key, &Expression::Reference { attribute }) => Some((attribute, key)),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .filter(|&(_, key)| is_independent(key, idx))
+            // Floats are compared with a tolerance, so equal ones can hash differently
+            .find(|&(attribute, _)| tuple.attributes[attribute].ty != BasicType::Float)
+            .map(|(attribute, key)| {
+                Index {
+                    attribute: attribute,
+                    ty: tuple.attributes[attribute].ty.clone(),
+                    key: key.clone(),
+                    groups: FnvHashMap::default(),
+                }
+            })
+    }
+
+    fn insert(&mut self, event: &Arc<Event>) {
+        let value = &event.tuple.data[self.attribute];
+        if !value.is_null() {
+            self.groups.entry(value.clone()).or_insert_with(Vec::new).push(event.clone());
+        }
+    }
+
+    fn remove(&mut self, event: &Arc<Event>) {
+        let value = &event.tuple.data[self.attribute];
+        let empty = match self.groups.get_mut(value) {
+            Some(group) => {
+                if let Some(index) = find_event(group, event) {
+                    group.remove(index);
+                }
+                group.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.groups.remove(value);
+        }
+    }
+
+    /// Removes the oldest events, that are the first ones of their groups.
+    fn remove_oldest<'a, I>(&mut self, events: I)
+        where I: IntoIterator<Item = &'a Arc<Event>>
+    {
+        let mut counts = FnvHashMap::<&Value, usize>::default();
+        for value in events.into_iter().map(|evt| &evt.tuple.data[self.attribute]) {
+            if !value.is_null() {
+                *counts.entry(value).or_insert(0) += 1;
+            }
+        }
+        for (value, count) in counts {
+            let empty = {
+                let group = self.groups.get_mut(value).unwrap();
+                group.drain(..count);
+                group.is_empty()
+            };
+            if empty {
+                self.groups.remove(value);
+            }
+        }
+    }
+
+    /// Events that can satisfy the equality for a partial result,
+    /// or `None` if the key needs a conversion to be compared with the attribute.
+    fn candidates(&self, result: &PartialResult, env: &Environment) -> Option<&[Arc<Event>]> {
+        match CompleteContext::new(result, (), env).evaluate_expression(&self.key) {
+            Ok(ref key) if key.is_null() => Some(Default::default()),
+            Ok(ref key) if key.get_type() == self.ty => {
+                Some(self.groups.get(key).map_or(Default::default(), |group| &group[..]))
+            }
+            Ok(_) => None,
+            // No event satisfies an equality that cannot be evaluated
+            Err(_) => Some(Default::default()),
+        }
+    }
+}
+
+/// Events that satisfy the local constraints of a predicate, shared by the stacks of all the
+/// equivalent predicates, that is the ones on the same type with the same local constraints.
+///
+/// Each stack sees a range of the events, and only the events that precede all the ranges
+/// are dropped, so the buffer retains the widest window of its stacks.
+#[derive(Debug, Default)]
+struct SharedEvents {
+    events: Vec<Arc<Event>>,
+    /// Number of events dropped since the creation of the buffer
+    dropped: usize,
+    /// Range of the events seen by each stack, counting the dropped events too
+    views: FnvHashMap<usize, (usize, usize)>,
+    next_id: usize,
+}
+
+/// How the view of a stack changes when it is given an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Push {
+    Unchanged,
+    Appended,
+    /// The event is not the last one of the view, as it happens with late events
+    /// or replaying events to a new stack
+    Inserted,
+}
+
+impl SharedEvents {
+    fn subscribe(&mut self) -> usize {
+        let id = self.next_id;
+        let end = self.dropped + self.events.len();
+        self.next_id += 1;
+        self.views.insert(id, (end, end));
+        id
+    }
+
+    fn unsubscribe(&mut self, id: usize) {
+        self.views.remove(&id);
+        self.drop_unused();
+    }
+
+    fn view(&self, id: usize) -> &[Arc<Event>] {
+        let (start, end) = self.views[&id];
+        &self.events[start - self.dropped..end - self.dropped]
+    }
+
+    fn push(&mut self, id: usize, event: &Arc<Event>) -> Push {
+        let pos = match find_event(&self.events, event) {
+            // Another stack has already pushed it
+            Some(pos) => self.dropped + pos,
+            None => {
+                // Events are kept sorted, and in arrival order if they are in the same position
+                let pos = find_order(&self.events, &event.order(), false);
+                self.events.insert(pos, event.clone());
+                let pos = self.dropped + pos;
+                // The other stacks will be given the same event, if it falls in their views
+                for view in self.views.values_mut() {
+                    if view.0 > pos {
+                        view.0 += 1;
+                    }
+                    if view.1 > pos {
+                        view.1 += 1;
+                    }
+                }
+                pos
+            }
+        };
+        let view = self.views.get_mut(&id).unwrap();
+        if pos < view.0 {
+            view.0 = pos;
+            Push::Inserted
+        } else if pos >= view.1 {
+            let appended = pos == view.1;
+            view.1 = pos + 1;
+            if appended { Push::Appended } else { Push::Inserted }
+        } else if pos + 1 < view.1 {
+            Push::Inserted
+        } else {
+            Push::Unchanged
+        }
+    }
+
+    /// Moves the start of the view of a stack forward by `count` events.
+    fn advance(&mut self, id: usize, count: usize) {
+        self.views.get_mut(&id).unwrap().0 += count;
+        self.drop_unused();
+    }
+
+    fn drop_unused(&mut self) {
+        let end = self.dropped + self.events.len();
+        let first = self.views.values().map(|&(start, _)| start).min().unwrap_or(end);
+        self.events.drain(..first - self.dropped);
+        self.dropped = first;
+    }
+}
+
+fn event_key(event: &Arc<Event>) -> usize { &**event as *const Event as usize }
+
+#[derive(Debug)]
 pub struct Stack {
     idx: usize,
     tuple: TupleDeclaration,
@@ -20,11 +259,25 @@ pub struct Stack {
     local_exprs: Vec<Expression>,
     global_exprs: Vec<Expression>,
     timing: Timing,
-    events: Vec<Arc<Event>>,
+    buffer: Arc<RwLock<SharedEvents>>,
+    id: usize,
+    /// Events consumed by this stack (or hidden from it when replaying),
+    /// that the other ones sharing the buffer can still see
+    consumed: FnvHashMap<usize, Arc<Event>>,
+    /// Aggregate of the events before the last trigger, if it can be maintained incrementally,
+    /// that is when the window ends at the trigger and no constraint depends on the results
+    running: Option<RunningAggregate>,
+    index: Option<Index>,
+    env: Arc<Environment>,
 }
 
 impl Stack {
-    pub fn new(idx: usize, tuple: &TupleDeclaration, predicate: &Predicate) -> Option<Stack> {
+    fn new(idx: usize,
+           tuple: &TupleDeclaration,
+           predicate: &Predicate,
+           env: &Arc<Environment>,
+           provider: &StackProvider)
+           -> Option<Stack> {
         match predicate.ty {
             PredicateType::Event { ref timing, .. } |
             PredicateType::EventAggregate { ref timing, .. } |
@@ -33,7 +286,29 @@ impl Stack {
                     .constraints
                     .iter()
                     .cloned()
-                    .partition(|expr| expr.is_local());
+                    .flat_map(Expression::into_conjuncts)
+                    .partition::<Vec<_>, _>(|expr| expr.is_local());
+
+                // The window must end at the trigger, to be the same for all the results
+                let within = match timing.bound {
+                    TimingBound::Within { .. } => true,
+                    TimingBound::Between { .. } |
+                    TimingBound::Last { .. } => false,
+                };
+                let incremental = within && timing.upper == 0 && global_exprs.is_empty();
+                let running = match predicate.ty {
+                    PredicateType::EventAggregate { ref aggregator, .. } if incremental => {
+                        RunningAggregate::new(aggregator, &tuple.attributes)
+                    }
+                    _ => None,
+                };
+                // The last events are counted among all the ones in the stack
+                let index = match timing.bound {
+                    TimingBound::Last { .. } => None,
+                    _ => Index::new(idx, tuple, &global_exprs),
+                };
+                let buffer = provider.shared_events(predicate.tuple.ty_id, &local_exprs);
+                let id = buffer.write().unwrap().subscribe();
 
                 Some(Stack {
                     idx: idx,
@@ -42,7 +317,12 @@ impl Stack {
                     local_exprs: local_exprs,
                     global_exprs: global_exprs,
                     timing: timing.clone(),
-                    events: Vec::new(),
+                    buffer: buffer,
+                    id: id,
+                    consumed: FnvHashMap::default(),
+                    running: running,
+                    index: index,
+                    env: env.clone(),
                 })
             }
             _ => None,
@@ -52,90 +332,219 @@ impl Stack {
     fn is_locally_satisfied(&self, event: &Arc<Event>) -> bool {
         event.tuple.ty_id == self.predicate.tuple.ty_id &&
         {
-            let context = SimpleContext::new(&event.tuple);
-            let check_expr = |expr| context.evaluate_expression(expr).unwrap_bool();
-            self.local_exprs.iter().all(check_expr)
+            let context = SimpleContext::new(event, &self.env);
+            self.local_exprs.iter().all(|expr| context.is_satisfied(expr))
         }
     }
 
-    fn is_globally_satisfied(&self, context: &CompleteContext) -> bool {
-        let check_expr = |expr| context.evaluate_expression(expr).unwrap_bool();
-        self.global_exprs.iter().all(check_expr)
+    fn is_globally_satisfied(&self, result: &PartialResult, event: &Arc<Event>) -> bool {
+        let context = CompleteContext::new(result, event, &self.env);
+        self.global_exprs.iter().all(|expr| context.is_satisfied(expr))
+    }
+
+    fn is_consumed(&self, event: &Arc<Event>) -> bool {
+        self.consumed.contains_key(&event_key(event))
+    }
+
+    /// Position of the first event of a window starting at `order`.
+    fn window_start(&self, events: &[Arc<Event>], order: &(DateTime<UTC>, usize)) -> usize {
+        find_order(events, order, self.timing.lower_boundary == Boundary::Closed)
+    }
+
+    /// Position of the first event of a window starting at `time`, with no event to break
+    /// the ties with the ones at the same time.
+    fn window_start_at(&self, events: &[Arc<Event>], time: DateTime<UTC>) -> usize {
+        let seq = match self.timing.lower_boundary {
+            Boundary::Closed => 0,
+            Boundary::Open => usize::max_value(),
+        };
+        self.window_start(events, &(time, seq))
+    }
+
+    /// Position after the last event of a window ending at `order`.
+    fn window_end(&self, events: &[Arc<Event>], order: &(DateTime<UTC>, usize)) -> usize {
+        find_order(events, order, self.timing.upper_boundary == Boundary::Open)
+    }
+
+    /// Position of the first of the last `count` events that have not been consumed,
+    /// or of the first event if there are fewer.
+    fn last_events(&self, events: &[Arc<Event>], count: usize) -> usize {
+        let mut seen = 0;
+        events.iter()
+            .rposition(|evt| {
+                if !self.is_consumed(evt) {
+                    seen += 1;
+                }
+                seen == count
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        if let Ok(mut buffer) = self.buffer.write() {
+            buffer.unsubscribe(self.id);
+        }
     }
 }
 
 impl EventProcessor for Stack {
     fn process(&mut self, event: &Arc<Event>) {
         if self.is_locally_satisfied(event) {
-            // TODO reason on precondition: all the events arrive in chronological order
-            self.events.push(event.clone());
+            let mut buffer = self.buffer.write().unwrap();
+            match buffer.push(self.id, event) {
+                Push::Unchanged => {}
+                Push::Appended => {
+                    if let Some(ref mut index) = self.index {
+                        index.insert(event);
+                    }
+                }
+                Push::Inserted => {
+                    // The view has changed before its last event, so everything is rebuilt
+                    if let Some(ref mut running) = self.running {
+                        running.clear();
+                    }
+                    if let Some(ref mut index) = self.index {
+                        index.groups.clear();
+                        for evt in buffer.view(self.id) {
+                            if !self.consumed.contains_key(&event_key(evt)) {
+                                index.insert(evt);
+                            }
+                        }
+                    }
+                }
+            }
         }
     }
 
     fn consume(&mut self, event: &Arc<Event>) {
-        let index = {
-            let start = self.events
-                .binary_search_by(|evt| {
-                    if evt.time < event.time { CmpOrd::Less } else { CmpOrd::Greater }
-                })
-                .unwrap_err();
-            // TODO handle the absence of the event from the queue
-            self.events[start..].iter().position(|evt| ptr_eq(evt, event)).unwrap() + start
+        // The event may be missing if it has already been consumed or removed
+        let found = {
+            let buffer = self.buffer.read().unwrap();
+            find_event(buffer.view(self.id), event).is_some()
         };
-        self.events.remove(index);
+        if found && !self.is_consumed(event) {
+            self.consumed.insert(event_key(event), event.clone());
+            if let Some(ref mut index) = self.index {
+                index.remove(event);
+            }
+            // Only the oldest event can be removed from the aggregate, so it is given up on
+            self.running = None;
+        }
     }
 
-    fn remove_old(&mut self, times: &FnvHashMap<usize, DateTime<UTC>>) -> Option<DateTime<UTC>> {
-        // TODO reason on interval (open vs close)
-        let time = match self.timing.bound {
-            TimingBound::Within { window } => times[&self.timing.upper] - window,
-            TimingBound::Between { lower } => times[&lower],
+    fn replay(&mut self, events: &[Arc<Event>]) {
+        for event in events {
+            self.process(event);
+        }
+        // The view spans from the first to the last replayed event, so it also covers the
+        // events in between that the other stacks share but were not replayed,
+        // as the ones consumed by a replaced rule
+        let replayed = events.iter().map(event_key).collect::<HashSet<_>>();
+        let hidden = {
+            let buffer = self.buffer.read().unwrap();
+            buffer.view(self.id)
+                .iter()
+                .filter(|evt| !replayed.contains(&event_key(evt)))
+                .cloned()
+                .collect::<Vec<_>>()
         };
+        for event in &hidden {
+            self.consume(event);
+        }
+    }
 
-        let index = self.events
-            .binary_search_by(|evt| {
-                if evt.time < time { CmpOrd::Less } else { CmpOrd::Greater }
-            })
-            .unwrap_err();
-        self.events.drain(..index);
+    fn remove_old(&mut self,
+                  orders: &FnvHashMap<usize, (DateTime<UTC>, usize)>)
+                  -> Option<(DateTime<UTC>, usize)> {
+        // The following windows start and end at the same positions or later,
+        // so the events before the current one are not needed anymore
+        let upper = orders[&self.timing.upper];
+        let mut buffer = self.buffer.write().unwrap();
+        let index = {
+            let events = buffer.view(self.id);
+            let index = match self.timing.bound {
+                TimingBound::Within { window } => self.window_start_at(events, upper.0 - window),
+                TimingBound::Between { lower } => self.window_start(events, &orders[&lower]),
+                TimingBound::Last { count } => {
+                    let end = self.window_end(events, &upper);
+                    self.last_events(&events[..end], count)
+                }
+            };
+            if let Some(ref mut running) = self.running {
+                let end = self.window_end(events, &upper);
+                // The window of a late trigger ends before the previous one
+                if end < running.len() {
+                    running.clear();
+                }
+                for evt in &events[running.len()..end] {
+                    running.push(evt);
+                }
+                // An empty window can start after its end
+                for evt in &events[..index.min(running.len())] {
+                    running.pop(evt);
+                }
+            }
+            if let Some(ref mut attribute_index) = self.index {
+                let consumed = &self.consumed;
+                attribute_index.remove_oldest(events[..index]
+                    .iter()
+                    .filter(|evt| !consumed.contains_key(&event_key(evt))));
+            }
+            for evt in &events[..index] {
+                self.consumed.remove(&event_key(evt));
+            }
+            index
+        };
+        buffer.advance(self.id, index);
+
+        buffer.view(self.id).iter().find(|evt| !self.is_consumed(evt)).map(|evt| evt.order())
+    }
 
-        self.events.first().map(|evt| evt.time)
+    fn buffered_events(&self) -> Vec<Arc<Event>> {
+        let buffer = self.buffer.read().unwrap();
+        buffer.view(self.id).iter().filter(|evt| !self.is_consumed(evt)).cloned().collect()
     }
 
     fn evaluate(&self, result: &PartialResult) -> Vec<PartialResult> {
-        let upper_time = result.get_time(self.timing.upper);
-        let lower_time = match self.timing.bound {
-            TimingBound::Within { window } => upper_time - window,
-            TimingBound::Between { lower } => result.get_time(lower),
+        let upper_order = result.get_order(self.timing.upper);
+        let buffer = self.buffer.read().unwrap();
+        let events = match self.index.as_ref().and_then(|it| it.candidates(result, &self.env)) {
+            Some(events) => events,
+            None => buffer.view(self.id),
         };
+        let upper = self.window_end(events, &upper_order);
+        let lower = match self.timing.bound {
+            TimingBound::Within { window } => self.window_start_at(events, upper_order.0 - window),
+            TimingBound::Between { lower } => self.window_start(events, &result.get_order(lower)),
+            TimingBound::Last { count } => self.last_events(&events[..upper], count),
+        };
+        // An empty window can start after its end
+        let lower = lower.min(upper);
 
-        let upper = self.events
-            .binary_search_by(|evt| {
-                if evt.time < upper_time { CmpOrd::Less } else { CmpOrd::Greater }
-            })
-            .unwrap_err();
-        let lower = self.events
-            .binary_search_by(|evt| {
-                if evt.time < lower_time { CmpOrd::Less } else { CmpOrd::Greater }
-            })
-            .unwrap_err();
-
-        let mut iterator = self.events[lower..upper].iter();
+        let mut iterator = events[lower..upper].iter().filter(|evt| !self.is_consumed(evt));
 
         match self.predicate.ty {
             PredicateType::Event { ref selection, ref parameters, .. } => {
                 let filter_map = |evt: &Arc<Event>| {
-                    let res =
-                        parameters.iter().enumerate().fold(result.clone(), |res, (i, param)| {
-                            let val = CompleteContext::new(&res, &evt.tuple)
-                                .evaluate_expression(&param.expression);
-                            res.insert_parameter((self.idx, i), val)
-                        });
-                    if self.is_globally_satisfied(&CompleteContext::new(&res, &evt.tuple)) {
-                        Some(res.insert_event(self.idx, evt.clone()))
-                    } else {
-                        None
-                    }
+                    parameters.iter()
+                        .enumerate()
+                        .fold(Ok(result.clone()), |res, (i, param)| {
+                            res.and_then(|res| {
+                                let val = CompleteContext::new(&res, evt, &self.env)
+                                    .evaluate_expression(&param.expression);
+                                val.map(|val| res.insert_parameter((self.idx, i), val))
+                            })
+                        })
+                        .ok()
+                        .and_then(|res| {
+                            if self.is_globally_satisfied(&res, evt) {
+                                Some(res.insert_event(self.idx, evt.clone()))
+                            } else {
+                                None
+                            }
+                        })
                 };
                 match *selection {
                     EventSelection::Each => iterator.filter_map(filter_map).collect(),
@@ -145,21 +554,30 @@ impl EventProcessor for Stack {
             }
             PredicateType::EventAggregate { ref aggregator, ref parameter, .. } => {
                 let check = |evt: &&Arc<Event>| {
-                    self.is_globally_satisfied(&CompleteContext::new(result, &evt.tuple))
+                    self.is_globally_satisfied(result, evt)
                 };
                 let map = |aggr: Value| {
-                    let context = CompleteContext::new(result, &aggr);
-                    let val = context.evaluate_expression(&parameter.expression);
-                    result.clone().insert_parameter((self.idx, 0), val)
+                    let context = CompleteContext::new(result, &aggr, &self.env);
+                    context.evaluate_expression(&parameter.expression)
+                        .ok()
+                        .map(|val| result.clone().insert_parameter((self.idx, 0), val))
+                };
+                let aggregate = match self.running {
+                    Some(ref running) => running.get(),
+                    None => {
+                        compute_aggregate(aggregator,
+                                          iterator.filter(check),
+                                          &self.tuple.attributes,
+                                          self.env.accuracy)
+                    }
                 };
-                compute_aggregate(aggregator, iterator.filter(check), &self.tuple.attributes)
-                    .map(map)
+                aggregate.and_then(map)
                     .into_iter()
                     .collect()
             }
             PredicateType::EventNegation { .. } => {
                 let check = |evt: &Arc<Event>| {
-                    self.is_globally_satisfied(&CompleteContext::new(result, &evt.tuple))
+                    self.is_globally_satisfied(result, evt)
                 };
                 if !iterator.any(check) { vec![result.clone()] } else { Vec::new() }
             }
@@ -168,15 +586,348 @@ impl EventProcessor for Stack {
     }
 }
 
-pub struct StackProvider;
+/// Provider of event stacks, where the equivalent predicates share their events.
+#[derive(Debug, Default)]
+pub struct StackProvider {
+    buffers: Mutex<Vec<(usize, Vec<Expression>, Weak<RwLock<SharedEvents>>)>>,
+}
+
+impl StackProvider {
+    pub fn new() -> Self { Self::default() }
+
+    fn shared_events(&self, ty_id: usize, local_exprs: &[Expression]) -> Arc<RwLock<SharedEvents>> {
+        let mut buffers = self.buffers.lock().unwrap();
+        // Forget the buffers whose stacks have all been dropped
+        buffers.retain(|&(_, _, ref buffer)| buffer.upgrade().is_some());
+        let existing = buffers.iter()
+            .filter(|&&(ty, ref exprs, _)| ty == ty_id && &exprs[..] == local_exprs)
+            .filter_map(|&(_, _, ref buffer)| buffer.upgrade())
+            .next();
+        existing.unwrap_or_else(|| {
+            let buffer = Arc::new(RwLock::new(SharedEvents::default()));
+            buffers.push((ty_id, local_exprs.to_vec(), Arc::downgrade(&buffer)));
+            buffer
+        })
+    }
+}
 
 impl NodeProvider for StackProvider {
     fn provide(&self,
                idx: usize,
                tuple: &TupleDeclaration,
                predicate: &Predicate,
-               _: &LinearMap<(usize, usize), BasicType>)
-               -> Option<Box<EventProcessor>> {
-        Stack::new(idx, tuple, predicate).map(|it| Box::new(it) as Box<EventProcessor>)
+               _: &LinearMap<(usize, usize), BasicType>,
+               env: &Arc<Environment>)
+               -> Result<Option<Box<EventProcessor>>, Error> {
+        Ok(Stack::new(idx, tuple, predicate, env, self)
+            .map(|it| Box::new(it) as Box<EventProcessor>))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use FnvHashMap;
+    use chrono::{Duration, TimeZone, UTC};
+    use expressions::evaluation::Environment;
+    use rule_processor::{EventProcessor, PartialResult};
+    use std::sync::Arc;
+    use super::{Index, Stack, StackProvider};
+    use tesla::{AttributeDeclaration, Event, Tuple, TupleDeclaration, TupleType};
+    use tesla::expressions::{BasicType, BinaryOperator, Expression, Value};
+    use tesla::predicates::{Boundary, ConstrainedTuple, EventSelection, Predicate, PredicateType,
+                            Timing, TimingBound};
+
+    #[test]
+    fn attribute_index() {
+        let tuple = TupleDeclaration {
+            ty: TupleType::Event,
+            id: 0,
+            name: "smoke".to_owned(),
+            attributes: vec![AttributeDeclaration {
+                                 name: "area".to_owned(),
+                                 ty: BasicType::Str,
+                                 nullable: true,
+                             }],
+        };
+        let constraint = Expression::BinaryOperation {
+            operator: BinaryOperator::Equal,
+            left: Box::new(Expression::Parameter {
+                predicate: 0,
+                parameter: 0,
+            }),
+            right: Box::new(Expression::Reference { attribute: 0 }),
+        };
+        let mut index = Index::new(1, &tuple, &[constraint.clone()]).unwrap();
+        assert!(Index::new(0, &tuple, &[constraint]).is_none());
+
+        let areas = vec!["a", "b", "a", "c", "a"];
+        let events = areas.iter()
+            .enumerate()
+            .map(|(i, area)| {
+                Arc::new(Event {
+                    tuple: Tuple {
+                        ty_id: 0,
+                        data: vec![Value::Str(area.to_string())],
+                    },
+                    time: UTC.timestamp(i as i64, 0),
+                    seq: 0,
+                })
+            })
+            .collect::<Vec<_>>();
+        for event in &events {
+            index.insert(event);
+        }
+        index.remove(&events[2]);
+        index.remove_oldest(&events[..2]);
+
+        let env = Environment::default();
+        let candidates = |area: Value| {
+            let result = PartialResult::new().insert_parameter((0, 0), area);
+            index.candidates(&result, &env).map(|events| events.to_vec())
+        };
+        assert_eq!(candidates(Value::Str("a".to_owned())).map(|events| events.len()),
+                   Some(1));
+        assert!(Arc::ptr_eq(&candidates(Value::Str("a".to_owned())).unwrap()[0], &events[4]));
+        assert_eq!(candidates(Value::Str("b".to_owned())).map(|events| events.len()),
+                   Some(0));
+        assert_eq!(candidates(Value::Null).map(|events| events.len()), Some(0));
+        assert!(candidates(Value::Int(1)).is_none());
+        assert_eq!(index.groups.len(), 2);
+    }
+
+    fn temperature() -> TupleDeclaration {
+        TupleDeclaration {
+            ty: TupleType::Event,
+            id: 0,
+            name: "temperature".to_owned(),
+            attributes: vec![AttributeDeclaration {
+                                 name: "value".to_owned(),
+                                 ty: BasicType::Int,
+                                 nullable: false,
+                             }],
+        }
+    }
+
+    /// Predicate on the positive temperatures in the last `window` seconds.
+    fn positive(window: i64) -> Predicate {
+        Predicate {
+            ty: PredicateType::Event {
+                selection: EventSelection::Each,
+                parameters: Vec::new(),
+                timing: Timing {
+                    upper: 0,
+                    bound: TimingBound::Within { window: Duration::seconds(window) },
+                    lower_boundary: Boundary::Closed,
+                    upper_boundary: Boundary::Open,
+                },
+            },
+            tuple: ConstrainedTuple {
+                ty_id: 0,
+                constraints: vec![Expression::BinaryOperation {
+                                      operator: BinaryOperator::GreaterThan,
+                                      left: Box::new(Expression::Reference { attribute: 0 }),
+                                      right: Box::new(Expression::Immediate {
+                                          value: Value::Int(0),
+                                      }),
+                                  }],
+                alias: "temperature".to_owned(),
+            },
+        }
+    }
+
+    fn reading(value: i64, time: i64) -> Arc<Event> {
+        Arc::new(Event {
+            tuple: Tuple {
+                ty_id: 0,
+                data: vec![Value::Int(value)],
+            },
+            time: UTC.timestamp(time, 0),
+            seq: 0,
+        })
+    }
+
+    #[test]
+    fn shared_events() {
+        let tuple = TupleDeclaration {
+            ty: TupleType::Event,
+            id: 0,
+            name: "temperature".to_owned(),
+            attributes: vec![AttributeDeclaration {
+                                 name: "value".to_owned(),
+                                 ty: BasicType::Int,
+                                 nullable: false,
+                             }],
+        };
+        let predicate = |window: i64| {
+            Predicate {
+                ty: PredicateType::Event {
+                    selection: EventSelection::Each,
+                    parameters: Vec::new(),
+                    timing: Timing {
+                        upper: 0,
+                        bound: TimingBound::Within { window: Duration::seconds(window) },
+                        lower_boundary: Boundary::Closed,
+                        upper_boundary: Boundary::Open,
+                    },
+                },
+                tuple: ConstrainedTuple {
+                    ty_id: 0,
+                    constraints: vec![Expression::BinaryOperation {
+                                          operator: BinaryOperator::GreaterThan,
+                                          left: Box::new(Expression::Reference { attribute: 0 }),
+                                          right: Box::new(Expression::Immediate {
+                                              value: Value::Int(0),
+                                          }),
+                                      }],
+                    alias: "temperature".to_owned(),
+                },
+            }
+        };
+        let env = Arc::new(Environment::default());
+        let provider = StackProvider::new();
+        let mut short = Stack::new(1, &tuple, &predicate(2), &env, &provider).unwrap();
+        let mut long = Stack::new(1, &tuple, &predicate(5), &env, &provider).unwrap();
+        assert!(Arc::ptr_eq(&short.buffer, &long.buffer));
+
+        for i in 0..10 {
+            let event = Arc::new(Event {
+                tuple: Tuple {
+                    ty_id: 0,
+                    data: vec![Value::Int(i % 3)],
+                },
+                time: UTC.timestamp(i, 0),
+                seq: 0,
+            });
+            let mut times = FnvHashMap::default();
+            times.insert(0, event.order());
+            short.process(&event);
+            long.process(&event);
+            short.remove_old(&times);
+            long.remove_old(&times);
+        }
+        // The buffer retains the events of the longest window only once
+        assert_eq!(short.buffered_events().len(), 2);
+        assert_eq!(long.buffered_events().len(), 4);
+        assert_eq!(short.buffer.read().unwrap().events.len(), 4);
+
+        // Consumption is not seen by the other stacks
+        let first = short.buffered_events()[0].clone();
+        short.consume(&first);
+        assert_eq!(short.buffered_events().len(), 1);
+        assert_eq!(long.buffered_events().len(), 4);
+
+        drop(long);
+        assert_eq!(short.buffer.read().unwrap().events.len(), 2);
+        assert_eq!(provider.buffers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn late_events() {
+        let tuple = temperature();
+        let env = Arc::new(Environment::default());
+        let provider = StackProvider::new();
+        let mut first = Stack::new(1, &tuple, &positive(10), &env, &provider).unwrap();
+        let mut second = Stack::new(1, &tuple, &positive(10), &env, &provider).unwrap();
+        for &(value, time) in &[(1, 0), (2, 4), (3, 2), (4, 4), (5, 1)] {
+            let event = reading(value, time);
+            first.process(&event);
+            second.process(&event);
+        }
+        for stack in &[first, second] {
+            let values = stack.buffered_events()
+                .iter()
+                .map(|evt| evt.tuple.data[0].clone())
+                .collect::<Vec<_>>();
+            let expected = [1, 5, 3, 2, 4].iter().map(|&it| Value::Int(it)).collect::<Vec<_>>();
+            assert_eq!(values, expected);
+        }
+    }
+
+    #[test]
+    fn count_windows() {
+        let tuple = temperature();
+        let mut predicate = positive(0);
+        if let PredicateType::Event { ref mut timing, .. } = predicate.ty {
+            timing.bound = TimingBound::Last { count: 2 };
+        }
+        let env = Arc::new(Environment::default());
+        let provider = StackProvider::new();
+        let mut stack = Stack::new(1, &tuple, &predicate, &env, &provider).unwrap();
+        for &(value, time) in &[(1, 0), (0, 1), (2, 2), (3, 3), (4, 5)] {
+            stack.process(&reading(value, time));
+        }
+        let mut times = FnvHashMap::default();
+        times.insert(0, (UTC.timestamp(4, 0), 0));
+        stack.remove_old(&times);
+        assert_eq!(stack.buffered_events().len(), 3);
+
+        // The zero reading is not counted and the one after the trigger is excluded
+        let result = PartialResult::new().insert_event(0, reading(0, 4));
+        let values = stack.evaluate(&result)
+            .iter()
+            .map(|res| res.get_event(1).unwrap().tuple.data[0].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![Value::Int(2), Value::Int(3)]);
+    }
+
+    #[test]
+    fn window_boundaries() {
+        let tuple = temperature();
+        let env = Arc::new(Environment::default());
+        let provider = StackProvider::new();
+        let events = [(1, 0), (2, 5), (3, 5), (4, 10)]
+            .iter()
+            .map(|&(value, time)| reading(value, time))
+            .collect::<Vec<_>>();
+        let result = PartialResult::new().insert_event(0, reading(0, 10));
+        let cases = [(Boundary::Closed, Boundary::Open, vec![2, 3]),
+                     (Boundary::Closed, Boundary::Closed, vec![2, 3, 4]),
+                     (Boundary::Open, Boundary::Open, vec![]),
+                     (Boundary::Open, Boundary::Closed, vec![4])];
+        for &(lower, upper, ref expected) in &cases {
+            let mut predicate = positive(5);
+            if let PredicateType::Event { ref mut timing, .. } = predicate.ty {
+                timing.lower_boundary = lower;
+                timing.upper_boundary = upper;
+            }
+            let mut stack = Stack::new(1, &tuple, &predicate, &env, &provider).unwrap();
+            for event in &events {
+                stack.process(event);
+            }
+            let mut times = FnvHashMap::default();
+            times.insert(0, (UTC.timestamp(10, 0), 0));
+            stack.remove_old(&times);
+
+            let values = stack.evaluate(&result)
+                .iter()
+                .map(|res| res.get_event(1).unwrap().tuple.data[0].clone())
+                .collect::<Vec<_>>();
+            let expected = expected.iter().map(|&it| Value::Int(it)).collect::<Vec<_>>();
+            assert_eq!(values, expected, "window {:?} {:?}", lower, upper);
+        }
+    }
+
+    #[test]
+    fn sequence_ties() {
+        let tuple = temperature();
+        let env = Arc::new(Environment::default());
+        let provider = StackProvider::new();
+        let mut stack = Stack::new(1, &tuple, &positive(5), &env, &provider).unwrap();
+        let sequenced = |value, seq| Arc::new(Event { seq: seq, ..(*reading(value, 5)).clone() });
+        let (before, trigger, after) = (sequenced(1, 1), sequenced(0, 2), sequenced(2, 3));
+        // Out of order, they are sorted by sequence number
+        stack.process(&after);
+        stack.process(&before);
+        let buffered = stack.buffered_events();
+        assert!(Arc::ptr_eq(&buffered[0], &before) && Arc::ptr_eq(&buffered[1], &after));
+
+        // Only the event that precedes the trigger is in its window
+        let result = PartialResult::new().insert_event(0, trigger);
+        let matches = stack.evaluate(&result);
+        assert_eq!(matches.len(), 1);
+        assert!(Arc::ptr_eq(matches[0].get_event(1).unwrap(), &before));
+
+        stack.consume(&after);
+        assert_eq!(stack.buffered_events().len(), 1);
     }
 }